@@ -1,106 +1,122 @@
-use base16ct::lower::encode_string;
-use eyre::Result;
-use serde::{Deserialize, Serialize};
-use sha3::{Digest, Keccak256};
-use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Write},
-    path::Path,
+use crate::{
+    state::{get_account_key, State},
+    storage::Storage,
+    trie::MerkleProof,
 };
-
-const ACCOUNTS_JSON: &str = "./accounts.json";
+use alloy_rlp::RlpEncodable;
+use eyre::{bail, Result};
+use helper::{keccak256, Address, B256};
+use serde::{Deserialize, Serialize};
 
 pub type Accounts = Vec<Account>;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Account {
-    pub address: String,
+    pub address: Address,
     pub balance: u64,
     pub nonce: u64,
+    /// Tokens staked by the account, which are not part of its balance. Under proof of stake
+    /// they make it a validator.
+    #[serde(default)]
+    pub stake: u64,
+    /// Tokens unstaked but still locked, which can be slashed like the stake until they return to
+    /// the balance with block `unbonding_until`.
+    #[serde(default)]
+    pub unbonding: u64,
+    #[serde(default)]
+    pub unbonding_until: u64,
 }
 
-impl Account {
-    pub fn get_account_hash(&self) -> Result<String> {
-        let hash = Keccak256::new()
-            .chain_update(self.address.clone())
-            .chain_update(self.balance.to_string().clone())
-            .chain_update(self.nonce.to_string().clone())
-            .finalize();
-        let hash_hex = encode_string(&hash);
-
-        Ok(hash_hex)
-    }
+/// The fields of an account that its leaf in the state trie commits to. The address is the key of
+/// the leaf.
+#[derive(RlpEncodable)]
+struct AccountLeaf {
+    balance: u64,
+    nonce: u64,
+    stake: u64,
+    unbonding: u64,
+    unbonding_until: u64,
 }
 
-pub fn accounts_init() -> Result<()> {
-    let path = Path::new(ACCOUNTS_JSON);
-    if !path.exists() {
-        let accounts: Accounts = vec![];
-        let accounts_json = serde_json::to_string_pretty(&accounts)?;
-        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
-        file.write_all(accounts_json.as_bytes())?;
+impl Account {
+    /// The account's leaf value in the state trie, the hash of the RLP encoding of its fields.
+    pub fn get_account_hash(&self) -> B256 {
+        keccak256(alloy_rlp::encode(AccountLeaf {
+            balance: self.balance,
+            nonce: self.nonce,
+            stake: self.stake,
+            unbonding: self.unbonding,
+            unbonding_until: self.unbonding_until,
+        }))
     }
-
-    Ok(())
 }
 
-pub fn update_accounts(account: &Account) -> Result<()> {
-    let mut accounts = get_all_accounts()?;
-
-    if let Some(index) = accounts.iter().position(|acc| acc.address == account.address) {
-        accounts[index] = account.clone();
-    } else {
-        accounts.push(account.clone());
-    }
+/// An account as of some block together with a Merkle proof of it against that block's
+/// `state_root`. `account` is `None` if the address was never written to state, in which case
+/// the proof shows that it is absent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountProof {
+    pub address: Address,
+    pub block_number: u64,
+    pub account: Option<Account>,
+    pub proof: MerkleProof,
+}
 
-    let path = Path::new(ACCOUNTS_JSON);
-    let mut file = OpenOptions::new().write(true).truncate(true).open(path)?;
-    let accounts_json = serde_json::to_string_pretty(&accounts)?;
-    file.write_all(accounts_json.as_bytes())?;
+pub fn update_accounts(storage: &dyn Storage, account: &Account) -> Result<()> {
+    storage.put_account(account)
+}
 
-    Ok(())
+pub fn get_all_accounts(storage: &dyn Storage) -> Result<Accounts> {
+    storage.get_accounts()
 }
 
-pub fn get_all_accounts() -> Result<Accounts> {
-    let path = Path::new(ACCOUNTS_JSON);
-    let mut file = File::open(path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let accounts: Accounts = serde_json::from_str(&contents)?;
+pub fn get_account_by_address(storage: &dyn Storage, address: &Address) -> Result<Account> {
+    let account = storage.get_account(address)?.unwrap_or_else(|| new_account(address));
 
-    Ok(accounts)
+    Ok(account)
 }
 
-pub fn get_account_by_address(address: &str) -> Result<Account> {
-    let accounts = get_all_accounts()?;
-    let account = accounts
-        .iter()
-        .find(|acc| acc.address == address)
-        .cloned()
-        .unwrap_or_else(|| Account { address: address.to_string(), balance: 1000, nonce: 0 });
+/// The account every address starts out with before it is first written to state. It is empty,
+/// tokens only enter the chain through the genesis allocation.
+pub fn new_account(address: &Address) -> Account {
+    Account { address: *address, balance: 0, nonce: 0, stake: 0, unbonding: 0, unbonding_until: 0 }
+}
 
-    Ok(account)
+pub fn get_state_root(storage: &dyn Storage) -> Result<B256> {
+    Ok(State::load(storage)?.get_state_root())
 }
 
-pub fn get_state_root() -> Result<String> {
-    let accounts = get_all_accounts()?;
-    let mut hasher = Keccak256::new();
+/// Checks `proof` against a `state_root` the caller already trusts, e.g. from a block header it
+/// has verified. Needs no access to the chain.
+pub fn verify_account_proof(state_root: &B256, proof: &AccountProof) -> Result<()> {
+    if let Some(account) = &proof.account {
+        if account.address != proof.address {
+            bail!("proof for {} carries the account of {}", proof.address, account.address);
+        }
+    }
 
-    if accounts.is_empty() {
-        hasher.update(String::default());
-        let hash = hasher.finalize();
-        let state_root = format!("0x{}", encode_string(&hash));
-        return Ok(state_root);
+    let value = proof.account.as_ref().map(Account::get_account_hash);
+    if !proof.proof.verify(*state_root, get_account_key(&proof.address), value) {
+        bail!("invalid proof for {} against state root {}", proof.address, state_root);
     }
 
-    let account_hashes: Vec<String> =
-        accounts.iter().map(|acc| acc.get_account_hash().unwrap()).collect();
+    Ok(())
+}
 
-    for hash in account_hashes {
-        hasher.update(hash);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_of_different_accounts_differ() {
+        let account = |balance, nonce| Account { balance, nonce, ..new_account(&Address::ZERO) };
+
+        assert_ne!(account(1, 23).get_account_hash(), account(12, 3).get_account_hash());
+        assert_ne!(account(5, 0).get_account_hash(), account(0, 5).get_account_hash());
+        let unbonding = Account { unbonding: 4, ..account(1, 1) };
+        assert_ne!(
+            unbonding.get_account_hash(),
+            Account { stake: 4, ..account(1, 1) }.get_account_hash()
+        );
     }
-
-    let hash = hasher.finalize();
-    let state_root = format!("0x{}", encode_string(&hash));
-    Ok(state_root)
 }
@@ -0,0 +1,1130 @@
+use crate::{
+    block::Block, blockchain::Blockchain, chainspec::HashScheme, evidence::Evidence,
+    transaction::verify_hash_signature,
+};
+use alloy_rlp::{BufMut, Decodable, Encodable, RlpDecodable, RlpEncodable};
+use ethers::signers::{LocalWallet, Signer};
+use eyre::{bail, Result};
+use helper::{keccak256, Address, Signature, B256};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::Duration,
+};
+
+/// [`Proposal::valid_round`] of a block that was never prevoted for by a quorum.
+pub const NO_ROUND: u64 = u64::MAX;
+
+/// Rounds ahead of the current one that proposals and votes are taken in for. Honest validators
+/// are only ever a few rounds apart, and anything further ahead could be sent without end to fill
+/// up the memory of the node.
+pub const MAX_FUTURE_ROUNDS: u64 = 16;
+
+/// Metadata key the block this node is locked on is stored under, so that a restart does not
+/// free it to prevote for another block at the same height.
+const LOCK_KEY: &str = "bft_lock";
+
+/// The steps of a Tendermint round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Step {
+    Propose,
+    Prevote,
+    Precommit,
+}
+
+/// What a validator votes in, encoded as 1 or 2 in RLP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum VoteStep {
+    Prevote = 1,
+    Precommit = 2,
+}
+
+/// The block a validator proposes for a round, signed with the validator's key.
+#[derive(Debug, Clone, RlpEncodable, RlpDecodable)]
+pub struct Proposal {
+    pub height: u64,
+    pub round: u64,
+    /// The last round in which more than two thirds of the validators prevoted for the block,
+    /// or [`NO_ROUND`] for a new block.
+    pub valid_round: u64,
+    pub block: Block,
+    pub proposer: Address,
+    pub signature: Signature,
+}
+
+/// The part of a proposal its signature covers.
+#[derive(RlpEncodable)]
+struct ProposalData {
+    height: u64,
+    round: u64,
+    valid_round: u64,
+    block_hash: B256,
+}
+
+/// A validator's prevote or precommit in a round, signed with the validator's key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, RlpEncodable, RlpDecodable)]
+pub struct RoundVote {
+    pub step: VoteStep,
+    pub height: u64,
+    pub round: u64,
+    /// The block voted for, or zero for a vote for no block at all (nil).
+    pub block_hash: B256,
+    pub voter: Address,
+    pub signature: Signature,
+}
+
+/// The part of a round vote its signature covers.
+#[derive(RlpEncodable)]
+struct RoundVoteData {
+    step: VoteStep,
+    height: u64,
+    round: u64,
+    block_hash: B256,
+}
+
+/// Proof that a block is final: the precommits for it of more than two thirds of the validators,
+/// all cast in the same round. Stored alongside the block and sent along with it to nodes that
+/// catch up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, RlpEncodable, RlpDecodable)]
+pub struct CommitCertificate {
+    pub height: u64,
+    pub round: u64,
+    pub block_hash: B256,
+    pub precommits: Vec<RoundVote>,
+}
+
+/// What [`BftState`] needs its node to do.
+#[derive(Debug, Clone)]
+pub enum Output {
+    /// Send the proposal to the network.
+    Proposal(Proposal),
+    /// Send the vote to the network.
+    Vote(RoundVote),
+    /// Call [`BftState::on_timeout`] with `timeout` once `after` has passed.
+    Schedule { timeout: Timeout, after: Duration },
+    /// Build the block for `height`, whose round `round` this node proposes in, and hand it to
+    /// [`BftState::propose`].
+    Propose { height: u64, round: u64 },
+    /// Commit `block` with [`Blockchain::commit_certified_block`]. The state moves on to the
+    /// next height on the [`TimeoutStep::Commit`] timeout it schedules along with it.
+    Commit { block: Block, certificate: CommitCertificate },
+    /// Add the evidence, which was verified, to the evidence pool and send it to the network.
+    /// The second vote it holds is not counted.
+    Evidence(Evidence),
+}
+
+/// What a timeout ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutStep {
+    Propose,
+    Prevote,
+    Precommit,
+    /// The pause after a commit, which paces the chain to one block per block time.
+    Commit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout {
+    pub height: u64,
+    pub round: u64,
+    pub step: TimeoutStep,
+}
+
+/// A block together with the round it was locked on or found valid in.
+#[derive(Debug, Clone)]
+struct RoundBlock {
+    round: u64,
+    block: Block,
+    hash: B256,
+}
+
+/// The lock of a node as it is stored.
+#[derive(Serialize, Deserialize)]
+struct StoredLock {
+    height: u64,
+    round: u64,
+    block: Block,
+}
+
+/// The prevotes or the precommits of one round, counting each validator once.
+#[derive(Debug, Default)]
+struct VoteSet {
+    votes: BTreeMap<Address, RoundVote>,
+}
+
+/// One node's side of Tendermint for the block after the head of its chain.
+///
+/// Every height goes through rounds. Each round has a proposer, the validators take turns by
+/// height and round, and three steps: the proposer sends a block, every validator prevotes for it
+/// or for nil, and once more than two thirds prevoted for the block they precommit for it. A
+/// block with the precommits of more than two thirds of the validators in one round is final, and
+/// the precommits make its [`CommitCertificate`]. A round that stalls, because its proposer is
+/// offline or its block invalid, times out into the next one, with timeouts that grow with the
+/// round.
+///
+/// A validator that precommits a block locks on it and prevotes for nothing else until another
+/// block gets the prevotes of more than two thirds of the validators in a later round. The lock
+/// is stored with the chain, so it holds across restarts. Two
+/// conflicting blocks can then never both be final unless more than a third of the validators
+/// sign conflicting votes.
+///
+/// The state does no networking itself. The node feeds it proposals, votes and timeouts and
+/// carries out the [`Output`]s it returns. Without a wallet, or with the key of an address that
+/// is not a validator, the node only follows along and commits the blocks the validators
+/// finalize.
+pub struct BftState {
+    wallet: Option<LocalWallet>,
+    scheme: HashScheme,
+    block_time: u64,
+    validators: Vec<Address>,
+    height: u64,
+    round: u64,
+    step: Step,
+    decided: bool,
+    locked: Option<RoundBlock>,
+    valid: Option<RoundBlock>,
+    /// The block this node proposed at this height, which it proposes again in its later rounds
+    /// instead of sealing a second block at the same height, which would be evidence against it.
+    proposed: Option<Block>,
+    /// The first proposal of each round's proposer, with the hash of its block.
+    proposals: BTreeMap<u64, (Proposal, B256)>,
+    prevotes: BTreeMap<u64, VoteSet>,
+    precommits: BTreeMap<u64, VoteSet>,
+    /// Rounds in which the rules that only fire once per round have fired.
+    prevote_timeouts: BTreeSet<u64>,
+    precommit_timeouts: BTreeSet<u64>,
+    polkas: BTreeSet<u64>,
+    validity: HashMap<B256, bool>,
+    /// Messages for the height after this one, from validators that moved on first.
+    future_proposals: Vec<Proposal>,
+    future_votes: Vec<RoundVote>,
+}
+
+impl Encodable for VoteStep {
+    fn encode(&self, out: &mut dyn BufMut) {
+        (*self as u8).encode(out)
+    }
+
+    fn length(&self) -> usize {
+        (*self as u8).length()
+    }
+}
+
+impl Decodable for VoteStep {
+    fn decode(buf: &mut &[u8]) -> alloy_rlp::Result<Self> {
+        match u8::decode(buf)? {
+            1 => Ok(VoteStep::Prevote),
+            2 => Ok(VoteStep::Precommit),
+            _ => Err(alloy_rlp::Error::Custom("unknown vote step")),
+        }
+    }
+}
+
+impl Proposal {
+    /// Proposes `block` for round `round` of height `height` as the validator of
+    /// `proposer_wallet`.
+    pub fn new(
+        height: u64,
+        round: u64,
+        valid_round: Option<u64>,
+        block: Block,
+        scheme: HashScheme,
+        proposer_wallet: &LocalWallet,
+    ) -> Result<Self> {
+        let valid_round = valid_round.unwrap_or(NO_ROUND);
+        let block_hash = block.get_block_hash(scheme)?;
+        let hash = Proposal::get_signing_hash(height, round, valid_round, block_hash);
+        let signature = Signature::from(proposer_wallet.sign_hash(hash.into())?);
+        let proposer = Address::from(proposer_wallet.address());
+
+        Ok(Proposal { height, round, valid_round, block, proposer, signature })
+    }
+
+    /// The round the block of the proposal was last found valid in, if any.
+    pub fn valid_round(&self) -> Option<u64> {
+        (self.valid_round != NO_ROUND).then_some(self.valid_round)
+    }
+
+    /// Hash a validator signs to propose a block.
+    pub fn get_signing_hash(height: u64, round: u64, valid_round: u64, block_hash: B256) -> B256 {
+        keccak256(alloy_rlp::encode(ProposalData { height, round, valid_round, block_hash }))
+    }
+
+    /// Checks that the proposal was signed by `proposer`.
+    pub fn verify(&self, scheme: HashScheme) -> Result<()> {
+        let block_hash = self.block.get_block_hash(scheme)?;
+        let hash =
+            Proposal::get_signing_hash(self.height, self.round, self.valid_round, block_hash);
+        verify_hash_signature(self.signature, hash, self.proposer)
+    }
+}
+
+impl RoundVote {
+    /// Votes for the block `block_hash`, or for nil, in round `round` of height `height` as the
+    /// validator of `voter_wallet`.
+    pub fn new(
+        step: VoteStep,
+        height: u64,
+        round: u64,
+        block_hash: Option<B256>,
+        voter_wallet: &LocalWallet,
+    ) -> Result<Self> {
+        let block_hash = block_hash.unwrap_or(B256::ZERO);
+        let hash = RoundVote::get_signing_hash(step, height, round, block_hash);
+        let signature = Signature::from(voter_wallet.sign_hash(hash.into())?);
+        let voter = Address::from(voter_wallet.address());
+
+        Ok(RoundVote { step, height, round, block_hash, voter, signature })
+    }
+
+    /// The block voted for, or `None` for nil.
+    pub fn block_hash(&self) -> Option<B256> {
+        (self.block_hash != B256::ZERO).then_some(self.block_hash)
+    }
+
+    /// Hash a validator signs to cast a round vote.
+    pub fn get_signing_hash(step: VoteStep, height: u64, round: u64, block_hash: B256) -> B256 {
+        keccak256(alloy_rlp::encode(RoundVoteData { step, height, round, block_hash }))
+    }
+
+    /// Checks that the vote was signed by `voter`.
+    pub fn verify(&self) -> Result<()> {
+        let hash = RoundVote::get_signing_hash(self.step, self.height, self.round, self.block_hash);
+        verify_hash_signature(self.signature, hash, self.voter)
+    }
+}
+
+impl CommitCertificate {
+    /// Checks that every precommit of the certificate is a signed precommit for its block by a
+    /// different one of `validators`, and returns how many validators signed it. Whether that
+    /// is a quorum is up to the consensus engine.
+    pub fn verify(&self, validators: &[Address]) -> Result<u64> {
+        let mut signers = BTreeSet::new();
+        for precommit in &self.precommits {
+            if precommit.step != VoteStep::Precommit
+                || precommit.height != self.height
+                || precommit.round != self.round
+                || precommit.block_hash != self.block_hash
+            {
+                bail!("certificate of block {} holds a vote for something else", self.height);
+            }
+            if !validators.contains(&precommit.voter) {
+                bail!("{} is not a validator", precommit.voter);
+            }
+            if !signers.insert(precommit.voter) {
+                bail!("{} precommitted twice in the certificate", precommit.voter);
+            }
+            precommit.verify()?;
+        }
+
+        Ok(signers.len() as u64)
+    }
+}
+
+impl VoteSet {
+    /// The earlier vote of the voter of `vote`, if it was for another block or for nil.
+    fn conflicting(&self, vote: &RoundVote) -> Option<&RoundVote> {
+        self.votes.get(&vote.voter).filter(|cast| cast.block_hash != vote.block_hash)
+    }
+
+    /// Adds `vote`. Returns whether it was new, and fails if it contradicts an earlier vote of
+    /// the same validator.
+    fn add(&mut self, vote: RoundVote) -> Result<bool> {
+        match self.votes.get(&vote.voter) {
+            Some(cast) if cast.block_hash == vote.block_hash => Ok(false),
+            Some(cast) => bail!(
+                "{} already voted for {} in round {} and cannot vote for {}",
+                vote.voter,
+                cast.block_hash,
+                cast.round,
+                vote.block_hash
+            ),
+            None => {
+                self.votes.insert(vote.voter, vote);
+                Ok(true)
+            }
+        }
+    }
+
+    /// Number of validators that voted for `block_hash`, or for nil.
+    fn count(&self, block_hash: Option<B256>) -> u64 {
+        self.votes.values().filter(|vote| vote.block_hash() == block_hash).count() as u64
+    }
+
+    fn total(&self) -> u64 {
+        self.votes.len() as u64
+    }
+}
+
+impl BftState {
+    /// A state that takes part as the validator of `wallet`, if any. It does nothing until
+    /// [`BftState::start_height`] is called.
+    pub fn new(blockchain: &Blockchain, wallet: Option<LocalWallet>) -> Self {
+        BftState {
+            wallet,
+            scheme: blockchain.spec().hash_scheme,
+            block_time: blockchain.spec().block_time,
+            validators: vec![],
+            height: 0,
+            round: 0,
+            step: Step::Propose,
+            decided: false,
+            locked: None,
+            valid: None,
+            proposed: None,
+            proposals: BTreeMap::new(),
+            prevotes: BTreeMap::new(),
+            precommits: BTreeMap::new(),
+            prevote_timeouts: BTreeSet::new(),
+            precommit_timeouts: BTreeSet::new(),
+            polkas: BTreeSet::new(),
+            validity: HashMap::new(),
+            future_proposals: vec![],
+            future_votes: vec![],
+        }
+    }
+
+    /// The height the state agrees on, the number of the block after the head when it started.
+    pub fn height(&self) -> u64 {
+        self.height
+    }
+
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    /// The validator that proposes in round `round` of the current height.
+    pub fn proposer(&self, round: u64) -> Address {
+        self.proposer_at(self.height, round)
+    }
+
+    fn proposer_at(&self, height: u64, round: u64) -> Address {
+        let turn = (height + round) % self.validators.len() as u64;
+        self.validators[turn as usize]
+    }
+
+    /// Starts round 0 of the block after the head of `blockchain`, dropping everything about
+    /// the height before. Called once the node has a chain and after every block it commits or
+    /// imports.
+    pub fn start_height(&mut self, blockchain: &Blockchain) -> Result<Vec<Output>> {
+        let head = blockchain.storage().get_last_block()?;
+        let Some(head) = head else {
+            bail!("cannot agree on blocks before the chain has a genesis block");
+        };
+
+        let height = head.header.number + 1;
+        let (future_proposals, future_votes) = if height == self.height + 1 {
+            (std::mem::take(&mut self.future_proposals), std::mem::take(&mut self.future_votes))
+        } else {
+            (vec![], vec![])
+        };
+        *self = BftState {
+            validators: blockchain.validators()?,
+            height,
+            ..BftState::new(blockchain, self.wallet.take())
+        };
+
+        // A lock survives restarts until the height is over.
+        if let Some(lock) = blockchain.storage().get_metadata(LOCK_KEY)? {
+            let lock: StoredLock = serde_json::from_str(&lock)?;
+            if lock.height == height {
+                let hash = lock.block.get_block_hash(self.scheme)?;
+                let block = RoundBlock { round: lock.round, block: lock.block, hash };
+                self.locked = Some(block.clone());
+                self.valid = Some(block);
+            }
+        }
+
+        let mut out = vec![];
+        self.start_round(0, &mut out)?;
+        // Messages that turn out to be invalid once the validators are known are dropped.
+        for proposal in future_proposals {
+            let _ = self.add_proposal(proposal);
+        }
+        for vote in future_votes {
+            let _ = self.add_vote(vote, &mut out);
+        }
+        self.update(blockchain, &mut out)?;
+
+        Ok(out)
+    }
+
+    /// Takes in a proposal from the network. Fails if it was not signed by the proposer of its
+    /// round or is too many rounds ahead.
+    pub fn on_proposal(
+        &mut self,
+        blockchain: &Blockchain,
+        proposal: Proposal,
+    ) -> Result<Vec<Output>> {
+        let mut out = vec![];
+        if proposal.height == self.height + 1 {
+            // The validators of the next height are only known once it starts, so this checks
+            // against the current ones and the proposal is checked again then.
+            self.check_proposal(&proposal, 0)?;
+            if !self.future_proposals.iter().any(|future| future.round == proposal.round) {
+                self.future_proposals.push(proposal);
+            }
+        } else if self.add_proposal(proposal)? {
+            self.update(blockchain, &mut out)?;
+        }
+
+        Ok(out)
+    }
+
+    /// Takes in a prevote or precommit from the network. Fails if it was not signed by a
+    /// validator, is too many rounds ahead or contradicts an earlier vote of the same validator.
+    pub fn on_vote(&mut self, blockchain: &Blockchain, vote: RoundVote) -> Result<Vec<Output>> {
+        let mut out = vec![];
+        if vote.height == self.height + 1 {
+            self.check_vote(&vote, 0)?;
+            // Only the first vote of each validator per step and round is kept, so the queue
+            // stays bounded.
+            let known = self.future_votes.iter().any(|future| {
+                (future.voter, future.step, future.round) == (vote.voter, vote.step, vote.round)
+            });
+            if !known {
+                self.future_votes.push(vote);
+            }
+        } else if self.add_vote(vote, &mut out)? {
+            self.update(blockchain, &mut out)?;
+        }
+
+        Ok(out)
+    }
+
+    /// Handles a timeout scheduled through [`Output::Schedule`]. Timeouts of rounds the state
+    /// already left do nothing.
+    pub fn on_timeout(&mut self, blockchain: &Blockchain, timeout: Timeout) -> Result<Vec<Output>> {
+        let mut out = vec![];
+        if timeout.height != self.height {
+            return Ok(out);
+        }
+        if timeout.step == TimeoutStep::Commit {
+            return self.start_height(blockchain);
+        }
+        if timeout.round != self.round || self.decided {
+            return Ok(out);
+        }
+
+        match timeout.step {
+            TimeoutStep::Propose if self.step == Step::Propose => {
+                self.vote(VoteStep::Prevote, None, &mut out)?;
+                self.step = Step::Prevote;
+            }
+            TimeoutStep::Prevote if self.step == Step::Prevote => {
+                self.vote(VoteStep::Precommit, None, &mut out)?;
+                self.step = Step::Precommit;
+            }
+            TimeoutStep::Precommit => self.start_round(self.round + 1, &mut out)?,
+            _ => (),
+        }
+        self.update(blockchain, &mut out)?;
+
+        Ok(out)
+    }
+
+    /// Proposes `block`, built by the node after an [`Output::Propose`], in the current round.
+    pub fn propose(&mut self, blockchain: &Blockchain, block: Block) -> Result<Vec<Output>> {
+        let mut out = vec![];
+        let Some(wallet) = &self.wallet else {
+            bail!("cannot propose without the key of a validator");
+        };
+        if self.step != Step::Propose
+            || self.proposer(self.round) != Address::from(wallet.address())
+        {
+            bail!(
+                "round {} of height {} is no longer this node's to propose in",
+                self.round,
+                self.height
+            );
+        }
+
+        let proposal =
+            Proposal::new(self.height, self.round, None, block.clone(), self.scheme, wallet)?;
+        self.proposed = Some(block);
+        out.push(Output::Proposal(proposal.clone()));
+        self.add_proposal(proposal)?;
+        self.update(blockchain, &mut out)?;
+
+        Ok(out)
+    }
+
+    /// This node's proposal and votes in the current round, to be sent again. The network does
+    /// not resend lost messages, and a round whose messages all got lost would never time out,
+    /// since its timeouts wait for a quorum of votes.
+    pub fn rebroadcast(&self) -> Vec<Output> {
+        let Some(me) = self.wallet.as_ref().map(|wallet| Address::from(wallet.address())) else {
+            return vec![];
+        };
+
+        let mut out = vec![];
+        if let Some((proposal, _)) = self.proposals.get(&self.round) {
+            if proposal.proposer == me {
+                out.push(Output::Proposal(proposal.clone()));
+            }
+        }
+        for votes in [self.prevotes.get(&self.round), self.precommits.get(&self.round)] {
+            if let Some(vote) = votes.and_then(|votes| votes.votes.get(&me)) {
+                out.push(Output::Vote(vote.clone()));
+            }
+        }
+
+        out
+    }
+
+    /// Records the first proposal of a round's proposer for the current height. Returns whether
+    /// it was new.
+    fn add_proposal(&mut self, proposal: Proposal) -> Result<bool> {
+        if proposal.height != self.height || self.proposals.contains_key(&proposal.round) {
+            return Ok(false);
+        }
+        self.check_proposal(&proposal, self.round)?;
+
+        let hash = proposal.block.get_block_hash(self.scheme)?;
+        self.proposals.insert(proposal.round, (proposal, hash));
+
+        Ok(true)
+    }
+
+    /// Records a validator's vote for the current height. Returns whether it was new. A vote that
+    /// contradicts an earlier one of the same validator is not counted, and the evidence of the
+    /// two goes to `out` instead.
+    fn add_vote(&mut self, vote: RoundVote, out: &mut Vec<Output>) -> Result<bool> {
+        if vote.height != self.height {
+            return Ok(false);
+        }
+        self.check_vote(&vote, self.round)?;
+
+        let votes = match vote.step {
+            VoteStep::Prevote => &mut self.prevotes,
+            VoteStep::Precommit => &mut self.precommits,
+        };
+        let votes = votes.entry(vote.round).or_default();
+        if let Some(first) = votes.conflicting(&vote) {
+            let first = first.clone();
+            out.push(Output::Evidence(Evidence::DoubleRoundVote { first, second: vote }));
+            return Ok(false);
+        }
+
+        votes.add(vote)
+    }
+
+    /// Checks that `proposal` was signed by the proposer of its round, which may be at most
+    /// [`MAX_FUTURE_ROUNDS`] after `round`.
+    fn check_proposal(&self, proposal: &Proposal, round: u64) -> Result<()> {
+        check_round(proposal.round, round)?;
+        if self.validators.is_empty() {
+            bail!("no validators to check proposals against yet");
+        }
+        let expected = self.proposer_at(proposal.height, proposal.round);
+        if proposal.proposer != expected {
+            bail!(
+                "{} proposed in round {} of height {}, which belongs to {}",
+                proposal.proposer,
+                proposal.round,
+                proposal.height,
+                expected
+            );
+        }
+
+        proposal.verify(self.scheme)
+    }
+
+    /// Checks that `vote` was signed by a validator in a round at most [`MAX_FUTURE_ROUNDS`]
+    /// after `round`.
+    fn check_vote(&self, vote: &RoundVote, round: u64) -> Result<()> {
+        check_round(vote.round, round)?;
+        if !self.validators.contains(&vote.voter) {
+            bail!("{} is not a validator", vote.voter);
+        }
+
+        vote.verify()
+    }
+
+    fn start_round(&mut self, round: u64, out: &mut Vec<Output>) -> Result<()> {
+        self.round = round;
+        self.step = Step::Propose;
+
+        let proposer = self.proposer(round);
+        if let Some(wallet) =
+            self.wallet.as_ref().filter(|wallet| Address::from(wallet.address()) == proposer)
+        {
+            // A block that may already be locked on by some validators is proposed again, so that
+            // the round can still finalize it, and so is the block this node proposed before.
+            let again = match (&self.valid, &self.proposed) {
+                (Some(valid), _) => Some((Some(valid.round), valid.block.clone())),
+                (None, proposed) => proposed.clone().map(|block| (None, block)),
+            };
+            match again {
+                Some((valid_round, block)) => {
+                    let proposal =
+                        Proposal::new(self.height, round, valid_round, block, self.scheme, wallet)?;
+                    out.push(Output::Proposal(proposal.clone()));
+                    self.add_proposal(proposal)?;
+                }
+                None => out.push(Output::Propose { height: self.height, round }),
+            }
+        }
+        self.schedule(TimeoutStep::Propose, out);
+
+        Ok(())
+    }
+
+    /// Applies the rules of the algorithm to the proposals and votes received so far until none
+    /// of them fires anymore.
+    fn update(&mut self, blockchain: &Blockchain, out: &mut Vec<Output>) -> Result<()> {
+        while !self.decided && self.apply_rule(blockchain, out)? {}
+        Ok(())
+    }
+
+    /// Fires the first rule whose conditions hold. Returns whether one did.
+    fn apply_rule(&mut self, blockchain: &Blockchain, out: &mut Vec<Output>) -> Result<bool> {
+        // A proposal with the precommits of a quorum is final, whatever round this node is in.
+        let decisions: Vec<(Proposal, B256, u64)> = self
+            .proposals
+            .iter()
+            .filter_map(|(round, (proposal, hash))| {
+                let precommits = self.precommits.get(round)?.count(Some(*hash));
+                Some((proposal.clone(), *hash, precommits))
+            })
+            .collect();
+        for (proposal, hash, precommits) in decisions {
+            if self.has_quorum(blockchain, precommits)
+                && self.is_valid(blockchain, &proposal.block, hash)
+            {
+                let votes = &self.precommits[&proposal.round].votes;
+                let certificate = CommitCertificate {
+                    height: self.height,
+                    round: proposal.round,
+                    block_hash: hash,
+                    precommits: votes
+                        .values()
+                        .filter(|vote| vote.block_hash() == Some(hash))
+                        .cloned()
+                        .collect(),
+                };
+                self.decided = true;
+                out.push(Output::Commit { block: proposal.block, certificate });
+                out.push(Output::Schedule {
+                    timeout: Timeout {
+                        height: self.height,
+                        round: self.round,
+                        step: TimeoutStep::Commit,
+                    },
+                    after: Duration::from_secs(self.block_time),
+                });
+                return Ok(true);
+            }
+        }
+
+        let round = self.round;
+        let proposal = self.proposals.get(&round).cloned();
+
+        if let (Step::Propose, Some((proposal, hash))) = (self.step, &proposal) {
+            let prevote =
+                match proposal.valid_round() {
+                    None => Some(self.locked.as_ref().is_none_or(|locked| locked.hash == *hash)),
+                    // A block found valid in an earlier round may be prevoted for by a validator
+                    // locked on another block, as long as it locked before that round.
+                    Some(valid_round)
+                        if valid_round < round
+                            && self.has_quorum(
+                                blockchain,
+                                self.prevotes_for(valid_round, Some(*hash)),
+                            ) =>
+                    {
+                        Some(self.locked.as_ref().is_none_or(|locked| {
+                            locked.round <= valid_round || locked.hash == *hash
+                        }))
+                    }
+                    _ => None,
+                };
+            if let Some(acceptable) = prevote {
+                let valid = acceptable && self.is_valid(blockchain, &proposal.block, *hash);
+                self.vote(VoteStep::Prevote, valid.then_some(*hash), out)?;
+                self.step = Step::Prevote;
+                return Ok(true);
+            }
+        }
+
+        let prevotes = self.prevotes.get(&round).map_or(0, VoteSet::total);
+        if self.step == Step::Prevote
+            && self.has_quorum(blockchain, prevotes)
+            && self.prevote_timeouts.insert(round)
+        {
+            self.schedule(TimeoutStep::Prevote, out);
+            return Ok(true);
+        }
+
+        if let Some((proposal, hash)) = &proposal {
+            if self.step >= Step::Prevote
+                && !self.polkas.contains(&round)
+                && self.has_quorum(blockchain, self.prevotes_for(round, Some(*hash)))
+                && self.is_valid(blockchain, &proposal.block, *hash)
+            {
+                self.polkas.insert(round);
+                let block = RoundBlock { round, block: proposal.block.clone(), hash: *hash };
+                if self.step == Step::Prevote {
+                    let lock =
+                        StoredLock { height: self.height, round, block: block.block.clone() };
+                    blockchain.storage().put_metadata(LOCK_KEY, &serde_json::to_string(&lock)?)?;
+                    self.locked = Some(block.clone());
+                    self.vote(VoteStep::Precommit, Some(*hash), out)?;
+                    self.step = Step::Precommit;
+                }
+                self.valid = Some(block);
+                return Ok(true);
+            }
+        }
+
+        if self.step == Step::Prevote && self.has_quorum(blockchain, self.prevotes_for(round, None))
+        {
+            self.vote(VoteStep::Precommit, None, out)?;
+            self.step = Step::Precommit;
+            return Ok(true);
+        }
+
+        let precommits = self.precommits.get(&round).map_or(0, VoteSet::total);
+        if self.has_quorum(blockchain, precommits) && self.precommit_timeouts.insert(round) {
+            self.schedule(TimeoutStep::Precommit, out);
+            return Ok(true);
+        }
+
+        // More than a third of the validators being in a later round means at least one honest
+        // validator is, so this node catches up instead of waiting for its own timeouts.
+        let validators = self.validators.len() as u64;
+        let later = self
+            .prevotes
+            .keys()
+            .chain(self.precommits.keys())
+            .copied()
+            .find(|later| *later > round && 3 * self.voters(*later) > validators);
+        if let Some(later) = later {
+            self.start_round(later, out)?;
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Signs and counts this node's vote, if it runs with the key of a validator.
+    fn vote(
+        &mut self,
+        step: VoteStep,
+        block_hash: Option<B256>,
+        out: &mut Vec<Output>,
+    ) -> Result<()> {
+        let Some(wallet) = self
+            .wallet
+            .as_ref()
+            .filter(|wallet| self.validators.contains(&Address::from(wallet.address())))
+        else {
+            return Ok(());
+        };
+
+        let vote = RoundVote::new(step, self.height, self.round, block_hash, wallet)?;
+        out.push(Output::Vote(vote.clone()));
+        self.add_vote(vote, out)?;
+
+        Ok(())
+    }
+
+    fn schedule(&self, step: TimeoutStep, out: &mut Vec<Output>) {
+        let timeout = Timeout { height: self.height, round: self.round, step };
+        // Each round waits a block time longer than the one before, so that the validators end
+        // up with rounds long enough to hear from each other.
+        let after = Duration::from_secs(self.block_time * (self.round + 1));
+        out.push(Output::Schedule { timeout, after });
+    }
+
+    fn prevotes_for(&self, round: u64, block_hash: Option<B256>) -> u64 {
+        self.prevotes.get(&round).map_or(0, |votes| votes.count(block_hash))
+    }
+
+    /// Number of validators that cast any vote in `round`.
+    fn voters(&self, round: u64) -> u64 {
+        let mut voters: BTreeSet<&Address> = BTreeSet::new();
+        for votes in [self.prevotes.get(&round), self.precommits.get(&round)].into_iter().flatten()
+        {
+            voters.extend(votes.votes.keys());
+        }
+        voters.len() as u64
+    }
+
+    fn has_quorum(&self, blockchain: &Blockchain, votes: u64) -> bool {
+        blockchain.consensus().has_quorum(votes.into(), self.validators.len() as u128)
+    }
+
+    /// Whether `block` is a valid child of the head of `blockchain`, checked once per block.
+    fn is_valid(&mut self, blockchain: &Blockchain, block: &Block, hash: B256) -> bool {
+        *self.validity.entry(hash).or_insert_with(|| match blockchain.validate_block(block) {
+            Ok(_) => true,
+            Err(err) => {
+                println!("Proposed block {} is invalid: {}", hash, err);
+                false
+            }
+        })
+    }
+}
+
+fn check_round(round: u64, current: u64) -> Result<()> {
+    if round > current.saturating_add(MAX_FUTURE_ROUNDS) {
+        bail!("round {} is too far ahead of round {}", round, current);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blockchain::get_last_block,
+        chainspec::{ChainSpec, ConsensusConfig},
+        storage::MemoryStorage,
+        validator::ValidatorSet,
+    };
+    use std::sync::Arc;
+
+    const KEYS: [&str; 4] = [
+        "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80",
+        "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d",
+        "5de4111afa1a4b94908f83103eb1f1706367c2e68ca870fc3fb9a804cdab365a",
+        "7c852118294e51e653712a81e05800f419141751be58f605c371e15141b007a6",
+    ];
+
+    fn wallet(index: usize) -> LocalWallet {
+        KEYS[index].parse().unwrap()
+    }
+
+    /// A Tendermint chain with the four validators of `KEYS`, in that order, so that the
+    /// validator of `KEYS[(height + round) % 4]` proposes.
+    fn blockchain() -> Blockchain {
+        let validators = (0..KEYS.len()).map(|index| Address::from(wallet(index).address()));
+        let spec = ChainSpec {
+            consensus: ConsensusConfig::Tendermint,
+            validators: ValidatorSet::new(validators.collect()).unwrap(),
+            ..ChainSpec::default()
+        };
+        Blockchain::init(Arc::new(MemoryStorage::new()), spec).unwrap()
+    }
+
+    fn votes(out: &[Output]) -> Vec<&RoundVote> {
+        out.iter()
+            .filter_map(|output| match output {
+                Output::Vote(vote) => Some(vote),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn timeouts(out: &[Output]) -> Vec<(Timeout, Duration)> {
+        out.iter()
+            .filter_map(|output| match output {
+                Output::Schedule { timeout, after } => Some((*timeout, *after)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Feeds `bft` the votes of the validators at `voters` and returns the outputs.
+    fn cast(
+        bft: &mut BftState,
+        blockchain: &Blockchain,
+        step: VoteStep,
+        round: u64,
+        block_hash: Option<B256>,
+        voters: &[usize],
+    ) -> Vec<Output> {
+        let mut out = vec![];
+        for voter in voters {
+            let vote = RoundVote::new(step, 1, round, block_hash, &wallet(*voter)).unwrap();
+            out.extend(bft.on_vote(blockchain, vote).unwrap());
+        }
+        out
+    }
+
+    #[test]
+    fn prevotes_nil_when_the_proposal_times_out() {
+        let blockchain = blockchain();
+        let mut bft = BftState::new(&blockchain, Some(wallet(0)));
+
+        let out = bft.start_height(&blockchain).unwrap();
+        assert_eq!((bft.height(), bft.round()), (1, 0));
+        assert_eq!(bft.proposer(0), Address::from(wallet(1).address()));
+        let timeout = Timeout { height: 1, round: 0, step: TimeoutStep::Propose };
+        assert_eq!(timeouts(&out), [(timeout, Duration::from_secs(5))]);
+
+        let out = bft.on_timeout(&blockchain, timeout).unwrap();
+        let prevotes = votes(&out);
+        assert_eq!(prevotes.len(), 1);
+        assert_eq!((prevotes[0].step, prevotes[0].block_hash()), (VoteStep::Prevote, None));
+    }
+
+    #[test]
+    fn moves_to_the_next_round_with_a_longer_timeout() {
+        let blockchain = blockchain();
+        let mut bft = BftState::new(&blockchain, Some(wallet(0)));
+        bft.start_height(&blockchain).unwrap();
+        let timeout = |step| Timeout { height: 1, round: 0, step };
+        bft.on_timeout(&blockchain, timeout(TimeoutStep::Propose)).unwrap();
+
+        // Nil prevotes of a quorum make this node precommit nil.
+        let out = cast(&mut bft, &blockchain, VoteStep::Prevote, 0, None, &[1, 2]);
+        let precommits = votes(&out);
+        assert_eq!(precommits.len(), 1);
+        assert_eq!((precommits[0].step, precommits[0].block_hash()), (VoteStep::Precommit, None));
+
+        let out = cast(&mut bft, &blockchain, VoteStep::Precommit, 0, None, &[1, 2]);
+        assert_eq!(timeouts(&out), [(timeout(TimeoutStep::Precommit), Duration::from_secs(5))]);
+
+        let out = bft.on_timeout(&blockchain, timeout(TimeoutStep::Precommit)).unwrap();
+        assert_eq!(bft.round(), 1);
+        let next = Timeout { height: 1, round: 1, step: TimeoutStep::Propose };
+        assert_eq!(timeouts(&out), [(next, Duration::from_secs(10))]);
+
+        // Timeouts of a round that is over do nothing.
+        assert!(bft.on_timeout(&blockchain, timeout(TimeoutStep::Propose)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn catches_up_with_a_later_round_of_more_than_a_third() {
+        let blockchain = blockchain();
+        let mut bft = BftState::new(&blockchain, Some(wallet(0)));
+        bft.start_height(&blockchain).unwrap();
+
+        cast(&mut bft, &blockchain, VoteStep::Prevote, 3, None, &[1]);
+        assert_eq!(bft.round(), 0);
+        cast(&mut bft, &blockchain, VoteStep::Prevote, 3, None, &[2]);
+        assert_eq!(bft.round(), 3);
+    }
+
+    #[test]
+    fn refuses_messages_of_the_wrong_proposer_or_too_far_ahead() {
+        let blockchain = blockchain();
+        let mut bft = BftState::new(&blockchain, Some(wallet(0)));
+        bft.start_height(&blockchain).unwrap();
+        let genesis = get_last_block(blockchain.storage()).unwrap();
+        let block = blockchain.propose_block(vec![], vec![], &genesis, &wallet(2)).unwrap();
+
+        let proposal = Proposal::new(1, 0, None, block, HashScheme::Rlp, &wallet(2)).unwrap();
+        let err = bft.on_proposal(&blockchain, proposal).unwrap_err();
+        assert!(err.to_string().contains("which belongs to"), "{}", err);
+
+        let ahead = MAX_FUTURE_ROUNDS + 1;
+        let vote = RoundVote::new(VoteStep::Prevote, 1, ahead, None, &wallet(1)).unwrap();
+        let err = bft.on_vote(&blockchain, vote).unwrap_err();
+        assert!(err.to_string().contains("too far ahead"), "{}", err);
+
+        let outsider = LocalWallet::from_bytes(&[0x11; 32]).unwrap();
+        let vote = RoundVote::new(VoteStep::Prevote, 1, 0, None, &outsider).unwrap();
+        let err = bft.on_vote(&blockchain, vote).unwrap_err();
+        assert!(err.to_string().contains("is not a validator"), "{}", err);
+    }
+
+    #[test]
+    fn reports_a_validator_that_precommits_twice_in_a_round() {
+        let blockchain = blockchain();
+        let mut bft = BftState::new(&blockchain, None);
+        bft.start_height(&blockchain).unwrap();
+        let (block, other) = (Some(B256::from([1; 32])), Some(B256::from([2; 32])));
+
+        cast(&mut bft, &blockchain, VoteStep::Precommit, 0, block, &[0, 1]);
+        let out = cast(&mut bft, &blockchain, VoteStep::Precommit, 0, other, &[1]);
+        let [Output::Evidence(evidence)] = out.as_slice() else {
+            panic!("no evidence of the second precommit: {:?}", out);
+        };
+        evidence.verify(HashScheme::Rlp).unwrap();
+        assert_eq!(evidence.offender(), Address::from(wallet(1).address()));
+        assert_eq!(evidence.height(), 1);
+
+        // Only the first precommit counts, so the block still lacks a quorum.
+        let out = cast(&mut bft, &blockchain, VoteStep::Precommit, 0, other, &[2]);
+        assert!(!out.iter().any(|output| matches!(output, Output::Commit { .. })));
+        assert_eq!(bft.precommits[&0].count(other), 1);
+    }
+
+    #[test]
+    fn commits_a_block_with_the_precommits_of_a_quorum() {
+        let mut blockchain = blockchain();
+        let mut bft = BftState::new(&blockchain, Some(wallet(0)));
+        bft.start_height(&blockchain).unwrap();
+        let genesis = get_last_block(blockchain.storage()).unwrap();
+        let block = blockchain.propose_block(vec![], vec![], &genesis, &wallet(1)).unwrap();
+        let hash = block.get_block_hash(HashScheme::Rlp).unwrap();
+
+        let proposal = Proposal::new(1, 0, None, block, HashScheme::Rlp, &wallet(1)).unwrap();
+        let out = bft.on_proposal(&blockchain, proposal).unwrap();
+        assert_eq!(votes(&out)[0].block_hash(), Some(hash));
+
+        let out = cast(&mut bft, &blockchain, VoteStep::Prevote, 0, Some(hash), &[1, 2]);
+        let precommit = votes(&out).into_iter().find(|vote| vote.step == VoteStep::Precommit);
+        assert_eq!(precommit.unwrap().block_hash(), Some(hash));
+
+        let out = cast(&mut bft, &blockchain, VoteStep::Precommit, 0, Some(hash), &[1, 2]);
+        let Some(Output::Commit { block, certificate }) =
+            out.into_iter().find(|output| matches!(output, Output::Commit { .. }))
+        else {
+            panic!("no commit after the precommits of a quorum");
+        };
+        assert_eq!(certificate.verify(&blockchain.validators().unwrap()).unwrap(), 3);
+
+        blockchain.commit_certified_block(block, certificate).unwrap();
+        let timeout = Timeout { height: 1, round: 0, step: TimeoutStep::Commit };
+        bft.on_timeout(&blockchain, timeout).unwrap();
+        assert_eq!((bft.height(), bft.round()), (2, 0));
+        assert_eq!(blockchain.get_commit_certificate(1).unwrap().unwrap().block_hash, hash);
+    }
+
+    #[test]
+    fn proposes_the_same_block_again_in_its_next_round() {
+        let blockchain = blockchain();
+        let mut bft = BftState::new(&blockchain, Some(wallet(1)));
+        let out = bft.start_height(&blockchain).unwrap();
+        assert!(matches!(out[0], Output::Propose { height: 1, round: 0 }));
+        let genesis = get_last_block(blockchain.storage()).unwrap();
+        let block = blockchain.propose_block(vec![], vec![], &genesis, &wallet(1)).unwrap();
+        let hash = block.get_block_hash(HashScheme::Rlp).unwrap();
+        bft.propose(&blockchain, block).unwrap();
+
+        // The validator of `KEYS[1]` proposes again four rounds later.
+        let mut out = vec![];
+        for round in 0..4 {
+            let timeout = Timeout { height: 1, round, step: TimeoutStep::Precommit };
+            out = bft.on_timeout(&blockchain, timeout).unwrap();
+        }
+        let Some(Output::Proposal(proposal)) =
+            out.into_iter().find(|output| matches!(output, Output::Proposal(_)))
+        else {
+            panic!("no proposal in round 4");
+        };
+        assert_eq!((proposal.round, proposal.valid_round), (4, NO_ROUND));
+        assert_eq!(proposal.block.get_block_hash(HashScheme::Rlp).unwrap(), hash);
+    }
+
+    #[test]
+    fn keeps_its_lock_across_restarts() {
+        let blockchain = blockchain();
+        let mut bft = BftState::new(&blockchain, Some(wallet(0)));
+        bft.start_height(&blockchain).unwrap();
+        let genesis = get_last_block(blockchain.storage()).unwrap();
+        let block = blockchain.propose_block(vec![], vec![], &genesis, &wallet(1)).unwrap();
+        let hash = block.get_block_hash(HashScheme::Rlp).unwrap();
+        let mut other = block.clone();
+        other.header.timestamp += 1;
+        blockchain.consensus().seal(&mut other.header, &wallet(1)).unwrap();
+
+        let proposal = Proposal::new(1, 0, None, block, HashScheme::Rlp, &wallet(1)).unwrap();
+        bft.on_proposal(&blockchain, proposal).unwrap();
+        let out = cast(&mut bft, &blockchain, VoteStep::Prevote, 0, Some(hash), &[1, 2]);
+        assert!(votes(&out).iter().any(|vote| vote.step == VoteStep::Precommit));
+
+        // After a restart the node does not prevote for another block at the same height.
+        let mut restarted = BftState::new(&blockchain, Some(wallet(0)));
+        restarted.start_height(&blockchain).unwrap();
+        let proposal = Proposal::new(1, 0, None, other, HashScheme::Rlp, &wallet(1)).unwrap();
+        let out = restarted.on_proposal(&blockchain, proposal).unwrap();
+        assert_eq!(votes(&out)[0].block_hash(), None);
+    }
+}
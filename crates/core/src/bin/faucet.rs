@@ -0,0 +1,49 @@
+use clap::Parser;
+use eyre::{eyre, Result};
+use helper::Address;
+use rustic_chain_of_blocks::{chainspec::ChainSpec, datadir::DataDir, faucet::Faucet};
+use std::{env, path::PathBuf};
+
+/// Environment variable holding the private key of the faucet account.
+const FAUCET_KEY_VAR: &str = "FAUCET_PRIVATE_KEY";
+
+#[derive(Parser)]
+#[command(about = "Funds an address on a Rustic Chain of Blocks devnet")]
+struct Args {
+    /// Address to fund.
+    address: Address,
+
+    /// Data directory of the node that should receive the transaction.
+    #[arg(long, default_value = ".")]
+    datadir: PathBuf,
+
+    /// Tokens to send.
+    #[arg(long, default_value_t = 100)]
+    amount: u64,
+
+    /// Seconds an address or requester has to wait before it can be funded again.
+    #[arg(long, default_value_t = 24 * 60 * 60)]
+    cooldown: u64,
+
+    /// Who asked for the tokens, such as the IP address of a web request, held to the same
+    /// cooldown as the address.
+    #[arg(long)]
+    requester: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let storage = DataDir::new(args.datadir).open_storage()?;
+
+    let pk = env::var(FAUCET_KEY_VAR)
+        .map_err(|_| eyre!("set {} to the private key of the faucet account", FAUCET_KEY_VAR))?;
+    let faucet = Faucet::new(&pk, args.amount, args.cooldown)?;
+
+    let spec = ChainSpec::load(&storage)?.unwrap_or_default();
+    faucet.fund(&storage, &spec, &args.address, args.requester.as_deref()).await?;
+
+    println!("🚰 Sent {} from faucet {} to {} 🚰", args.amount, faucet.address(), args.address);
+
+    Ok(())
+}
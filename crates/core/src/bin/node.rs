@@ -1,6 +1,9 @@
 use alloy_rlp::{Decodable, Encodable};
-use eyre::Result;
+use clap::Parser;
+use ethers::signers::{LocalWallet, Signer};
+use eyre::{eyre, Result};
 use futures::stream::StreamExt;
+use helper::{Address, B256};
 use libp2p::{
     gossipsub, mdns, noise,
     swarm::{NetworkBehaviour, SwarmEvent},
@@ -8,24 +11,108 @@ use libp2p::{
 };
 use once_cell::sync::Lazy;
 use rustic_chain_of_blocks::{
-    account::accounts_init,
-    block::Block,
+    bft::{BftState, Output, Proposal, RoundVote, Timeout, VoteStep},
+    block::{Block, Header},
     blockchain::{get_last_block, get_last_n_blocks, Blockchain},
-    mempool::{get_all_transaction_reqs, mempool_init},
-    p2p::{P2PMessage, VoteOnBlock},
-    transaction::Transaction,
+    chainspec::{ChainSpec, ConsensusConfig, HashScheme, DEFAULT_CHAIN_ID},
+    datadir::DataDir,
+    evidence::{Evidence, EvidencePool},
+    mempool::get_all_transactions,
+    p2p::{NBlocks, P2PMessage, VoteOnBlock},
+    state::State,
+    transaction::{Transaction, Transactions},
+    vote::VotePool,
 };
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, VecDeque},
+    env,
     hash::{Hash, Hasher},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
     time::Duration,
 };
-use tokio::{io, select, time::interval};
+use tokio::{
+    io, select,
+    task::{self, JoinHandle},
+    time::{interval, sleep_until, Instant},
+};
 use tracing_subscriber::EnvFilter;
 
+/// Environment variable holding the private key of the validator this node proposes blocks as.
+const VALIDATOR_KEY_VAR: &str = "VALIDATOR_PRIVATE_KEY";
+
+/// Blocks before the local head a proof of work node asks for along with a block it cannot place,
+/// so that a branch which forked off a little while ago can still be switched to.
+const REORG_LOOKBACK: u64 = 16;
+
 static TOPIC: Lazy<gossipsub::IdentTopic> =
     Lazy::new(|| gossipsub::IdentTopic::new("Rustic Chain of Blocks"));
 
+#[derive(Parser)]
+#[command(about = "Runs a Rustic Chain of Blocks node")]
+struct Args {
+    /// Directory the node keeps its blocks, accounts and mempool in.
+    #[arg(long, default_value = ".")]
+    datadir: PathBuf,
+
+    /// JSON chain spec of the network to join. Without one the node runs the default network
+    /// with the given chain id.
+    #[arg(long, conflicts_with_all = ["chain_id", "legacy_hashing"])]
+    chain: Option<PathBuf>,
+
+    /// Chain id of the network, which transactions must be signed for.
+    #[arg(long, default_value_t = DEFAULT_CHAIN_ID)]
+    chain_id: u64,
+
+    /// Hash blocks and transactions with the legacy scheme, for networks whose chain was started
+    /// before blocks were hashed over their RLP encoding.
+    #[arg(long)]
+    legacy_hashing: bool,
+
+    /// Vote to add this address to the signers in the blocks this node proposes, under Clique.
+    #[arg(long, value_name = "ADDRESS")]
+    authorize: Vec<Address>,
+
+    /// Vote to remove this address from the signers in the blocks this node proposes, under
+    /// Clique.
+    #[arg(long, value_name = "ADDRESS")]
+    deauthorize: Vec<Address>,
+}
+
+impl Args {
+    fn chain_spec(&self) -> Result<ChainSpec> {
+        match &self.chain {
+            Some(path) => ChainSpec::from_file(path),
+            None if self.legacy_hashing => Ok(ChainSpec::new(self.chain_id, HashScheme::Legacy)),
+            None => Ok(ChainSpec::new(self.chain_id, HashScheme::Rlp)),
+        }
+    }
+}
+
+/// A proposed block collecting votes until the next tick, when it is committed if enough
+/// validators voted for it.
+struct Round {
+    block: Block,
+    block_hash: B256,
+}
+
+/// How the node agrees with the other validators on the next block, which depends on the
+/// consensus engine of the chain.
+#[allow(clippy::large_enum_variant)]
+enum Agreement {
+    /// Every tick either proposes a block or commits the [`Round`] of the last one once enough
+    /// validators voted for it.
+    /// The last block this node proposed is proposed again while its parent is the head, as
+    /// sealing a second block at the same height would be evidence against the node.
+    Votes { round: Option<Round>, votes: VotePool, proposed: Option<Block> },
+    /// Tendermint rounds run by a [`BftState`], with the timeouts it asked for.
+    Bft { state: BftState, timeouts: Vec<(Instant, Timeout)> },
+    /// Proof of work: every tick starts mining the next block unless the node is still mining
+    /// one, and blocks mined by others are committed right away.
+    Work,
+}
+
 #[derive(NetworkBehaviour)]
 struct RCOBBehaviour {
     gossipsub: gossipsub::Behaviour,
@@ -34,6 +121,11 @@ struct RCOBBehaviour {
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
+    let spec = args.chain_spec()?;
+    let datadir = DataDir::new(args.datadir);
+    let _lock = datadir.lock()?;
+
     let _ = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env()).try_init();
 
     let mut swarm = libp2p::SwarmBuilder::with_new_identity()
@@ -52,7 +144,7 @@ async fn main() -> Result<()> {
                 .validation_mode(gossipsub::ValidationMode::Strict)
                 .message_id_fn(message_id_fn)
                 .build()
-                .map_err(|msg| io::Error::new(io::ErrorKind::Other, msg))?;
+                .map_err(io::Error::other)?;
 
             let gossipsub = gossipsub::Behaviour::new(
                 gossipsub::MessageAuthenticity::Signed(key.clone()),
@@ -73,41 +165,170 @@ async fn main() -> Result<()> {
 
     println!("🦀 Blockchain is live! 🦀");
 
-    accounts_init()?;
-    mempool_init()?;
-    let mut blockchain = Blockchain::init()?;
+    let storage = Arc::new(datadir.open_storage()?);
+    let mut blockchain = Blockchain::init(storage.clone(), spec)?;
+    storage.compact()?;
 
-    let mut block_time = interval(Duration::from_secs(5));
+    let mut block_time = interval(Duration::from_secs(blockchain.spec().block_time));
     block_time.tick().await;
 
-    let mut proposed_block = Block::genesis()?;
-    let mut yes_votes: u64 = 0;
-    let mut proposed = false;
+    for candidate in &args.authorize {
+        blockchain.consensus().propose_vote(*candidate, true)?;
+    }
+    for candidate in &args.deauthorize {
+        blockchain.consensus().propose_vote(*candidate, false)?;
+    }
+
+    let wallet = validator_wallet()?;
+    let mut evidence_pool = EvidencePool::new();
+    let mut agreement = if blockchain.consensus().requires_certificate() {
+        let mut state = BftState::new(&blockchain, wallet.clone());
+        let outputs = state.start_height(&blockchain)?;
+        let mut agreement = Agreement::Bft { state, timeouts: vec![] };
+        handle_outputs(
+            &mut swarm,
+            &mut blockchain,
+            &mut agreement,
+            &mut evidence_pool,
+            wallet.as_ref(),
+            outputs,
+        )
+        .await?;
+        agreement
+    } else if let ConsensusConfig::ProofOfWork { .. } = blockchain.spec().consensus {
+        Agreement::Work
+    } else {
+        Agreement::Votes { round: None, votes: VotePool::new(), proposed: None }
+    };
+    // Proof of work mines on a blocking thread, so that the node keeps up with the network in the
+    // meantime.
+    let mut mining: Option<JoinHandle<Result<Block>>> = None;
 
     loop {
+        let deadline = match &agreement {
+            Agreement::Bft { timeouts, .. } => timeouts.iter().map(|(at, _)| *at).min(),
+            Agreement::Votes { .. } | Agreement::Work => None,
+        };
+
         select! {
+            _ = sleep_until(deadline.unwrap_or_else(far_future)), if deadline.is_some() => {
+                let Agreement::Bft { state, timeouts } = &mut agreement else {
+                    continue;
+                };
+                let now = Instant::now();
+                let (due, pending): (Vec<_>, Vec<_>) =
+                    timeouts.drain(..).partition(|(at, _)| *at <= now);
+                *timeouts = pending;
+
+                let mut outputs = vec![];
+                for (_, timeout) in due {
+                    outputs.extend(state.on_timeout(&blockchain, timeout)?);
+                }
+                handle_outputs(
+                    &mut swarm,
+                    &mut blockchain,
+                    &mut agreement,
+                    &mut evidence_pool,
+                    wallet.as_ref(),
+                    outputs,
+                )
+                .await?;
+            }
+            mined = async { mining.as_mut().unwrap().await }, if mining.is_some() => {
+                mining = None;
+                let block = match mined? {
+                    Ok(block) => block,
+                    Err(err) => {
+                        println!("Failed to mine block: {}", err);
+                        continue;
+                    }
+                };
+                // Someone else may have found a block at the same height first.
+                if let Err(err) = blockchain.commit_block(block.clone()) {
+                    println!("Failed to commit mined block: {}", err);
+                    continue;
+                }
+                if let Err(err) = handle_send_block(&mut swarm, 5, block).await {
+                    println!("Failed to send mined block: {}", err);
+                }
+            }
             _ = block_time.tick() => {
-                if !proposed {
-                    let reqs = get_all_transaction_reqs()?;
-                    let mut txs = vec![];
-                    if !reqs.is_empty() {
-                        for req in reqs {
-                            let tx = Transaction::new(req.from, req.to, req.value, req.pk).await?;
-                            txs.push(tx.clone());
-                            handle_send_tx(&mut swarm, tx.clone()).await?;
+                let (round, votes, proposed) = match &mut agreement {
+                    Agreement::Votes { round, votes, proposed } => (round, votes, proposed),
+                    Agreement::Work => {
+                        if let (None, Some(wallet)) = (&mining, &wallet) {
+                            let parent_block = get_last_block(blockchain.storage())?;
+                            let txs = collect_transactions(&mut swarm, &blockchain).await?;
+                            let evidence =
+                                collect_evidence(&blockchain, &mut evidence_pool, &parent_block);
+                            let (blockchain, wallet) = (blockchain.clone(), wallet.clone());
+                            mining = Some(task::spawn_blocking(move || {
+                                blockchain.propose_block(txs, evidence, &parent_block, &wallet)
+                            }));
                         }
+                        continue;
+                    }
+                    Agreement::Bft { state, .. } => {
+                        let outputs = state.rebroadcast();
+                        handle_outputs(
+                            &mut swarm,
+                            &mut blockchain,
+                            &mut agreement,
+                            &mut evidence_pool,
+                            wallet.as_ref(),
+                            outputs,
+                        )
+                        .await?;
+                        continue;
                     }
-                    let parent_block = get_last_block()?;
-                    proposed_block = blockchain.propose_block(txs.clone(), &parent_block)?;
-                    handle_send_block(&mut swarm, 5, proposed_block.clone()).await?;
-                    proposed = true;
-                } else {
-                    if yes_votes > (swarm.connected_peers().count() / 2).try_into()? {
-                        println!("Got majority votes, finalizing the block...");
-                        blockchain.commit_block(proposed_block.clone())?;
+                };
+                if let Some(round) = round.take() {
+                    let number = round.block.header.number;
+                    let yes_voters = votes.yes_voters(number, &round.block_hash);
+                    if blockchain.has_quorum(&yes_voters)? {
+                        println!(
+                            "Got {} validator votes, finalizing the block...",
+                            yes_voters.len()
+                        );
+                        let certificate = votes.certificate(number, &round.block_hash);
+                        match blockchain.commit_voted_block(round.block, certificate) {
+                            Ok(()) => votes.prune(number),
+                            Err(err) => println!("Failed to commit block: {}", err),
+                        }
                     }
-                    yes_votes = 0;
-                    proposed = false;
+                    continue;
+                }
+
+                let parent_block = get_last_block(blockchain.storage())?;
+                let proposer = match &wallet {
+                    Some(wallet)
+                        if blockchain
+                            .can_propose(&parent_block, &Address::from(wallet.address()))? =>
+                    {
+                        Some(wallet)
+                    }
+                    _ => None,
+                };
+                if let Some(wallet) = proposer {
+                    let parent_hash = parent_block.get_block_hash(blockchain.spec().hash_scheme)?;
+                    let block = match proposed.take() {
+                        Some(block) if block.header.parent_hash == parent_hash => block,
+                        _ => {
+                            build_block(
+                                &mut swarm,
+                                &blockchain,
+                                &mut evidence_pool,
+                                &parent_block,
+                                wallet,
+                            )
+                            .await?
+                        }
+                    };
+                    *proposed = Some(block.clone());
+                    handle_send_block(&mut swarm, 5, block.clone()).await?;
+                    cast_vote(&mut swarm, &blockchain, votes, Some(wallet), &block, true).await?;
+                    let block_hash = block.get_block_hash(blockchain.spec().hash_scheme)?;
+                    *round = Some(Round { block, block_hash });
                 }
             }
             event = swarm.select_next_some() => match event {
@@ -128,7 +349,16 @@ async fn main() -> Result<()> {
                     message_id: _id,
                     message,
                 })) => {
-                    handle_message(&mut swarm, peer_id, message.data, &mut yes_votes).await?
+                    handle_message(
+                        &mut swarm,
+                        &mut blockchain,
+                        &mut agreement,
+                        &mut evidence_pool,
+                        wallet.as_ref(),
+                        peer_id,
+                        message.data,
+                    )
+                    .await?
                 },
                 SwarmEvent::NewListenAddr { address, .. } => {
                     println!("Blockchain is live on {address}");
@@ -141,9 +371,12 @@ async fn main() -> Result<()> {
 
 async fn handle_message(
     swarm: &mut Swarm<RCOBBehaviour>,
+    blockchain: &mut Blockchain,
+    agreement: &mut Agreement,
+    evidence_pool: &mut EvidencePool,
+    wallet: Option<&LocalWallet>,
     peer_id: PeerId,
     message: Vec<u8>,
-    yes_votes: &mut u64,
 ) -> Result<()> {
     let recv_msg = serde_json::from_slice::<P2PMessage>(&message)?;
     let code = None;
@@ -158,8 +391,10 @@ async fn handle_message(
             let data = Some(out);
             let msg = P2PMessage { id: 1, code, want, data, random };
             let msgjson = serde_json::to_string(&msg)?;
-            swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())?;
-            println!("Sent Pong in response to Ping from {peer_id}");
+            match swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes()) {
+                Ok(_) => println!("Sent Pong in response to Ping from {peer_id}"),
+                Err(err) => println!("Failed to send Pong: {}", err),
+            }
         }
         1 => (),
         2 => {
@@ -168,39 +403,229 @@ async fn handle_message(
             let data = Some(out);
             let msg = P2PMessage { id: 3, code, want, data, random };
             let msgjson = serde_json::to_string(&msg)?;
-            swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())?;
-            println!("Sent {} in response to Address from {peer_id}", address);
+            match swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes()) {
+                Ok(_) => println!("Sent {} in response to Address from {peer_id}", address),
+                Err(err) => println!("Failed to send address: {}", err),
+            }
         }
         3 => (),
         4 => (),
-        5 => (),
+        5 => {
+            if let Agreement::Bft { .. } = agreement {
+                return Ok(());
+            }
+            let recv_block = recv_msg.data.unwrap();
+            let block = Block::decode(&mut recv_block.as_slice())?;
+            println!("Received block {} proposed by {}", block.header.number, block.header.miner);
+            detect_double_sign(swarm, blockchain, evidence_pool, &block.header)?;
+            if let Agreement::Work = agreement {
+                return import_mined_block(swarm, blockchain, block);
+            }
+
+            let next_number = get_last_block(blockchain.storage())?.header.number + 1;
+            if block.header.number > next_number {
+                println!("Local chain is behind the proposed block, requesting blocks...");
+                // Miners send their blocks once they committed them, so ask for the proposed
+                // block too.
+                let want = Some(block.header.number - next_number + 1);
+                let msg = P2PMessage { id: 6, code, want, data: None, random };
+                let msgjson = serde_json::to_string(&msg)?;
+                if let Err(err) =
+                    swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())
+                {
+                    println!("Failed to send block request: {}", err);
+                }
+            } else if block.header.number == next_number {
+                match agreement {
+                    Agreement::Votes { round, votes, .. } if round.is_none() => {
+                        // Another validator's turn: vote on its block like a P2P node and commit
+                        // it once it has the quorum, so the chain is in place when this node's turn
+                        // comes.
+                        let approve = match blockchain.validate_block(&block) {
+                            Ok(_) => true,
+                            Err(err) => {
+                                println!("Proposed block is invalid: {}", err);
+                                false
+                            }
+                        };
+                        cast_vote(swarm, blockchain, votes, wallet, &block, approve).await?;
+                        if approve {
+                            blockchain.note_proposal()?;
+                            let block_hash = block.get_block_hash(blockchain.spec().hash_scheme)?;
+                            *round = Some(Round { block, block_hash });
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
         6 => {
             let num_blocks = recv_msg.want.unwrap();
-            let blocks = get_last_n_blocks(num_blocks.try_into()?)?;
+            let blocks = get_last_n_blocks(blockchain.storage(), num_blocks.try_into()?)?;
             blocks.encode(&mut out);
             let data = Some(out);
             let msg = P2PMessage { id: 7, code, want, data, random };
             let msgjson = serde_json::to_string(&msg)?;
-            swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())?;
-            println!("Sent Block in response to GetBlock from {peer_id}");
+            match swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes()) {
+                Ok(_) => println!("Sent Block in response to GetBlock from {peer_id}"),
+                Err(err) => println!("Failed to send blocks: {}", err),
+            }
+        }
+        7 => {
+            let recv_blocks = recv_msg.data.unwrap();
+            let decoded_blocks = NBlocks::decode(&mut recv_blocks.as_slice())?;
+            println!("Received {} blocks from {peer_id}", decoded_blocks.blocks.len());
+            let head = get_last_block(blockchain.storage())?.header.number;
+            if let Agreement::Work = agreement {
+                // The blocks may be a heavier branch that forked off before the local head.
+                if let Err(err) = blockchain.import_branch(decoded_blocks.blocks) {
+                    println!("Failed to import blocks: {}", err);
+                }
+                return Ok(());
+            }
+            for block in &decoded_blocks.blocks {
+                let number = block.header.number;
+                if number != get_last_block(blockchain.storage())?.header.number + 1 {
+                    continue;
+                }
+                // Blocks are only final with the votes or the commit certificate of a quorum.
+                let imported =
+                    match (decoded_blocks.certificate(number), decoded_blocks.votes(number)) {
+                        (Some(certificate), _) => {
+                            blockchain.commit_certified_block(block.clone(), certificate.clone())
+                        }
+                        (None, Some(votes)) => {
+                            blockchain.commit_voted_block(block.clone(), votes.clone())
+                        }
+                        (None, None) => {
+                            Err(eyre!("block {} came without the votes that finalized it", number))
+                        }
+                    };
+                if let Err(err) = imported {
+                    println!("Failed to import block: {}", err);
+                    break;
+                }
+            }
+
+            // Whatever height the node was agreeing on or mining is behind it now.
+            if get_last_block(blockchain.storage())?.header.number != head {
+                match agreement {
+                    Agreement::Bft { state, .. } => {
+                        let outputs = state.start_height(blockchain)?;
+                        handle_outputs(
+                            swarm,
+                            blockchain,
+                            agreement,
+                            evidence_pool,
+                            wallet,
+                            outputs,
+                        )
+                        .await?;
+                    }
+                    Agreement::Votes { .. } | Agreement::Work => (),
+                }
+            }
         }
-        7 => (),
         8 => {
-            let block_num = get_last_block()?.header.number;
+            let block_num = get_last_block(blockchain.storage())?.header.number;
             block_num.encode(&mut out);
             let data = Some(out);
             let msg = P2PMessage { id: 9, code, want, data, random };
             let msgjson = serde_json::to_string(&msg)?;
-            swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())?;
-            println!("Sent GetLatestBlockResponse in response to GetLatestBlock from {peer_id}");
+            match swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes()) {
+                Ok(_) => println!(
+                    "Sent GetLatestBlockResponse in response to GetLatestBlock from {peer_id}"
+                ),
+                Err(err) => println!("Failed to send latest block number: {}", err),
+            }
         }
         9 => (),
         10 => {
+            let Agreement::Votes { votes, .. } = agreement else {
+                return Ok(());
+            };
             let recv_data = recv_msg.data.unwrap();
             let recv_vote = VoteOnBlock::decode(&mut recv_data.as_slice())?;
-            println!("Received {} for block number {}", recv_vote.vote, recv_vote.block_number);
-            if recv_vote.vote == "YES" {
-                *yes_votes += 1;
+            let (voter, approve, block_number) =
+                (recv_vote.voter, recv_vote.approve, recv_vote.block_number);
+            if let Some(first) = votes.conflicting(&recv_vote) {
+                let evidence =
+                    Evidence::DoubleVote { first: first.clone(), second: recv_vote.clone() };
+                if evidence.verify(blockchain.spec().hash_scheme).is_ok()
+                    && evidence_pool.add(evidence.clone())
+                {
+                    println!("Caught {} voting twice on block {}", voter, block_number);
+                    publish_evidence(swarm, &evidence)?;
+                }
+            }
+            match votes.add(recv_vote, &blockchain.validators()?) {
+                Ok(true) => println!(
+                    "Received {} from {} for block number {}",
+                    if approve { "YES" } else { "NO" },
+                    voter,
+                    block_number
+                ),
+                Ok(false) => (),
+                Err(err) => println!("Ignoring vote from {}: {}", voter, err),
+            }
+        }
+        11 => {
+            let Agreement::Bft { state, .. } = agreement else {
+                return Ok(());
+            };
+            let recv_data = recv_msg.data.unwrap();
+            let proposal = Proposal::decode(&mut recv_data.as_slice())?;
+            let (proposer, height, round) = (proposal.proposer, proposal.height, proposal.round);
+            println!("Received proposal for block {} in round {} from {}", height, round, proposer);
+            detect_double_sign(swarm, blockchain, evidence_pool, &proposal.block.header)?;
+
+            let next_number = get_last_block(blockchain.storage())?.header.number + 1;
+            if height > next_number {
+                println!("Local chain is behind the proposed block, requesting blocks...");
+                let want = Some(height - next_number);
+                let msg = P2PMessage { id: 6, code, want, data: None, random };
+                let msgjson = serde_json::to_string(&msg)?;
+                if let Err(err) =
+                    swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())
+                {
+                    println!("Failed to send block request: {}", err);
+                }
+            }
+
+            match state.on_proposal(blockchain, proposal) {
+                Ok(outputs) => {
+                    handle_outputs(swarm, blockchain, agreement, evidence_pool, wallet, outputs)
+                        .await?
+                }
+                Err(err) => println!("Ignoring proposal from {}: {}", proposer, err),
+            }
+        }
+        12 => {
+            let Agreement::Bft { state, .. } = agreement else {
+                return Ok(());
+            };
+            let recv_data = recv_msg.data.unwrap();
+            let vote = RoundVote::decode(&mut recv_data.as_slice())?;
+            let voter = vote.voter;
+            match state.on_vote(blockchain, vote) {
+                Ok(outputs) => {
+                    handle_outputs(swarm, blockchain, agreement, evidence_pool, wallet, outputs)
+                        .await?
+                }
+                Err(err) => println!("Ignoring vote from {}: {}", voter, err),
+            }
+        }
+        13 => {
+            let recv_data = recv_msg.data.unwrap();
+            let evidence = Evidence::decode(&mut recv_data.as_slice())?;
+            let (offender, height) = (evidence.offender(), evidence.height());
+            match evidence.verify(blockchain.spec().hash_scheme) {
+                Ok(()) => {
+                    if evidence_pool.add(evidence) {
+                        println!("Received evidence against {} for block {}", offender, height);
+                    }
+                }
+                Err(err) => println!("Ignoring evidence against {}: {}", offender, err),
             }
         }
         _ => println!("Unknown message type"),
@@ -209,13 +634,300 @@ async fn handle_message(
     Ok(())
 }
 
+/// Reads the key this node seals its blocks with. Without one the node only follows the chain,
+/// and with the key of an address that is not a validator it proposes nothing until the chain
+/// makes it one.
+fn validator_wallet() -> Result<Option<LocalWallet>> {
+    let Ok(pk) = env::var(VALIDATOR_KEY_VAR) else {
+        println!("⚠️ {} is not set, this node will not propose blocks ⚠️", VALIDATOR_KEY_VAR);
+        return Ok(None);
+    };
+
+    let wallet = LocalWallet::from_str(&pk)?;
+    println!("🔑 Proposing blocks as validator {} 🔑", Address::from(wallet.address()));
+    Ok(Some(wallet))
+}
+
+/// Signs this node's vote on `block`, counts it and sends it to the network. Only nodes with the
+/// key of a validator vote.
+async fn cast_vote(
+    swarm: &mut Swarm<RCOBBehaviour>,
+    blockchain: &Blockchain,
+    votes: &mut VotePool,
+    wallet: Option<&LocalWallet>,
+    block: &Block,
+    approve: bool,
+) -> Result<()> {
+    let validators = blockchain.validators()?;
+    let Some(wallet) =
+        wallet.filter(|wallet| validators.contains(&Address::from(wallet.address())))
+    else {
+        return Ok(());
+    };
+
+    let block_hash = block.get_block_hash(blockchain.spec().hash_scheme)?;
+    let vote = VoteOnBlock::on_block(block, block_hash, approve, wallet)?;
+    // Validators vote for one block per height, so a second block at the same height gets no
+    // vote.
+    if let Err(err) = votes.add(vote.clone(), &validators) {
+        println!("Not voting for block {}: {}", block.header.number, err);
+        return Ok(());
+    }
+    println!("Voting {} for block {}", if approve { "YES" } else { "NO" }, block.header.number);
+
+    let mut out = Vec::<u8>::new();
+    vote.encode(&mut out);
+    let msg = P2PMessage {
+        id: 10,
+        code: None,
+        want: None,
+        data: Some(out),
+        random: rand::random::<u64>(),
+    };
+    let msgjson = serde_json::to_string(&msg)?;
+    if let Err(err) = swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes()) {
+        println!("Failed to send vote: {}", err);
+    }
+
+    Ok(())
+}
+
+/// Carries out what the [`BftState`] of `agreement` asked for, and what it asks for in turn.
+async fn handle_outputs(
+    swarm: &mut Swarm<RCOBBehaviour>,
+    blockchain: &mut Blockchain,
+    agreement: &mut Agreement,
+    evidence_pool: &mut EvidencePool,
+    wallet: Option<&LocalWallet>,
+    outputs: Vec<Output>,
+) -> Result<()> {
+    let Agreement::Bft { state, timeouts } = agreement else {
+        return Ok(());
+    };
+
+    let mut outputs = VecDeque::from(outputs);
+    while let Some(output) = outputs.pop_front() {
+        match output {
+            Output::Proposal(proposal) => {
+                println!(
+                    "Proposing block {} in round {}",
+                    proposal.block.header.number, proposal.round
+                );
+                let mut out = Vec::<u8>::new();
+                proposal.encode(&mut out);
+                publish_round_message(swarm, 11, out)?;
+            }
+            Output::Vote(vote) => {
+                println!(
+                    "{} {} for block {} in round {}",
+                    match vote.step {
+                        VoteStep::Prevote => "Prevoting",
+                        VoteStep::Precommit => "Precommitting",
+                    },
+                    vote.block_hash().map_or("nil".to_string(), |hash| hash.to_string()),
+                    vote.height,
+                    vote.round
+                );
+                let mut out = Vec::<u8>::new();
+                vote.encode(&mut out);
+                publish_round_message(swarm, 12, out)?;
+            }
+            Output::Schedule { timeout, after } => timeouts.push((Instant::now() + after, timeout)),
+            Output::Propose { .. } => {
+                let Some(wallet) = wallet else {
+                    continue;
+                };
+                let parent_block = get_last_block(blockchain.storage())?;
+                match build_block(swarm, blockchain, evidence_pool, &parent_block, wallet).await {
+                    Ok(block) => outputs.extend(state.propose(blockchain, block)?),
+                    // The round times out like any other round without a proposal.
+                    Err(err) => println!("Failed to propose block: {}", err),
+                }
+            }
+            Output::Commit { block, certificate } => {
+                println!(
+                    "Got {} precommits in round {}, finalizing the block...",
+                    certificate.precommits.len(),
+                    certificate.round
+                );
+                if let Err(err) = blockchain.commit_certified_block(block, certificate) {
+                    println!("Failed to commit block: {}", err);
+                }
+            }
+            Output::Evidence(evidence) => {
+                if evidence_pool.add(evidence.clone()) {
+                    println!(
+                        "Caught {} voting twice in a round of block {}",
+                        evidence.offender(),
+                        evidence.height()
+                    );
+                    publish_evidence(swarm, &evidence)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds the child of `parent_block` as the validator of `wallet` from the transactions of the
+/// mempool that still apply, sending each of them to the network along the way, and the evidence
+/// that still has to be punished.
+async fn build_block(
+    swarm: &mut Swarm<RCOBBehaviour>,
+    blockchain: &Blockchain,
+    evidence_pool: &mut EvidencePool,
+    parent_block: &Block,
+    wallet: &LocalWallet,
+) -> Result<Block> {
+    let txs = collect_transactions(swarm, blockchain).await?;
+    let evidence = collect_evidence(blockchain, evidence_pool, parent_block);
+    blockchain.propose_block(txs, evidence, parent_block, wallet)
+}
+
+/// The evidence of the pool that may still go into the child of `parent_block`. The rest was
+/// either punished already or is too old, so it is dropped from the pool.
+fn collect_evidence(
+    blockchain: &Blockchain,
+    evidence_pool: &mut EvidencePool,
+    parent_block: &Block,
+) -> Vec<Evidence> {
+    evidence_pool.retain(parent_block.header.number, |evidence| {
+        blockchain.verify_evidence(parent_block, evidence).is_ok()
+    });
+
+    evidence_pool.pending().to_vec()
+}
+
+/// Checks `header` for a second header its miner sealed at the same height, and sends the
+/// evidence to the network if it is one.
+fn detect_double_sign(
+    swarm: &mut Swarm<RCOBBehaviour>,
+    blockchain: &Blockchain,
+    evidence_pool: &mut EvidencePool,
+    header: &Header,
+) -> Result<()> {
+    if let Some(evidence) = evidence_pool.observe_header(header, blockchain.spec().hash_scheme) {
+        println!("Caught {} sealing two blocks at height {}", header.miner, header.number);
+        publish_evidence(swarm, &evidence)?;
+    }
+
+    Ok(())
+}
+
+/// Imports `block`, mined by a peer on a proof of work chain. It is committed if it extends the
+/// head and takes over the top of the chain if its branch holds more work. If its parent is
+/// unknown the blocks before it are asked for.
+fn import_mined_block(
+    swarm: &mut Swarm<RCOBBehaviour>,
+    blockchain: &mut Blockchain,
+    block: Block,
+) -> Result<()> {
+    if blockchain.storage().get_block_by_hash(&block.header.parent_hash)?.is_some() {
+        if let Err(err) = blockchain.import_branch(vec![block]) {
+            println!("Proposed block is invalid: {}", err);
+        }
+        return Ok(());
+    }
+
+    println!("Proposed block is on a branch the node does not have, requesting blocks...");
+    // Miners send their blocks once they committed them, so ask for the proposed block and the
+    // blocks back to where its branch may have forked off.
+    let head = get_last_block(blockchain.storage())?.header.number;
+    let want = Some(block.header.number.saturating_sub(head) + REORG_LOOKBACK);
+    let msg = P2PMessage { id: 6, code: None, want, data: None, random: rand::random::<u64>() };
+    let msgjson = serde_json::to_string(&msg)?;
+    if let Err(err) = swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes()) {
+        println!("Failed to send block request: {}", err);
+    }
+
+    Ok(())
+}
+
+/// Sends evidence of misbehavior to the network. Other nodes that saw both messages report it too,
+/// so failing to send it is not fatal.
+fn publish_evidence(swarm: &mut Swarm<RCOBBehaviour>, evidence: &Evidence) -> Result<()> {
+    let mut out = Vec::<u8>::new();
+    evidence.encode(&mut out);
+    let msg = P2PMessage {
+        id: 13,
+        code: None,
+        want: None,
+        data: Some(out),
+        random: rand::random::<u64>(),
+    };
+    let msgjson = serde_json::to_string(&msg)?;
+    if let Err(err) = swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes()) {
+        println!("Failed to send evidence: {}", err);
+    }
+
+    Ok(())
+}
+
+/// The transactions of the mempool that apply on top of the head, each of which is sent to the
+/// network. They stay in the mempool until a block that includes them is committed, so a block
+/// that never makes it loses none of them. Transactions that do not apply yet, like ones whose
+/// nonce comes after a missing one, wait for a later block.
+async fn collect_transactions(
+    swarm: &mut Swarm<RCOBBehaviour>,
+    blockchain: &Blockchain,
+) -> Result<Transactions> {
+    let mut mempool = get_all_transactions(blockchain.storage())?;
+    // A sender's transactions can only apply in the order of their nonces.
+    mempool.sort_by_key(|tx| tx.nonce);
+    let scheme = blockchain.spec().hash_scheme;
+    let mut txs = vec![];
+    let mut forged = vec![];
+    let mut pending_state = State::load(blockchain.storage())?;
+    let number = get_last_block(blockchain.storage())?.header.number + 1;
+    for tx in mempool {
+        if let Err(err) = tx.verify(blockchain.spec().chain_id) {
+            println!("Dropping transaction from {}: {}", tx.sender, err);
+            forged.push(tx.get_transaction_hash(scheme));
+            continue;
+        }
+        if pending_state.apply_transaction(&tx, number).is_err() {
+            continue;
+        }
+        txs.push(tx.clone());
+        handle_send_tx(swarm, tx.clone()).await?;
+    }
+    if !forged.is_empty() {
+        blockchain
+            .storage()
+            .remove_from_mempool(&|tx| forged.contains(&tx.get_transaction_hash(scheme)))?;
+    }
+
+    Ok(txs)
+}
+
+/// Sends a proposal or vote of a Tendermint round. One that does not get out, for instance
+/// because the node has no peers yet, only costs the round, so failing to send it is not fatal.
+fn publish_round_message(swarm: &mut Swarm<RCOBBehaviour>, id: u64, data: Vec<u8>) -> Result<()> {
+    let msg =
+        P2PMessage { id, code: None, want: None, data: Some(data), random: rand::random::<u64>() };
+    let msgjson = serde_json::to_string(&msg)?;
+    if let Err(err) = swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes()) {
+        println!("Failed to send round message: {}", err);
+    }
+
+    Ok(())
+}
+
+/// A deadline for `sleep_until` when no timeout is pending.
+fn far_future() -> Instant {
+    Instant::now() + Duration::from_secs(3600)
+}
+
 async fn handle_send_block(swarm: &mut Swarm<RCOBBehaviour>, id: u64, block: Block) -> Result<()> {
     let mut out = Vec::<u8>::new();
     block.encode(&mut out);
     let msg =
         P2PMessage { id, code: None, want: None, data: Some(out), random: rand::random::<u64>() };
     let msgjson = serde_json::to_string(&msg)?;
-    swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())?;
+    if let Err(err) = swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes()) {
+        println!("Failed to send block: {}", err);
+    }
 
     Ok(())
 }
@@ -231,7 +943,9 @@ async fn handle_send_tx(swarm: &mut Swarm<RCOBBehaviour>, tx: Transaction) -> Re
         random: rand::random::<u64>(),
     };
     let msgjson = serde_json::to_string(&msg)?;
-    swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())?;
+    if let Err(err) = swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes()) {
+        println!("Failed to send transaction: {}", err);
+    }
 
     Ok(())
 }
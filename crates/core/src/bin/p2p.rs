@@ -1,7 +1,9 @@
 use alloy_rlp::{Decodable, Encodable};
-use ethers::types::{Signature, H160, H256};
-use eyre::Result;
+use clap::Parser;
+use ethers::signers::{LocalWallet, Signer};
+use eyre::{eyre, Result};
 use futures::stream::StreamExt;
+use helper::Address;
 use libp2p::{
     gossipsub, mdns, noise,
     swarm::{NetworkBehaviour, SwarmEvent},
@@ -9,23 +11,88 @@ use libp2p::{
 };
 use once_cell::sync::Lazy;
 use rustic_chain_of_blocks::{
+    bft::{BftState, Output, Proposal, RoundVote, Timeout},
     block::Block,
+    blockchain::{BlockValidationError, Blockchain},
+    chainspec::{ChainSpec, HashScheme, DEFAULT_CHAIN_ID},
+    datadir::DataDir,
     p2p::{NBlocks, P2PMessage, VoteOnBlock},
+    state::State,
+    storage::{MemoryStorage, Storage},
     transaction::Transaction,
+    vote::VotePool,
 };
-use sha3::{Digest, Keccak256};
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, VecDeque},
+    env,
     hash::{Hash, Hasher},
+    path::PathBuf,
     str::FromStr,
+    sync::Arc,
     time::Duration,
 };
-use tokio::{io, io::AsyncBufReadExt, select};
+use tokio::{
+    io,
+    io::AsyncBufReadExt,
+    select,
+    time::{interval, sleep_until, Instant},
+};
 use tracing_subscriber::EnvFilter;
 
+/// Environment variable holding the private key of the validator this node votes as.
+const VALIDATOR_KEY_VAR: &str = "VALIDATOR_PRIVATE_KEY";
+
 static TOPIC: Lazy<gossipsub::IdentTopic> =
     Lazy::new(|| gossipsub::IdentTopic::new("Rustic Chain of Blocks"));
 
+#[derive(Parser)]
+#[command(about = "Runs a Rustic Chain of Blocks P2P node that votes on proposed blocks")]
+struct Args {
+    /// Directory to keep the local copy of the chain in. Kept in memory when not set.
+    #[arg(long)]
+    datadir: Option<PathBuf>,
+
+    /// JSON chain spec of the network to join. Without one the node runs the default network
+    /// with the given chain id.
+    #[arg(long, conflicts_with_all = ["chain_id", "legacy_hashing"])]
+    chain: Option<PathBuf>,
+
+    /// Chain id of the network, which transactions must be signed for.
+    #[arg(long, default_value_t = DEFAULT_CHAIN_ID)]
+    chain_id: u64,
+
+    /// Hash blocks and transactions with the legacy scheme, for networks whose chain was started
+    /// before blocks were hashed over their RLP encoding.
+    #[arg(long)]
+    legacy_hashing: bool,
+}
+
+impl Args {
+    fn chain_spec(&self) -> Result<ChainSpec> {
+        match &self.chain {
+            Some(path) => ChainSpec::from_file(path),
+            None if self.legacy_hashing => Ok(ChainSpec::new(self.chain_id, HashScheme::Legacy)),
+            None => Ok(ChainSpec::new(self.chain_id, HashScheme::Rlp)),
+        }
+    }
+}
+
+/// The local copy of the chain a P2P node checks proposals against.
+///
+/// A valid proposal is only final once enough validators voted for it, so it is kept aside
+/// until then. The node votes itself if it runs with the key of a validator.
+///
+/// Under engines whose blocks need a commit certificate the node takes part in the rounds of
+/// `bft` instead, but never proposes, so the rounds it would propose in time out.
+struct LocalChain {
+    blockchain: Blockchain,
+    pending: Option<Block>,
+    votes: VotePool,
+    wallet: Option<LocalWallet>,
+    bft: Option<BftState>,
+    timeouts: Vec<(Instant, Timeout)>,
+}
+
 #[derive(NetworkBehaviour)]
 struct P2PBehaviour {
     gossipsub: gossipsub::Behaviour,
@@ -34,6 +101,37 @@ struct P2PBehaviour {
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args = Args::parse();
+    let spec = args.chain_spec()?;
+    let (storage, _lock): (Arc<dyn Storage>, _) = match args.datadir {
+        Some(path) => {
+            let datadir = DataDir::new(path);
+            let lock = datadir.lock()?;
+            (Arc::new(datadir.open_storage()?), Some(lock))
+        }
+        None => (Arc::new(MemoryStorage::new()), None),
+    };
+    let wallet = match env::var(VALIDATOR_KEY_VAR) {
+        Ok(pk) => Some(LocalWallet::from_str(&pk)?),
+        Err(_) => {
+            println!("⚠️ {} is not set, this node will not vote ⚠️", VALIDATOR_KEY_VAR);
+            None
+        }
+    };
+    let blockchain = Blockchain::open(storage, spec)?;
+    let bft = blockchain
+        .consensus()
+        .requires_certificate()
+        .then(|| BftState::new(&blockchain, wallet.clone()));
+    let mut chain = LocalChain {
+        blockchain,
+        pending: None,
+        votes: VotePool::new(),
+        wallet,
+        bft,
+        timeouts: vec![],
+    };
+
     let _ = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env()).try_init();
 
     let mut swarm = libp2p::SwarmBuilder::with_new_identity()
@@ -52,7 +150,7 @@ async fn main() -> Result<()> {
                 .validation_mode(gossipsub::ValidationMode::Strict)
                 .message_id_fn(message_id_fn)
                 .build()
-                .map_err(|msg| io::Error::new(io::ErrorKind::Other, msg))?;
+                .map_err(io::Error::other)?;
 
             let gossipsub = gossipsub::Behaviour::new(
                 gossipsub::MessageAuthenticity::Signed(key.clone()),
@@ -74,8 +172,32 @@ async fn main() -> Result<()> {
 
     let mut stdin = io::BufReader::new(io::stdin()).lines();
 
+    let mut resend = interval(Duration::from_secs(chain.blockchain.spec().block_time));
+    let outputs = chain.start_height()?;
+    handle_outputs(&mut swarm, &mut chain, outputs).await?;
+
     loop {
+        let deadline = chain.timeouts.iter().map(|(at, _)| *at).min();
+
         select! {
+            _ = sleep_until(deadline.unwrap_or_else(far_future)), if deadline.is_some() => {
+                let now = Instant::now();
+                let (due, pending): (Vec<_>, Vec<_>) =
+                    chain.timeouts.drain(..).partition(|(at, _)| *at <= now);
+                chain.timeouts = pending;
+
+                let mut outputs = vec![];
+                if let Some(bft) = &mut chain.bft {
+                    for (_, timeout) in due {
+                        outputs.extend(bft.on_timeout(&chain.blockchain, timeout)?);
+                    }
+                }
+                handle_outputs(&mut swarm, &mut chain, outputs).await?;
+            }
+            _ = resend.tick(), if chain.bft.is_some() => {
+                let outputs = chain.bft.as_ref().map_or_else(Vec::new, BftState::rebroadcast);
+                handle_outputs(&mut swarm, &mut chain, outputs).await?;
+            }
             Ok(Some(input)) = stdin.next_line() => {
                 handle_input(&mut swarm, input.to_string()).await?;
             }
@@ -97,7 +219,7 @@ async fn main() -> Result<()> {
                     message_id: _id,
                     message,
                 })) => {
-                    handle_message(&mut swarm, peer_id, message.data).await?
+                    handle_message(&mut swarm, &mut chain, peer_id, message.data).await?
                 },
                 SwarmEvent::NewListenAddr { address, .. } => {
                     println!("P2P Node is live on {address}");
@@ -122,8 +244,10 @@ async fn handle_input(swarm: &mut Swarm<P2PBehaviour>, line: String) -> Result<(
             let data = Some(out);
             let msg = P2PMessage { id, code, want, data, random };
             let msgjson = serde_json::to_string(&msg)?;
-            swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())?;
-            println!("Sent Ping message");
+            match swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes()) {
+                Ok(_) => println!("Sent Ping message"),
+                Err(err) => println!("Failed to send Ping: {}", err),
+            }
         }
         1 => (),
         2 => {
@@ -132,8 +256,10 @@ async fn handle_input(swarm: &mut Swarm<P2PBehaviour>, line: String) -> Result<(
             let data = Some(out);
             let msg = P2PMessage { id, code, want, data, random };
             let msgjson = serde_json::to_string(&msg)?;
-            swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())?;
-            println!("Sent Address message");
+            match swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes()) {
+                Ok(_) => println!("Sent Address message"),
+                Err(err) => println!("Failed to send address: {}", err),
+            }
         }
         3 => (),
         4 => (),
@@ -142,15 +268,19 @@ async fn handle_input(swarm: &mut Swarm<P2PBehaviour>, line: String) -> Result<(
             let want = input[1].parse::<u64>()?;
             let msg = P2PMessage { id, code, want: Some(want), data: None, random };
             let msgjson = serde_json::to_string(&msg)?;
-            swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())?;
-            println!("Sent GetBlock message");
+            match swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes()) {
+                Ok(_) => println!("Sent GetBlock message"),
+                Err(err) => println!("Failed to send block request: {}", err),
+            }
         }
         7 => (),
         8 => {
             let msg = P2PMessage { id, code, want, data: None, random };
             let msgjson = serde_json::to_string(&msg)?;
-            swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())?;
-            println!("Sent GetLatestBlock message");
+            match swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes()) {
+                Ok(_) => println!("Sent GetLatestBlock message"),
+                Err(err) => println!("Failed to send latest block request: {}", err),
+            }
         }
         9 => (),
         10 => (),
@@ -162,6 +292,7 @@ async fn handle_input(swarm: &mut Swarm<P2PBehaviour>, line: String) -> Result<(
 
 async fn handle_message(
     swarm: &mut Swarm<P2PBehaviour>,
+    chain: &mut LocalChain,
     peer_id: PeerId,
     message: Vec<u8>,
 ) -> Result<()> {
@@ -198,40 +329,62 @@ async fn handle_message(
             let recv_block = recv_msg.data.unwrap();
             let decoded_block = Block::decode(&mut recv_block.as_slice())?;
             println!("Received a NewBlock message from {peer_id}\n{:#?}", decoded_block.clone());
+            if chain.import_competing(&decoded_block)? {
+                return Ok(());
+            }
 
-            let miner_address = H160::from_str(&decoded_block.header.miner)?;
-            let miner_signature = Signature::from_str(&decoded_block.header.extra_data[0])?;
-
-            let mut buffer = Vec::<u8>::new();
-            decoded_block.header.parent_hash.encode(&mut buffer);
-            decoded_block.header.miner.encode(&mut buffer);
-            decoded_block.header.state_root.encode(&mut buffer);
-            decoded_block.header.transactions_root.encode(&mut buffer);
-            decoded_block.header.number.encode(&mut buffer);
-            decoded_block.header.timestamp.encode(&mut buffer);
-
-            let hash = Keccak256::digest(&buffer);
-            let header_hash = H256::from_slice(&hash);
-
-            let vote = if miner_signature.verify(header_hash, miner_address).is_ok() {
-                println!("Voting YES for the proposed block");
-                VoteOnBlock { block_number: decoded_block.header.number, vote: "YES".to_string() }
-            } else {
-                println!("Voting NO for the proposed block");
-                VoteOnBlock { block_number: decoded_block.header.number, vote: "NO".to_string() }
+            let valid = match chain.validate(&decoded_block)? {
+                Some(Ok(())) => true,
+                Some(Err(err)) => {
+                    println!("Proposed block is invalid: {}", err);
+                    false
+                }
+                None => {
+                    println!("Local chain is behind the proposed block, requesting blocks...");
+                    // Miners send their blocks once they committed them, so ask for the proposed
+                    // block too.
+                    let want =
+                        decoded_block.header.number.saturating_sub(chain.next_block_number()?) + 1;
+                    let msg = P2PMessage { id: 6, code, want: Some(want), data: None, random };
+                    let msgjson = serde_json::to_string(&msg)?;
+                    if let Err(err) =
+                        swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())
+                    {
+                        println!("Failed to send block request: {}", err);
+                    }
+                    false
+                }
             };
 
-            vote.encode(&mut out);
-            let data = Some(out);
-            let msg = P2PMessage { id: 10, code, want, data, random };
-            let msgjson = serde_json::to_string(&msg)?;
-            swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())?;
+            if valid {
+                chain.pending = Some(decoded_block.clone());
+            }
+
+            if let Some(vote) = chain.vote(&decoded_block, valid)? {
+                vote.encode(&mut out);
+                let data = Some(out);
+                let msg = P2PMessage { id: 10, code, want, data, random };
+                let msgjson = serde_json::to_string(&msg)?;
+                if let Err(err) =
+                    swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())
+                {
+                    println!("Failed to send vote: {}", err);
+                }
+            }
+            // Votes of validators that were faster than this node may already be in.
+            chain.finalize_pending()?;
         }
         6 => (),
         7 => {
             let recv_blocks = recv_msg.data.unwrap();
             let decoded_blocks = NBlocks::decode(&mut recv_blocks.as_slice())?;
             println!("Received a Block message from {peer_id}\n{:#?}", decoded_blocks);
+            let head = chain.next_block_number()?;
+            chain.import(decoded_blocks)?;
+            if chain.next_block_number()? != head {
+                let outputs = chain.start_height()?;
+                handle_outputs(swarm, chain, outputs).await?;
+            }
         }
         8 => (),
         9 => {
@@ -242,9 +395,277 @@ async fn handle_message(
                 decoded_block_number
             );
         }
-        10 => (),
+        10 => {
+            let recv_data = recv_msg.data.unwrap();
+            let recv_vote = VoteOnBlock::decode(&mut recv_data.as_slice())?;
+            let voter = recv_vote.voter;
+            let validators = chain.validators()?;
+            if validators.is_empty() {
+                // Without a genesis block the node cannot tell who the validators are yet.
+                return Ok(());
+            }
+            match chain.votes.add(recv_vote, &validators) {
+                Ok(_) => chain.finalize_pending()?,
+                Err(err) => println!("Ignoring vote from {}: {}", voter, err),
+            }
+        }
+        11 => {
+            let recv_data = recv_msg.data.unwrap();
+            let proposal = Proposal::decode(&mut recv_data.as_slice())?;
+            let (proposer, height) = (proposal.proposer, proposal.height);
+            println!("Received proposal for block {} from {peer_id}", height);
+
+            let next_number = chain.next_block_number()?;
+            if height > next_number {
+                println!("Local chain is behind the proposed block, requesting blocks...");
+                let want = Some(height - next_number);
+                let msg = P2PMessage { id: 6, code, want, data: None, random };
+                let msgjson = serde_json::to_string(&msg)?;
+                if let Err(err) =
+                    swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())
+                {
+                    println!("Failed to send block request: {}", err);
+                }
+            }
+
+            let Some(bft) = &mut chain.bft else {
+                return Ok(());
+            };
+            match bft.on_proposal(&chain.blockchain, proposal) {
+                Ok(outputs) => handle_outputs(swarm, chain, outputs).await?,
+                Err(err) => println!("Ignoring proposal from {}: {}", proposer, err),
+            }
+        }
+        12 => {
+            let Some(bft) = &mut chain.bft else {
+                return Ok(());
+            };
+            let recv_data = recv_msg.data.unwrap();
+            let vote = RoundVote::decode(&mut recv_data.as_slice())?;
+            let voter = vote.voter;
+            match bft.on_vote(&chain.blockchain, vote) {
+                Ok(outputs) => handle_outputs(swarm, chain, outputs).await?,
+                Err(err) => println!("Ignoring vote from {}: {}", voter, err),
+            }
+        }
         _ => (),
     }
 
     Ok(())
 }
+
+/// Carries out what the [`BftState`] of `chain` asked for.
+async fn handle_outputs(
+    swarm: &mut Swarm<P2PBehaviour>,
+    chain: &mut LocalChain,
+    outputs: Vec<Output>,
+) -> Result<()> {
+    let mut outputs = VecDeque::from(outputs);
+    while let Some(output) = outputs.pop_front() {
+        let mut out = Vec::<u8>::new();
+        let id = match output {
+            Output::Proposal(proposal) => {
+                proposal.encode(&mut out);
+                11
+            }
+            Output::Vote(vote) => {
+                println!("Voting in round {} of block {}", vote.round, vote.height);
+                vote.encode(&mut out);
+                12
+            }
+            Output::Schedule { timeout, after } => {
+                chain.timeouts.push((Instant::now() + after, timeout));
+                continue;
+            }
+            Output::Propose { height, round } => {
+                println!(
+                    "Not proposing block {} in round {}, P2P nodes build no blocks",
+                    height, round
+                );
+                continue;
+            }
+            Output::Commit { block, certificate } => {
+                if let Err(err) = chain.blockchain.commit_certified_block(block, certificate) {
+                    println!("Failed to commit block: {}", err);
+                }
+                continue;
+            }
+            // P2P nodes build no blocks to include evidence in, so they only pass it on.
+            Output::Evidence(evidence) => {
+                println!(
+                    "Caught {} voting twice in a round of block {}",
+                    evidence.offender(),
+                    evidence.height()
+                );
+                evidence.encode(&mut out);
+                13
+            }
+        };
+
+        // A message that does not get out only costs the round.
+        let data = Some(out);
+        let msg = P2PMessage { id, code: None, want: None, data, random: rand::random::<u64>() };
+        let msgjson = serde_json::to_string(&msg)?;
+        if let Err(err) = swarm.behaviour_mut().gossipsub.publish(TOPIC.clone(), msgjson.as_bytes())
+        {
+            println!("Failed to send round message: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// A deadline for `sleep_until` when no timeout is pending.
+fn far_future() -> Instant {
+    Instant::now() + Duration::from_secs(3600)
+}
+
+impl LocalChain {
+    fn next_block_number(&self) -> Result<u64> {
+        let head = self.blockchain.storage().get_last_block()?;
+        Ok(head.map_or(0, |block| block.header.number + 1))
+    }
+
+    fn extends_head(&self, block: &Block) -> Result<bool> {
+        match self.blockchain.storage().get_last_block()? {
+            Some(head) => Ok(head.get_block_hash(self.blockchain.spec().hash_scheme)?
+                == block.header.parent_hash),
+            None => Ok(block.header.number == 0),
+        }
+    }
+
+    /// Runs every block validation rule for `block` on top of the local head. Returns `None` if
+    /// the local chain is not at the block's parent and cannot judge it.
+    fn validate(&self, block: &Block) -> Result<Option<Result<(), BlockValidationError>>> {
+        if !self.extends_head(block)? {
+            return Ok(None);
+        }
+
+        let Some(parent) = self.blockchain.storage().get_last_block()? else {
+            return Ok(None);
+        };
+        let state = State::load(self.blockchain.storage())?;
+        Ok(Some(self.blockchain.validate_child(&parent, &state, block).map(|_| ())))
+    }
+
+    /// The validators whose votes count for the block after the local head.
+    fn validators(&self) -> Result<Vec<Address>> {
+        match self.blockchain.storage().get_last_block()? {
+            Some(_) => self.blockchain.validators(),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Signs this node's vote on `block` and counts it. Returns `None` if the node does not run
+    /// with the key of a validator or already voted in the round of `block`.
+    fn vote(&mut self, block: &Block, approve: bool) -> Result<Option<VoteOnBlock>> {
+        let validators = self.validators()?;
+        let Some(wallet) = self
+            .wallet
+            .as_ref()
+            .filter(|wallet| validators.contains(&Address::from(wallet.address())))
+        else {
+            return Ok(None);
+        };
+
+        let block_hash = block.get_block_hash(self.blockchain.spec().hash_scheme)?;
+        let vote = VoteOnBlock::on_block(block, block_hash, approve, wallet)?;
+        // Validators vote for one block per height, even if another block is proposed for it.
+        if let Err(err) = self.votes.add(vote.clone(), &validators) {
+            println!("Not voting for the proposed block: {}", err);
+            return Ok(None);
+        }
+        println!("Voting {} for the proposed block", if approve { "YES" } else { "NO" });
+
+        Ok(Some(vote))
+    }
+
+    /// Commits the pending block once enough validators voted for it.
+    fn finalize_pending(&mut self) -> Result<()> {
+        let Some(pending) = &self.pending else {
+            return Ok(());
+        };
+        if !self.extends_head(pending)? {
+            self.pending = None;
+            return Ok(());
+        }
+
+        let number = pending.header.number;
+        let block_hash = pending.get_block_hash(self.blockchain.spec().hash_scheme)?;
+        if self.blockchain.has_quorum(&self.votes.yes_voters(number, &block_hash))? {
+            if let Some(pending) = self.pending.take() {
+                let certificate = self.votes.certificate(number, &block_hash);
+                self.blockchain.commit_voted_block(pending, certificate)?;
+                self.votes.prune(number);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Starts agreeing on the block after the local head, if the chain runs Tendermint rounds
+    /// and the node has a genesis block to start from.
+    fn start_height(&mut self) -> Result<Vec<Output>> {
+        match &mut self.bft {
+            Some(bft) if self.blockchain.storage().get_last_block()?.is_some() => {
+                bft.start_height(&self.blockchain)
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// Imports `block` as a branch that may take over the top of the chain if the chain allows
+    /// reorgs and the block competes with a local block for its parent. Returns whether it did.
+    fn import_competing(&mut self, block: &Block) -> Result<bool> {
+        if !self.blockchain.consensus().allows_reorgs() || self.extends_head(block)? {
+            return Ok(false);
+        }
+        if self.blockchain.storage().get_block_by_hash(&block.header.parent_hash)?.is_none() {
+            return Ok(false);
+        }
+
+        if let Err(err) = self.blockchain.import_branch(vec![block.clone()]) {
+            println!("Proposed block is invalid: {}", err);
+        }
+        Ok(true)
+    }
+
+    /// Commits every block from a `Block` response that extends the local chain, checking the
+    /// commit certificate or the votes it came with.
+    fn import(&mut self, blocks: NBlocks) -> Result<()> {
+        if self.blockchain.consensus().allows_reorgs() {
+            // The blocks may be a heavier branch that forked off before the local head.
+            if let Err(err) = self.blockchain.import_branch(blocks.blocks) {
+                println!("Failed to import blocks: {}", err);
+            }
+            return Ok(());
+        }
+
+        for block in &blocks.blocks {
+            if !self.extends_head(block)? {
+                continue;
+            }
+            let number = block.header.number;
+            // Blocks after genesis are only final with the votes or the commit certificate of a
+            // quorum.
+            let imported = match (blocks.certificate(number), blocks.votes(number)) {
+                (Some(certificate), _) => {
+                    self.blockchain.commit_certified_block(block.clone(), certificate.clone())
+                }
+                (None, Some(votes)) => {
+                    self.blockchain.commit_voted_block(block.clone(), votes.clone())
+                }
+                (None, None) if number == 0 => self.blockchain.commit_block(block.clone()),
+                (None, None) => {
+                    Err(eyre!("block {} came without the votes that finalized it", number))
+                }
+            };
+            if let Err(err) = imported {
+                println!("Failed to import block: {}", err);
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,30 +1,70 @@
+use clap::Parser;
 use eyre::Result;
-use rustic_chain_of_blocks::{account::*, mempool::add_transaction_req};
-use std::io::{self, Write};
+use helper::Address;
+use rustic_chain_of_blocks::{
+    account::*,
+    chainspec::ChainSpec,
+    datadir::DataDir,
+    mempool::{add_transaction, get_next_nonce},
+    transaction::{Transaction, STAKE_ADDRESS, UNSTAKE_ADDRESS},
+};
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
 
-fn main() -> Result<()> {
-    let from = input("Your address: ")?;
-    let to = input("Receiver address: ")?;
+#[derive(Parser)]
+#[command(about = "Sends a transaction to a Rustic Chain of Blocks node")]
+struct Args {
+    /// Data directory of the node that should receive the transaction.
+    #[arg(long, default_value = ".")]
+    datadir: PathBuf,
+
+    /// Stake the value instead of sending it to a receiver.
+    #[arg(long, conflicts_with = "unstake")]
+    stake: bool,
+
+    /// Unstake the value, which returns to the balance 256 blocks later, instead of sending it to a
+    /// receiver.
+    #[arg(long)]
+    unstake: bool,
+
+    /// Fee paid from the balance to the miner of the block that includes the transaction.
+    #[arg(long, default_value_t = 0)]
+    fee: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let storage = DataDir::new(args.datadir).open_storage()?;
+
+    let from = input_parse::<Address>("Your address: ")?;
+    let to = match (args.stake, args.unstake) {
+        (true, _) => STAKE_ADDRESS,
+        (_, true) => UNSTAKE_ADDRESS,
+        _ => input_parse::<Address>("Receiver address: ")?,
+    };
     let value = input_parse::<u64>("Value: ")?;
     let pk = input("Your private key: ")?;
 
-    let mut sender_account = get_account_by_address(&from)?;
+    let sender_account = get_account_by_address(&storage, &from)?;
 
-    if sender_account.balance >= value {
-        sender_account.balance -= value;
-    } else {
+    if args.unstake && sender_account.stake < value {
+        println!("You don't have that much at stake!");
+        return Ok(());
+    }
+    let spent = if args.unstake { 0 } else { value };
+    if sender_account.balance < spent.saturating_add(args.fee) {
         println!("You don't have sufficient funds to make this transaction!");
         return Ok(());
     }
 
-    sender_account.nonce += 1;
-    update_accounts(&sender_account)?;
-
-    let mut receiver_account = get_account_by_address(&to)?;
-    receiver_account.balance += value;
-    update_accounts(&receiver_account)?;
-
-    add_transaction_req(from, to, value, pk)?;
+    // The key only lives in this process: the mempool just gets the signed transaction.
+    let spec = ChainSpec::load(&storage)?.unwrap_or_default();
+    let nonce = get_next_nonce(&storage, &from)?;
+    let tx = Transaction::new(from, to, value, args.fee, nonce, spec.chain_id, &pk).await?;
+    add_transaction(&storage, &spec, tx)?;
 
     println!("📥 Your transaction was added successfully to the mempool 📥");
 
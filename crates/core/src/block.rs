@@ -1,33 +1,14 @@
 use crate::{
-    account::get_state_root,
-    transaction::{get_transactions_root, Transactions},
+    chainspec::{ChainSpec, ConsensusConfig, HashScheme},
+    evidence::Evidence,
+    transaction::{get_transactions_root, verify_hash_signature, Transactions},
 };
 use alloy_rlp::{Encodable, RlpDecodable, RlpEncodable};
-use base16ct::lower::encode_string;
-use ethers::{signers::LocalWallet, types::H256};
-use eyre::Result;
+use ethers::signers::LocalWallet;
+use eyre::{bail, OptionExt, Result};
+use helper::{keccak256, Address, Signature, B256};
 use serde::{Deserialize, Serialize};
-use sha3::{Digest, Keccak256};
-use std::{
-    str::FromStr,
-    time::{SystemTime, UNIX_EPOCH},
-};
-
-pub const MINERS: [&str; 5] = [
-    "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266",
-    "0x70997970C51812dc3A010C7d01b50e0d17dc79C8",
-    "0x3C44CdDdB6a900fa2b585dd299e03d12FA4293BC",
-    "0x90F79bf6EB2c4f870365E785982E1f101E93b906",
-    "0x15d34AAf54267DB7D7c367839AAf71A00a2C6A65",
-];
-
-pub const PKS: [&str; 5] = [
-    "0xac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80",
-    "0x59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d",
-    "0x5de4111afa1a4b94908f83103eb1f1706367c2e68ca870fc3fb9a804cdab365a",
-    "0x7c852118294e51e653712a81e05800f419141751be58f605c371e15141b007a6",
-    "0x47e179ec197488593b187f80a00eb0da91f1b9d0b13f8733639f19c30a34926a",
-];
+use std::str::FromStr;
 
 pub type Blocks = Vec<Block>;
 
@@ -35,78 +16,177 @@ pub type Blocks = Vec<Block>;
 pub struct Block {
     pub header: Header,
     pub txs: Transactions,
+    /// Misbehavior of validators the block punishes, committed to by the header's
+    /// `evidence_root`.
+    #[serde(default)]
+    pub evidence: Vec<Evidence>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, RlpEncodable, RlpDecodable)]
 pub struct Header {
-    pub parent_hash: String,
-    pub miner: String,
-    pub state_root: String,
-    pub transactions_root: String,
+    pub parent_hash: B256,
+    pub miner: Address,
+    pub state_root: B256,
+    pub transactions_root: B256,
+    /// Root of the block's evidence, see [`crate::evidence::get_evidence_root`].
+    #[serde(default)]
+    pub evidence_root: B256,
+    /// Sum of the balances, stakes and unbonding stakes of every account after the block, so that
+    /// what the chain issued and burned can be audited from the headers alone.
+    #[serde(default)]
+    pub total_supply: u128,
     pub number: u64,
     pub timestamp: u64,
+    /// Under Clique [`crate::consensus::DIFF_IN_TURN`] if the block was sealed by the signer
+    /// whose turn it was, [`crate::consensus::DIFF_NO_TURN`] otherwise. Under proof of work the
+    /// number of hashes it takes on average to find `nonce`.
+    #[serde(default)]
+    pub difficulty: u64,
+    /// The proof of work found by the miner, see [`crate::miner`]. Only set under proof of work.
+    #[serde(default)]
+    pub nonce: u64,
+    /// Address the miner votes to add to or remove from the signers, or zero for no vote.
+    #[serde(default)]
+    pub candidate: Address,
+    /// Whether the vote on `candidate` is to add it rather than to remove it.
+    #[serde(default)]
+    pub authorize: bool,
+    /// The signers after this block, only listed by checkpoint blocks. Under proof of stake the
+    /// validators of the epoch the block starts.
+    #[serde(default)]
+    pub signers: Vec<Address>,
+    /// Stakes of the `signers` under proof of stake, in the same order.
+    #[serde(default)]
+    pub stakes: Vec<u64>,
+    /// Randomness the proposers after this block are drawn with under proof of stake, see
+    /// [`crate::consensus::ProofOfStake`].
+    #[serde(default)]
+    pub seed: B256,
     pub extra_data: Vec<String>,
 }
 
 impl Header {
     pub fn new(
-        parent_hash: String,
-        miner: String,
-        state_root: String,
-        transactions_root: String,
+        parent_hash: B256,
+        miner: Address,
+        state_root: B256,
+        transactions_root: B256,
         number: u64,
         timestamp: u64,
         extra_data: Vec<String>,
     ) -> Self {
-        Header { parent_hash, miner, state_root, transactions_root, number, timestamp, extra_data }
+        Header {
+            parent_hash,
+            miner,
+            state_root,
+            transactions_root,
+            evidence_root: B256::ZERO,
+            total_supply: 0,
+            number,
+            timestamp,
+            difficulty: 0,
+            nonce: 0,
+            candidate: Address::ZERO,
+            authorize: false,
+            signers: vec![],
+            stakes: vec![],
+            seed: B256::ZERO,
+            extra_data,
+        }
     }
-}
 
-impl Block {
-    pub fn genesis() -> Result<Self> {
-        let txs = vec![];
-        let parent_hash =
-            String::from("0x0000000000000000000000000000000000000000000000000000000000000000");
-        let miner = String::from(MINERS[0]);
-        let state_root = get_state_root()?;
-        let transactions_root = get_transactions_root(&mut txs.clone())?;
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    /// Hash the miner signs to seal the header. It covers every field but the seal in
+    /// `extra_data`, which carries the signature itself.
+    pub fn get_seal_hash(&self, scheme: HashScheme) -> B256 {
+        match scheme {
+            HashScheme::Legacy => {
+                let mut out = Vec::<u8>::new();
+                self.parent_hash.to_string().encode(&mut out);
+                self.miner.to_string().encode(&mut out);
+                self.state_root.to_string().encode(&mut out);
+                self.transactions_root.to_string().encode(&mut out);
+                self.number.encode(&mut out);
+                self.timestamp.encode(&mut out);
 
-        let mut out = Vec::<u8>::new();
-        parent_hash.encode(&mut out);
-        miner.encode(&mut out);
-        state_root.encode(&mut out);
-        transactions_root.encode(&mut out);
-        0u64.encode(&mut out);
-        timestamp.encode(&mut out);
+                keccak256(&out)
+            }
+            HashScheme::Rlp => {
+                let unsealed = Header { extra_data: vec![], ..self.clone() };
+                keccak256(alloy_rlp::encode(&unsealed))
+            }
+        }
+    }
 
-        let hash = Keccak256::digest(&out);
-        let header_hash = H256::from_slice(&hash);
+    /// Hash of the whole header, seal included.
+    pub fn get_header_hash(&self) -> B256 {
+        keccak256(alloy_rlp::encode(self))
+    }
 
-        let miner_wallet = LocalWallet::from_str(PKS[0])?;
-        let signature = miner_wallet.sign_hash(header_hash)?.to_string();
+    /// Checks that `extra_data[0]` is a signature by `miner` over the seal hash. Under
+    /// [`HashScheme::Rlp`] the seal must also be written in its canonical form, as any other
+    /// spelling of the same signature would give the block another hash.
+    pub fn verify_seal(&self, scheme: HashScheme) -> Result<()> {
+        let seal = self.extra_data.first().ok_or_eyre("header has no seal")?;
+        let signature = Signature::from_str(seal)?;
+        if scheme == HashScheme::Rlp && *seal != signature.to_string() {
+            bail!("seal {} is not in canonical form", seal);
+        }
 
-        let extra_data = vec![signature];
+        verify_hash_signature(signature, self.get_seal_hash(scheme), self.miner)
+    }
 
-        let header = Header::new(
-            parent_hash,
-            miner,
-            state_root,
-            transactions_root,
+    /// Signs the seal hash with the miner's key and stores the signature in `extra_data`.
+    pub fn seal(&mut self, scheme: HashScheme, miner_wallet: &LocalWallet) -> Result<()> {
+        let seal_hash = self.get_seal_hash(scheme);
+        let signature = Signature::from(miner_wallet.sign_hash(seal_hash.into())?);
+        self.extra_data = vec![signature.to_string()];
+
+        Ok(())
+    }
+}
+
+impl Block {
+    /// The genesis block of the chain `spec`. It only depends on the spec, so every node of a
+    /// network derives the same block and hash. Nobody proposes it, which leaves it unsealed.
+    /// Under Clique it lists the validators as the first signers, under proof of work it sets the
+    /// difficulty the chain starts out with and under proof of stake it lists the stakers of the
+    /// genesis allocation as the validators of the first epoch.
+    pub fn genesis(spec: &ChainSpec) -> Self {
+        let txs = vec![];
+        let state = spec.genesis_state();
+        let mut header = Header::new(
+            B256::ZERO,
+            Address::ZERO,
+            state.get_state_root(),
+            get_transactions_root(&txs, spec.hash_scheme),
             0,
-            timestamp,
-            extra_data,
+            spec.genesis.timestamp,
+            vec![],
         );
+        header.total_supply = state.total_supply();
+        match spec.consensus {
+            ConsensusConfig::Clique { .. } => {
+                let mut signers: Vec<Address> = spec.validators.iter().copied().collect();
+                signers.sort();
+                header.signers = signers;
+            }
+            ConsensusConfig::ProofOfWork { difficulty } => header.difficulty = difficulty,
+            ConsensusConfig::ProofOfStake { min_stake, .. } => {
+                (header.signers, header.stakes) = state.stakers(min_stake).into_iter().unzip();
+            }
+            _ => (),
+        }
 
-        Ok(Block { header, txs })
+        Block { header, txs, evidence: vec![] }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         txs: Transactions,
-        parent_hash: String,
-        miner: String,
-        state_root: String,
-        transactions_root: String,
+        parent_hash: B256,
+        miner: Address,
+        state_root: B256,
+        transactions_root: B256,
         number: u64,
         timestamp: u64,
         extra_data: Vec<String>,
@@ -120,23 +200,25 @@ impl Block {
             timestamp,
             extra_data,
         );
-        Block { txs, header }
+        Block { txs, header, evidence: vec![] }
     }
 
-    pub fn get_block_hash(&self) -> Result<String> {
-        let extra_data_bytes = serde_json::to_string(&self.header.extra_data)?.into_bytes();
-        let txs_bytes = serde_json::to_string(&self.txs)?.into_bytes();
-        let hash = Keccak256::new()
-            .chain_update(self.header.parent_hash.clone())
-            .chain_update(self.header.miner.clone())
-            .chain_update(self.header.state_root.clone())
-            .chain_update(self.header.transactions_root.clone())
-            .chain_update(self.header.number.to_string())
-            .chain_update(self.header.timestamp.to_string())
-            .chain_update(extra_data_bytes)
-            .chain_update(txs_bytes)
-            .finalize();
-        let hash_hex = format!("0x{}", encode_string(&hash));
-        Ok(hash_hex)
+    pub fn get_block_hash(&self, scheme: HashScheme) -> Result<B256> {
+        match scheme {
+            HashScheme::Legacy => {
+                let data = [
+                    self.header.parent_hash.to_string(),
+                    self.header.miner.to_string(),
+                    self.header.state_root.to_string(),
+                    self.header.transactions_root.to_string(),
+                    self.header.number.to_string(),
+                    self.header.timestamp.to_string(),
+                    serde_json::to_string(&self.header.extra_data)?,
+                    serde_json::to_string(&self.txs)?,
+                ];
+                Ok(keccak256(data.concat()))
+            }
+            HashScheme::Rlp => Ok(self.header.get_header_hash()),
+        }
     }
 }
@@ -1,39 +1,158 @@
 use crate::{
-    account::get_state_root,
-    block::{Block, Blocks, MINERS, PKS},
+    account::{Account, AccountProof, Accounts},
+    bft::CommitCertificate,
+    block::{Block, Header},
+    chainspec::{ChainSpec, HashScheme},
+    consensus::{self, ConsensusEngine},
+    evidence::{get_evidence_root, Evidence, MAX_EVIDENCE_AGE},
+    mempool::prune_mempool,
     p2p::NBlocks,
+    state::{
+        execute_transactions, get_account_key, release_unbonded, reward_miner, slash_offenders,
+        verify_state_transition, State,
+    },
+    storage::Storage,
     transaction::{get_transactions_root, Transactions},
+    trie::{prove_stored, StoredNode},
+    vote::VoteCertificate,
 };
-use alloy_rlp::Encodable;
-use ethers::{signers::LocalWallet, types::H256};
-use eyre::Result;
+use ethers::signers::{LocalWallet, Signer};
+use eyre::{bail, eyre, OptionExt, Result};
+use helper::{Address, B256};
 use serde::{Deserialize, Serialize};
-use sha3::{Digest, Keccak256};
 use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Write},
-    path::Path,
-    str::FromStr,
+    collections::BTreeMap,
+    sync::Arc,
     time::{SystemTime, UNIX_EPOCH},
 };
 
-const BLOCKCHAIN_JSON: &str = "./blockchain.json";
+const STATE_JOURNAL_KEY: &str = "state_journal";
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Prefix of the metadata keys the commit certificates of blocks are stored under, followed by
+/// the block number.
+const COMMIT_CERTIFICATE_KEY: &str = "commit_certificate_";
+
+/// Prefix of the metadata keys the votes that finalized blocks are stored under, followed by the
+/// block number.
+const VOTE_CERTIFICATE_KEY: &str = "vote_certificate_";
+
+/// Prefix of the metadata keys the nodes of the state trie are stored under, followed by the
+/// hash of the node. Nodes are only ever added, so the trie of every block's `state_root` stays
+/// available for proofs.
+const TRIE_NODE_KEY: &str = "trie_node_";
+
+/// Prefix of the metadata keys the [`StateUndo`] of each block is stored under, followed by the
+/// block number.
+const STATE_UNDO_KEY: &str = "state_undo_";
+
+/// Prefix of the metadata keys accounts are stored under, followed by their leaf value in the
+/// state trie.
+const ACCOUNT_LEAF_KEY: &str = "account_leaf_";
+
+/// The rule a block broke, as reported by [`Blockchain::validate_child`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum BlockValidationError {
+    #[error("parent hash {got} does not match the hash {expected} of the parent block")]
+    ParentHashMismatch { expected: B256, got: B256 },
+    #[error("block number {got} does not follow parent block number {parent}")]
+    InvalidNumber { parent: u64, got: u64 },
+    #[error("timestamp {got} is not after parent block timestamp {parent}")]
+    InvalidTimestamp { parent: u64, got: u64 },
+    #[error("block was proposed by {got} but the slot belongs to {expected}")]
+    WrongProposer { expected: Address, got: Address },
+    #[error("invalid miner seal: {0}")]
+    InvalidSeal(String),
+    #[error("failed to finalize the block: {0}")]
+    FinalizeFailed(String),
+    #[error("timestamp {got} is before {earliest}, one block time after the parent block")]
+    TooEarly { earliest: u64, got: u64 },
+    #[error("difficulty {got} does not match the expected difficulty {expected}")]
+    InvalidDifficulty { expected: u64, got: u64 },
+    #[error("timestamp {got} is after {latest}, too far ahead of the local clock")]
+    FutureTimestamp { latest: u64, got: u64 },
+    #[error("nonce {nonce} does not meet difficulty {difficulty}")]
+    InsufficientWork { nonce: u64, difficulty: u64 },
+    #[error("seed {got} does not match the expected seed {expected}")]
+    InvalidSeed { expected: B256, got: B256 },
+    #[error("{0} is not an authorized signer")]
+    UnauthorizedSigner(Address),
+    #[error("{0} sealed one of the latest blocks and may not seal another one yet")]
+    RecentlySigned(Address),
+    #[error("invalid checkpoint: {0}")]
+    InvalidCheckpoint(String),
+    #[error("failed to load the signers of the parent block: {0}")]
+    Snapshot(String),
+    #[error("transactions root {got} does not match the recomputed root {expected}")]
+    TransactionsRootMismatch { expected: B256, got: B256 },
+    #[error("transaction {index} has an invalid signature: {reason}")]
+    InvalidTransactionSignature { index: usize, reason: String },
+    #[error("transaction {index} is signed for chain {got} instead of chain {expected}")]
+    WrongChainId { index: usize, expected: u64, got: u64 },
+    #[error("transaction {index} has nonce {got} but the sender's next nonce is {expected}")]
+    InvalidTransactionNonce { index: usize, expected: u64, got: u64 },
+    #[error("transaction {index} failed to execute: {reason}")]
+    TransactionExecutionFailed { index: usize, reason: String },
+    #[error("evidence root {got} does not match the recomputed root {expected}")]
+    EvidenceRootMismatch { expected: B256, got: B256 },
+    #[error("evidence {index} is invalid: {reason}")]
+    InvalidEvidence { index: usize, reason: String },
+    #[error("total supply {got} does not match the post-execution supply {expected}")]
+    TotalSupplyMismatch { expected: u128, got: u128 },
+    #[error("state root {got} does not match the post-execution root {expected}")]
+    StateRootMismatch { expected: B256, got: B256 },
+    #[error("failed to hash block: {0}")]
+    Hashing(String),
+}
+
+#[derive(Clone)]
 pub struct Blockchain {
-    pub blocks: Blocks,
+    storage: Arc<dyn Storage>,
+    spec: ChainSpec,
+    consensus: Arc<dyn ConsensusEngine>,
+    /// Unix time the chain last showed progress: its head was committed or opened, or a valid
+    /// proposal for the next block was seen.
+    progress_since: u64,
+}
+
+/// Account changes made by a block, or by rewinding the chain to a block, written to metadata
+/// before they are applied so that a crash half way through can be rolled forward on the next
+/// start once block `number` is the head.
+#[derive(Debug, Serialize, Deserialize)]
+struct StateJournal {
+    number: u64,
+    accounts: Accounts,
+    /// Accounts that did not exist yet after block `number`.
+    #[serde(default)]
+    removed: Vec<Address>,
+    /// Metadata keys and values written along with the accounts, such as the certificate that
+    /// finalized the block, so that they are only ever stored for a block that made it.
+    #[serde(default)]
+    metadata: Vec<(String, String)>,
+}
+
+/// What it takes to move the accounts from the state after a block back to the state before it.
+#[derive(Debug, Serialize, Deserialize)]
+struct StateUndo {
+    /// The accounts the block changed, as they were before it.
+    accounts: Accounts,
+    /// Accounts the block created.
+    created: Vec<Address>,
 }
 
 impl Blockchain {
-    pub fn init() -> Result<Self> {
-        let path = Path::new(BLOCKCHAIN_JSON);
+    /// Opens the chain in `storage`, creating the genesis block of `spec` if it is empty.
+    pub fn init(storage: Arc<dyn Storage>, spec: ChainSpec) -> Result<Self> {
+        let blockchain = Blockchain::open(storage, spec)?;
 
-        if !path.exists() {
-            let genesis_block = Block::genesis()?;
-            let blockchain = Blockchain { blocks: vec![genesis_block.clone()] };
-            let blockchain_json = serde_json::to_string_pretty(&blockchain)?;
-            let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
-            file.write_all(blockchain_json.as_bytes())?;
+        if blockchain.storage.get_last_block()?.is_none() {
+            let genesis_block = Block::genesis(&blockchain.spec);
+            let state = State::load(blockchain.storage())?;
+            blockchain.write_block(
+                &genesis_block,
+                &state,
+                &blockchain.spec.genesis_state(),
+                None,
+            )?;
 
             println!("🎉 Mined genesis block 🎉");
             println!("Genesis Block:\n{:#?}", genesis_block);
@@ -43,93 +162,935 @@ impl Blockchain {
 
         println!("🔄 Syncing with latest state of blockchain 🔄");
 
-        let mut file = OpenOptions::new().read(true).open(path)?;
-        let mut content = String::new();
-        file.read_to_string(&mut content)?;
-        let blockchain: Blockchain = serde_json::from_str(&content)?;
+        Ok(blockchain)
+    }
+
+    /// Opens the chain in `storage` as is, leaving an empty chain empty so that its genesis
+    /// block can be imported from a peer with [`Blockchain::commit_block`].
+    ///
+    /// Storage that was created with another chain spec is refused, so a
+    /// data directory can never mix blocks of different networks.
+    pub fn open(storage: Arc<dyn Storage>, spec: ChainSpec) -> Result<Self> {
+        storage.recover()?;
+
+        match ChainSpec::load(storage.as_ref())? {
+            Some(stored) if stored.chain_id != spec.chain_id => bail!(
+                "storage belongs to chain {} but the node runs chain {}",
+                stored.chain_id,
+                spec.chain_id
+            ),
+            Some(stored) if stored.hash_scheme != spec.hash_scheme => bail!(
+                "storage is hashed with the {} scheme but the node runs the {} scheme",
+                stored.hash_scheme,
+                spec.hash_scheme
+            ),
+            Some(stored) if stored != spec => {
+                bail!("storage was created with another chain spec for chain {}", spec.chain_id)
+            }
+            Some(_) => (),
+            // Storage that has blocks but no spec was written before specs were stored, back
+            // when every chain was hashed with the legacy scheme.
+            None if spec.hash_scheme != HashScheme::Legacy
+                && storage.get_last_block()?.is_some() =>
+            {
+                bail!(
+                    "storage is hashed with the {} scheme but the node runs the {} scheme",
+                    HashScheme::Legacy,
+                    spec.hash_scheme
+                )
+            }
+            None => spec.store(storage.as_ref())?,
+        }
+
+        let consensus = consensus::build(&spec);
+        let progress_since = unix_time()?;
+        let blockchain = Blockchain { storage, spec, consensus, progress_since };
+        blockchain.replay_state_journal()?;
 
         Ok(blockchain)
     }
 
-    pub fn propose_block(&self, txs: Transactions, parent_block: &Block) -> Result<Block> {
-        let parent_hash = parent_block.get_block_hash()?;
-        let miner = MINERS[(parent_block.header.number as usize) % 5].to_string();
-        let state_root = get_state_root()?;
-        let transactions_root = get_transactions_root(&mut txs.clone())?;
-        let number = parent_block.header.number + 1;
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    pub fn storage(&self) -> &dyn Storage {
+        self.storage.as_ref()
+    }
 
-        let mut out = Vec::<u8>::new();
-        parent_hash.encode(&mut out);
-        miner.encode(&mut out);
-        state_root.encode(&mut out);
-        transactions_root.encode(&mut out);
-        number.encode(&mut out);
-        timestamp.encode(&mut out);
+    pub fn spec(&self) -> &ChainSpec {
+        &self.spec
+    }
 
-        let hash = Keccak256::digest(&out);
-        let header_hash = H256::from_slice(&hash);
+    pub fn consensus(&self) -> &dyn ConsensusEngine {
+        self.consensus.as_ref()
+    }
 
-        let miner_wallet = LocalWallet::from_str(PKS[(parent_block.header.number as usize) % 5])?;
-        let signature = miner_wallet.sign_hash(header_hash)?.to_string();
+    /// Whether `signer` may propose the child of `parent_block`, the head of the chain, now.
+    pub fn can_propose(&self, parent_block: &Block, signer: &Address) -> Result<bool> {
+        let waited = unix_time()?.saturating_sub(self.progress_since);
+        self.consensus.can_propose(self.storage(), parent_block, signer, waited)
+    }
 
-        let extra_data = vec![signature];
+    /// The validators whose votes count for the block after the head of the chain.
+    pub fn validators(&self) -> Result<Vec<Address>> {
+        self.consensus.validators(self.storage(), &get_last_block(self.storage())?)
+    }
 
-        println!("🎉 Proposed a new block by miner {} 🎉", miner);
+    /// Whether the yes votes of `voters` are enough to commit the block after the head, weighing
+    /// each vote by the voting power of its validator.
+    pub fn has_quorum(&self, voters: &[Address]) -> Result<bool> {
+        let powers =
+            self.consensus.voting_powers(self.storage(), &get_last_block(self.storage())?)?;
+        // Stakes add up to more than fits into a u64.
+        let total = powers.iter().map(|(_, power)| u128::from(*power)).sum();
+        let yes_votes = powers
+            .iter()
+            .filter(|(validator, _)| voters.contains(validator))
+            .map(|(_, power)| u128::from(*power))
+            .sum();
 
-        Ok(Block::new(
-            txs,
+        Ok(self.consensus.has_quorum(yes_votes, total))
+    }
+
+    /// Records that a valid proposal for the next block was seen, so that signers whose turn it
+    /// is not keep waiting for it instead of proposing a competing block.
+    pub fn note_proposal(&mut self) -> Result<()> {
+        self.progress_since = unix_time()?;
+        Ok(())
+    }
+
+    /// Builds the child of `parent_block` with `txs` and `evidence` and seals it with
+    /// `miner_wallet`, the key of a validator that may propose it, going through the steps of the
+    /// consensus engine.
+    pub fn propose_block(
+        &self,
+        txs: Transactions,
+        evidence: Vec<Evidence>,
+        parent_block: &Block,
+        miner_wallet: &LocalWallet,
+    ) -> Result<Block> {
+        let scheme = self.spec.hash_scheme;
+        let parent_hash = parent_block.get_block_hash(scheme)?;
+        let miner = Address::from(miner_wallet.address());
+        if !self.can_propose(parent_block, &miner)? {
+            bail!("{} may not propose block {} now", miner, parent_block.header.number + 1);
+        }
+        let transactions_root = get_transactions_root(&txs, scheme);
+        let number = parent_block.header.number + 1;
+        let timestamp = unix_time()?;
+
+        let mut header = Header::new(
             parent_hash,
             miner,
-            state_root,
+            B256::ZERO,
             transactions_root,
             number,
             timestamp,
-            extra_data,
-        ))
+            vec![],
+        );
+        header.evidence_root = get_evidence_root(&evidence);
+        self.consensus.prepare(self.storage(), parent_block, &mut header)?;
+
+        let mut state = execute_transactions(&State::load(self.storage())?, &txs, number)?;
+        slash_offenders(&mut state, &evidence);
+        release_unbonded(&mut state, number)?;
+        reward_miner(&mut state, &header, &txs, self.spec.block_reward)?;
+        self.consensus.finalize(&header, &mut state)?;
+        header.total_supply = state.total_supply();
+        header.state_root = state.get_state_root();
+
+        self.consensus.seal(&mut header, miner_wallet)?;
+
+        println!("🎉 Proposed a new block by miner {} 🎉", header.miner);
+
+        Ok(Block { header, txs, evidence })
     }
 
+    /// Validates `block` against the current head of the chain and returns the state it leads
+    /// to. Errors from the validation rules can be downcast to [`BlockValidationError`].
+    pub fn validate_block(&self, block: &Block) -> Result<State> {
+        let parent = get_last_block(self.storage())?;
+        let state = State::load(self.storage())?;
+
+        Ok(self.validate_child(&parent, &state, block)?)
+    }
+
+    /// Validates `block` on top of the current head and appends it to the chain. On an empty
+    /// chain the block must be the genesis block of the chain spec.
+    ///
+    /// Engines whose blocks are only final with a commit certificate refuse every block but the
+    /// genesis block here, see [`Blockchain::commit_certified_block`].
     pub fn commit_block(&mut self, block: Block) -> Result<()> {
-        self.blocks.push(block.clone());
-        update_blockchain(self)?;
+        if self.consensus.requires_certificate() && block.header.number > 0 {
+            bail!("block {} cannot be committed without a commit certificate", block.header.number);
+        }
+
+        self.commit(block, None)
+    }
+
+    /// Validates `block` on top of the current head and appends it to the chain together with
+    /// `certificate`, which has to hold the precommits of a quorum of the validators for it.
+    pub fn commit_certified_block(
+        &mut self,
+        block: Block,
+        certificate: CommitCertificate,
+    ) -> Result<()> {
+        let parent = get_last_block(self.storage())?;
+        let block_hash = block.get_block_hash(self.spec.hash_scheme)?;
+        if certificate.height != block.header.number || certificate.block_hash != block_hash {
+            bail!("commit certificate is for another block than block {}", block.header.number);
+        }
+
+        let validators = self.consensus.validators(self.storage(), &parent)?;
+        let signers = certificate.verify(&validators)?;
+        if !self.consensus.has_quorum(signers.into(), validators.len() as u128) {
+            bail!(
+                "commit certificate of block {} only holds {} of {} validators",
+                block.header.number,
+                signers,
+                validators.len()
+            );
+        }
+
+        let key = format!("{}{}", COMMIT_CERTIFICATE_KEY, block.header.number);
+        self.commit(block, Some((key, serde_json::to_string(&certificate)?)))
+    }
+
+    /// Validates `block` on top of the current head and appends it to the chain together with
+    /// `certificate`, which has to hold the yes votes of validators with a quorum for it.
+    pub fn commit_voted_block(&mut self, block: Block, certificate: VoteCertificate) -> Result<()> {
+        let block_hash = block.get_block_hash(self.spec.hash_scheme)?;
+        if certificate.block_number != block.header.number || certificate.block_hash != block_hash {
+            bail!("votes are for another block than block {}", block.header.number);
+        }
+
+        let voters = certificate.verify(&self.validators()?)?;
+        if !self.has_quorum(&voters)? {
+            bail!("the {} votes for block {} are no quorum", voters.len(), block.header.number);
+        }
+
+        let key = format!("{}{}", VOTE_CERTIFICATE_KEY, block.header.number);
+        self.commit(block, Some((key, serde_json::to_string(&certificate)?)))
+    }
+
+    /// The commit certificate stored with block `number`, if it has one.
+    pub fn get_commit_certificate(&self, number: u64) -> Result<Option<CommitCertificate>> {
+        get_commit_certificate(self.storage(), number)
+    }
+
+    /// The votes stored with block `number`, if it was finalized by votes.
+    pub fn get_vote_certificate(&self, number: u64) -> Result<Option<VoteCertificate>> {
+        get_vote_certificate(self.storage(), number)
+    }
+
+    /// Validates `block` and appends it, storing `certificate`, the metadata key and value of
+    /// whatever finalized it, along with it.
+    fn commit(&mut self, block: Block, certificate: Option<(String, String)>) -> Result<()> {
+        let state = State::load(self.storage())?;
+        let post_state = match self.storage.get_last_block()? {
+            Some(parent) => self.validate_child(&parent, &state, &block)?,
+            None if block.header.number == 0 => self.verify_genesis(&block)?,
+            None => bail!("cannot start a chain with block {}", block.header.number),
+        };
+        self.write_block(&block, &state, &post_state, certificate)?;
+        // The block is in, so failing to tidy up the mempool only leaves transactions that no
+        // longer apply, which are skipped when proposing.
+        if let Err(err) = prune_mempool(self.storage(), &post_state) {
+            println!("Failed to drop included transactions from the mempool: {}", err);
+        }
+        self.progress_since = unix_time()?;
+        self.consensus.abort_seal(&block.get_block_hash(self.spec.hash_scheme)?);
+
         println!("🎉 Mined a new block 🎉");
         println!("{:#?}", block);
 
         Ok(())
     }
+
+    /// Runs every validation rule of the chain for `block` as the child of `parent`, where `state`
+    /// is the state after `parent`. Returns the state after `block`.
+    ///
+    /// The consensus rules need the chain up to `parent`, so `parent` must be part of it.
+    pub fn validate_child(
+        &self,
+        parent: &Block,
+        state: &State,
+        block: &Block,
+    ) -> Result<State, BlockValidationError> {
+        let spec = &self.spec;
+        let header = &block.header;
+
+        let parent_hash = parent
+            .get_block_hash(spec.hash_scheme)
+            .map_err(|err| BlockValidationError::Hashing(err.to_string()))?;
+        if header.parent_hash != parent_hash {
+            return Err(BlockValidationError::ParentHashMismatch {
+                expected: parent_hash,
+                got: header.parent_hash,
+            });
+        }
+
+        if header.number != parent.header.number + 1 {
+            return Err(BlockValidationError::InvalidNumber {
+                parent: parent.header.number,
+                got: header.number,
+            });
+        }
+
+        if header.timestamp <= parent.header.timestamp {
+            return Err(BlockValidationError::InvalidTimestamp {
+                parent: parent.header.timestamp,
+                got: header.timestamp,
+            });
+        }
+
+        self.consensus.verify_header(self.storage(), parent, header)?;
+        self.consensus.verify_seal(self.storage(), parent, header)?;
+
+        let transactions_root = get_transactions_root(&block.txs, spec.hash_scheme);
+        if header.transactions_root != transactions_root {
+            return Err(BlockValidationError::TransactionsRootMismatch {
+                expected: transactions_root,
+                got: header.transactions_root,
+            });
+        }
+
+        let evidence_root = get_evidence_root(&block.evidence);
+        if header.evidence_root != evidence_root {
+            return Err(BlockValidationError::EvidenceRootMismatch {
+                expected: evidence_root,
+                got: header.evidence_root,
+            });
+        }
+
+        for (index, evidence) in block.evidence.iter().enumerate() {
+            let duplicate = block.evidence[..index].iter().any(|earlier| {
+                earlier.offender() == evidence.offender() && earlier.height() == evidence.height()
+            });
+            if duplicate {
+                return Err(BlockValidationError::InvalidEvidence {
+                    index,
+                    reason: format!(
+                        "{} is already slashed for block {} by this block",
+                        evidence.offender(),
+                        evidence.height()
+                    ),
+                });
+            }
+
+            self.verify_evidence(parent, evidence).map_err(|err| {
+                BlockValidationError::InvalidEvidence { index, reason: err.to_string() }
+            })?;
+        }
+
+        let mut state = state.clone();
+        for (index, tx) in block.txs.iter().enumerate() {
+            let chain_id = tx.chain_id().map_err(|err| {
+                BlockValidationError::InvalidTransactionSignature { index, reason: err.to_string() }
+            })?;
+            if chain_id != spec.chain_id {
+                return Err(BlockValidationError::WrongChainId {
+                    index,
+                    expected: spec.chain_id,
+                    got: chain_id,
+                });
+            }
+
+            tx.verify(spec.chain_id).map_err(|err| {
+                BlockValidationError::InvalidTransactionSignature { index, reason: err.to_string() }
+            })?;
+
+            let expected = state.get_account(&tx.sender).nonce;
+            if tx.nonce != expected {
+                return Err(BlockValidationError::InvalidTransactionNonce {
+                    index,
+                    expected,
+                    got: tx.nonce,
+                });
+            }
+
+            state.apply_transaction(tx, header.number).map_err(|err| {
+                BlockValidationError::TransactionExecutionFailed { index, reason: err.to_string() }
+            })?;
+        }
+        slash_offenders(&mut state, &block.evidence);
+        release_unbonded(&mut state, header.number)
+            .map_err(|err| BlockValidationError::FinalizeFailed(err.to_string()))?;
+        reward_miner(&mut state, header, &block.txs, spec.block_reward)
+            .map_err(|err| BlockValidationError::FinalizeFailed(err.to_string()))?;
+
+        self.consensus
+            .finalize(header, &mut state)
+            .map_err(|err| BlockValidationError::FinalizeFailed(err.to_string()))?;
+
+        let total_supply = state.total_supply();
+        if header.total_supply != total_supply {
+            return Err(BlockValidationError::TotalSupplyMismatch {
+                expected: total_supply,
+                got: header.total_supply,
+            });
+        }
+
+        let state_root = state.get_state_root();
+        if header.state_root != state_root {
+            return Err(BlockValidationError::StateRootMismatch {
+                expected: state_root,
+                got: header.state_root,
+            });
+        }
+
+        Ok(state)
+    }
+
+    /// Checks that `evidence` may go into the child of `parent`: it has to prove misbehavior at
+    /// most [`MAX_EVIDENCE_AGE`] blocks before the child that no block since has punished.
+    ///
+    /// Whether the blocks since were punished is looked up in storage, so `parent` must be part
+    /// of the chain.
+    pub fn verify_evidence(&self, parent: &Block, evidence: &Evidence) -> Result<()> {
+        evidence.verify(self.spec.hash_scheme)?;
+
+        let number = parent.header.number + 1;
+        let height = evidence.height();
+        if height >= number {
+            bail!("evidence is for block {} but may only go into a later block", height);
+        }
+        if number - height > MAX_EVIDENCE_AGE {
+            bail!("evidence for block {} is too old to go into block {}", height, number);
+        }
+
+        for n in height + 1..number {
+            let block = self
+                .storage
+                .get_block_by_number(n)?
+                .ok_or_else(|| eyre!("block {} is missing from storage", n))?;
+            let punished = block.evidence.iter().any(|included| {
+                included.offender() == evidence.offender() && included.height() == height
+            });
+            if punished {
+                bail!(
+                    "{} was already slashed for block {} in block {}",
+                    evidence.offender(),
+                    height,
+                    n
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Appends `block` and moves the accounts from `state` to `post_state`, then stores
+    /// `certificate`.
+    fn write_block(
+        &self,
+        block: &Block,
+        state: &State,
+        post_state: &State,
+        certificate: Option<(String, String)>,
+    ) -> Result<()> {
+        let journal = StateJournal {
+            number: block.header.number,
+            accounts: post_state.changed_accounts(state),
+            removed: post_state.removed_accounts(state),
+            metadata: certificate.into_iter().collect(),
+        };
+        for node in post_state.trie().changed_nodes(state.trie()) {
+            let key = format!("{}{}", TRIE_NODE_KEY, node.hash());
+            self.storage.put_metadata(&key, &serde_json::to_string(&node)?)?;
+        }
+        for account in &journal.accounts {
+            let key = format!("{}{}", ACCOUNT_LEAF_KEY, account.get_account_hash());
+            self.storage.put_metadata(&key, &serde_json::to_string(account)?)?;
+        }
+        let (accounts, created): (Vec<_>, Vec<_>) =
+            journal.accounts.iter().partition(|account| state.contains(&account.address));
+        let undo = StateUndo {
+            accounts: accounts
+                .iter()
+                .map(|account| state.get_account(&account.address))
+                .chain(journal.removed.iter().map(|address| state.get_account(address)))
+                .collect(),
+            created: created.iter().map(|account| account.address).collect(),
+        };
+        let key = format!("{}{}", STATE_UNDO_KEY, block.header.number);
+        self.storage.put_metadata(&key, &serde_json::to_string(&undo)?)?;
+        self.storage.put_metadata(STATE_JOURNAL_KEY, &serde_json::to_string(&Some(&journal))?)?;
+        self.storage.append_block(block)?;
+        self.apply_state_journal(&journal)
+    }
+
+    /// Switches the chain to `blocks`, a branch that forks off it, if the branch holds more work
+    /// than the blocks after the fork, and returns whether the chain changed. Blocks the chain
+    /// already has are skipped, the first other block has to be the child of a block of the chain.
+    ///
+    /// A branch that extends the head is simply committed. One that replaces blocks is only taken
+    /// by engines that [allow reorgs](ConsensusEngine::allows_reorgs), and only if the sum of its
+    /// difficulties beats that of the blocks it replaces, so ties keep the local chain, and only
+    /// once every block of it is valid. The transactions of the dropped blocks whose nonce the
+    /// branch did not use go back to the mempool.
+    pub fn import_branch(&mut self, blocks: Vec<Block>) -> Result<bool> {
+        let scheme = self.spec.hash_scheme;
+        let mut branch = Vec::new();
+        for block in blocks {
+            if branch.is_empty() {
+                if let Some(local) = self.storage.get_block_by_number(block.header.number)? {
+                    if local.get_block_hash(scheme)? == block.get_block_hash(scheme)? {
+                        continue;
+                    }
+                }
+            }
+            branch.push(block);
+        }
+        let Some(first) = branch.first() else {
+            return Ok(false);
+        };
+
+        let head = get_last_block(self.storage())?.header.number;
+        let fork = first
+            .header
+            .number
+            .checked_sub(1)
+            .ok_or_eyre("a branch cannot replace the genesis block")?;
+        let parent = self
+            .storage
+            .get_block_by_number(fork)?
+            .ok_or_else(|| eyre!("branch starts at block {} past the head", first.header.number))?;
+        if first.header.parent_hash != parent.get_block_hash(scheme)? {
+            bail!("branch does not fork off the chain at block {}", fork);
+        }
+        if fork == head {
+            for block in branch {
+                self.commit_block(block)?;
+            }
+            return Ok(true);
+        }
+        if !self.consensus.allows_reorgs() {
+            bail!("block {} conflicts with the chain, whose blocks are final", first.header.number);
+        }
+
+        // Checking the work first keeps a branch of made up blocks from rewinding the chain.
+        for (index, block) in branch.iter().enumerate() {
+            let parent = if index == 0 { &parent } else { &branch[index - 1] };
+            self.consensus.verify_seal(self.storage(), parent, &block.header)?;
+        }
+        let dropped = self.storage.get_last_n_blocks((head - fork) as usize)?;
+        if total_difficulty(&branch) <= total_difficulty(&dropped) {
+            return Ok(false);
+        }
+        // So is every other rule, so that an invalid branch is refused before anything is dropped.
+        let mut state = self.get_state_at(fork)?;
+        for (index, block) in branch.iter().enumerate() {
+            let parent = if index == 0 { &parent } else { &branch[index - 1] };
+            state = self.validate_child(parent, &state, block)?;
+        }
+
+        println!("🔀 Switching to a heavier branch from block {} on 🔀", fork + 1);
+        self.rewind(fork)?;
+        for block in branch.clone() {
+            // Only storage failures get here, which would not let the old blocks back in either,
+            // so the chain stays at the fork until blocks are imported again.
+            self.commit_block(block)?;
+        }
+
+        let state = State::load(self.storage())?;
+        for tx in dropped.iter().flat_map(|block| &block.txs) {
+            if tx.nonce >= state.get_account(&tx.sender).nonce {
+                self.storage.add_to_mempool(tx)?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Drops every block after block `number` and moves the accounts back to the state after it.
+    fn rewind(&self, number: u64) -> Result<()> {
+        let state = State::load(self.storage())?;
+        let target = self.get_state_at(number)?;
+        let journal = StateJournal {
+            number,
+            accounts: target.changed_accounts(&state),
+            removed: target.removed_accounts(&state),
+            metadata: vec![],
+        };
+        self.storage.put_metadata(STATE_JOURNAL_KEY, &serde_json::to_string(&Some(&journal))?)?;
+        self.storage.truncate_blocks(number)?;
+        self.apply_state_journal(&journal)
+    }
+
+    /// Checks that `block` is the genesis block of the chain spec and returns the state after it.
+    fn verify_genesis(&self, block: &Block) -> Result<State> {
+        let scheme = self.spec.hash_scheme;
+        let genesis = Block::genesis(&self.spec);
+        let hash = block.get_block_hash(scheme)?;
+        if hash == genesis.get_block_hash(scheme)? {
+            return Ok(self.spec.genesis_state());
+        }
+
+        // Legacy chains were started before the genesis block was derived from the spec, so
+        // theirs can only be checked by executing it.
+        if scheme == HashScheme::Legacy {
+            return verify_state_transition(
+                &self.spec.genesis_state(),
+                block,
+                self.spec.block_reward,
+                self.consensus(),
+            );
+        }
+
+        bail!("block {} is not the genesis block of chain {}", hash, self.spec.chain_id)
+    }
+
+    /// Rebuilds the state after block `number`. The state after the head is read from storage;
+    /// older states are rolled back from it with the [`StateUndo`] of every block after `number`
+    /// and checked against the `state_root` of block `number`.
+    pub fn get_state_at(&self, number: u64) -> Result<State> {
+        let head = get_last_block(self.storage())?;
+        if number > head.header.number {
+            bail!("block {} is past the head of the chain at block {}", number, head.header.number);
+        }
+        if number == head.header.number {
+            return State::load(self.storage());
+        }
+
+        let mut accounts: BTreeMap<Address, Account> = self
+            .storage
+            .get_accounts()?
+            .into_iter()
+            .map(|account| (account.address, account))
+            .collect();
+        for n in (number + 1..=head.header.number).rev() {
+            let undo = self
+                .storage
+                .get_metadata(&format!("{}{}", STATE_UNDO_KEY, n))?
+                .ok_or_else(|| eyre!("the state changes of block {} are missing", n))?;
+            let undo: StateUndo = serde_json::from_str(&undo)?;
+            for address in &undo.created {
+                accounts.remove(address);
+            }
+            for account in undo.accounts {
+                accounts.insert(account.address, account);
+            }
+        }
+
+        let state = State::from_accounts(accounts.into_values().collect());
+        let block = self
+            .storage
+            .get_block_by_number(number)?
+            .ok_or_else(|| eyre!("block {} is missing from storage", number))?;
+        if state.get_state_root() != block.header.state_root {
+            bail!("the rolled back state does not match the state root of block {}", number);
+        }
+
+        Ok(state)
+    }
+
+    /// Returns the account at `address` as of block `block_number` with a proof against that
+    /// block's `state_root`, to be checked with [`crate::account::verify_account_proof`].
+    ///
+    /// The proof is read from the trie nodes stored with each block, so older blocks cost no more
+    /// to prove than the head.
+    pub fn get_account_proof(&self, address: &Address, block_number: u64) -> Result<AccountProof> {
+        let block = self
+            .storage
+            .get_block_by_number(block_number)?
+            .ok_or_else(|| eyre!("block {} is not part of the chain", block_number))?;
+        let key = get_account_key(address);
+        let proof = prove_stored(block.header.state_root, key, |hash| {
+            let node = self
+                .storage
+                .get_metadata(&format!("{}{}", TRIE_NODE_KEY, hash))?
+                .ok_or_else(|| eyre!("trie node {} of block {} is missing", hash, block_number))?;
+            let node: StoredNode = serde_json::from_str(&node)?;
+            if node.hash() != hash {
+                bail!("trie node {} of block {} is corrupt", hash, block_number);
+            }
+            Ok(node)
+        })?;
+
+        let account = match &proof.leaf {
+            Some(leaf) if leaf.key == key => {
+                let account = self
+                    .storage
+                    .get_metadata(&format!("{}{}", ACCOUNT_LEAF_KEY, leaf.value))?
+                    .ok_or_else(|| eyre!("account with leaf {} is missing", leaf.value))?;
+                // Accounts with the same fields share a leaf value, whatever their address.
+                let account = Account { address: *address, ..serde_json::from_str(&account)? };
+                if account.get_account_hash() != leaf.value {
+                    bail!("account with leaf {} is corrupt", leaf.value);
+                }
+                Some(account)
+            }
+            _ => None,
+        };
+
+        Ok(AccountProof { address: *address, block_number, account, proof })
+    }
+
+    fn apply_state_journal(&self, journal: &StateJournal) -> Result<()> {
+        for account in &journal.accounts {
+            self.storage.put_account(account)?;
+        }
+        for address in &journal.removed {
+            self.storage.delete_account(address)?;
+        }
+        for (key, value) in &journal.metadata {
+            self.storage.put_metadata(key, value)?;
+        }
+        self.storage.put_metadata(STATE_JOURNAL_KEY, &serde_json::to_string(&None::<StateJournal>)?)
+    }
+
+    fn replay_state_journal(&self) -> Result<()> {
+        let Some(journal) = self.storage.get_metadata(STATE_JOURNAL_KEY)? else {
+            return Ok(());
+        };
+        let Some(journal) = serde_json::from_str::<Option<StateJournal>>(&journal)? else {
+            return Ok(());
+        };
+
+        let head = self.storage.get_last_block()?.map(|block| block.header.number);
+        if head == Some(journal.number) {
+            println!("🔄 Finishing the state update of block {} 🔄", journal.number);
+            self.apply_state_journal(&journal)
+        } else {
+            // The block itself never made it to storage, so neither may its state changes.
+            self.storage
+                .put_metadata(STATE_JOURNAL_KEY, &serde_json::to_string(&None::<StateJournal>)?)
+        }
+    }
+}
+
+fn total_difficulty(blocks: &[Block]) -> u128 {
+    blocks.iter().map(|block| block.header.difficulty as u128).sum()
+}
+
+fn unix_time() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+pub fn get_last_block(storage: &dyn Storage) -> Result<Block> {
+    storage.get_last_block()?.ok_or_eyre("blockchain has no blocks")
 }
 
-pub fn get_last_block() -> Result<Block> {
-    let path = Path::new(BLOCKCHAIN_JSON);
-    let mut file = File::open(path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let blockchain: Blockchain = serde_json::from_str(&contents)?;
+pub fn get_commit_certificate(
+    storage: &dyn Storage,
+    number: u64,
+) -> Result<Option<CommitCertificate>> {
+    let key = format!("{}{}", COMMIT_CERTIFICATE_KEY, number);
+    let Some(certificate) = storage.get_metadata(&key)? else {
+        return Ok(None);
+    };
 
-    Ok(blockchain.blocks.last().unwrap().clone())
+    Ok(Some(serde_json::from_str(&certificate)?))
 }
 
-pub fn get_last_n_blocks(n: usize) -> Result<NBlocks> {
-    let path = Path::new(BLOCKCHAIN_JSON);
-    let mut file = File::open(path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let blockchain: Blockchain = serde_json::from_str(&contents)?;
+pub fn get_vote_certificate(storage: &dyn Storage, number: u64) -> Result<Option<VoteCertificate>> {
+    let key = format!("{}{}", VOTE_CERTIFICATE_KEY, number);
+    let Some(certificate) = storage.get_metadata(&key)? else {
+        return Ok(None);
+    };
 
-    let num_blocks = blockchain.blocks.len();
-    let start_index = if num_blocks > n { num_blocks - n } else { 0 };
-    let last_n_blocks = blockchain.blocks[start_index..].to_vec();
+    Ok(Some(serde_json::from_str(&certificate)?))
+}
 
-    let nblocks = NBlocks { blocks: last_n_blocks };
+/// The last `n` blocks of the chain, with the commit certificates or votes of those that have
+/// them.
+pub fn get_last_n_blocks(storage: &dyn Storage, n: usize) -> Result<NBlocks> {
+    let last_n_blocks = storage.get_last_n_blocks(n)?;
+    let mut certificates = vec![];
+    let mut votes = vec![];
+    for block in &last_n_blocks {
+        certificates.extend(get_commit_certificate(storage, block.header.number)?);
+        votes.extend(get_vote_certificate(storage, block.header.number)?);
+    }
+    let nblocks = NBlocks { blocks: last_n_blocks, certificates, votes };
 
     Ok(nblocks)
 }
 
-pub fn update_blockchain(chain: &Blockchain) -> Result<()> {
-    let path = Path::new(BLOCKCHAIN_JSON);
-    let blockchain_json = serde_json::to_string_pretty(chain)?;
-    let mut file = OpenOptions::new().write(true).truncate(true).open(path)?;
-    file.write_all(blockchain_json.as_bytes())?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        account::verify_account_proof,
+        chainspec::{ConsensusConfig, Genesis, GenesisAccount},
+        consensus::MIN_DIFFICULTY,
+        storage::MemoryStorage,
+        transaction::Transaction,
+    };
+
+    const KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+    const OTHER_KEY: &str = "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
+    const BLOCK_REWARD: u64 = 10;
+
+    fn blockchain() -> Blockchain {
+        let spec = ChainSpec {
+            consensus: ConsensusConfig::ProofOfWork { difficulty: MIN_DIFFICULTY },
+            block_reward: BLOCK_REWARD,
+            ..ChainSpec::default()
+        };
+        Blockchain::init(Arc::new(MemoryStorage::new()), spec).unwrap()
+    }
+
+    /// Mines `count` empty blocks with `key` on top of the head of `blockchain` and returns them.
+    fn mine(blockchain: &mut Blockchain, count: usize, key: &str) -> Vec<Block> {
+        let wallet: LocalWallet = key.parse().unwrap();
+        let mut blocks = vec![];
+        for _ in 0..count {
+            let parent = get_last_block(blockchain.storage()).unwrap();
+            let block = blockchain.propose_block(vec![], vec![], &parent, &wallet).unwrap();
+            blockchain.commit_block(block.clone()).unwrap();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    fn head_hash(blockchain: &Blockchain) -> B256 {
+        get_last_block(blockchain.storage()).unwrap().get_block_hash(HashScheme::Rlp).unwrap()
+    }
+
+    #[test]
+    fn headers_carry_the_total_supply_after_the_block() {
+        let mut blockchain = blockchain();
+        let blocks = mine(&mut blockchain, 2, KEY);
+
+        assert_eq!(blocks[1].header.total_supply, 2 * u128::from(BLOCK_REWARD));
+        assert_eq!(State::load(blockchain.storage()).unwrap().total_supply(), 20);
 
-    Ok(())
+        // A block that issues more than the reward is refused even with a valid seal.
+        let other = self::blockchain();
+        let mut inflated = mine(&mut self::blockchain(), 1, KEY).remove(0);
+        inflated.header.total_supply += 1;
+        other.consensus().seal(&mut inflated.header, &KEY.parse().unwrap()).unwrap();
+        let err = other.validate_block(&inflated).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<BlockValidationError>(),
+            Some(&BlockValidationError::TotalSupplyMismatch { expected: 10, got: 11 })
+        );
+    }
+
+    #[tokio::test]
+    async fn committing_drops_only_the_transactions_whose_nonce_was_used() {
+        let mut blockchain = blockchain();
+        mine(&mut blockchain, 1, KEY);
+        let wallet: LocalWallet = KEY.parse().unwrap();
+        let sender = Address::from(wallet.address());
+        let chain_id = blockchain.spec().chain_id;
+        let transfer =
+            |nonce| Transaction::new(sender, Address::new([7; 20]), 1, 0, nonce, chain_id, KEY);
+        let (included, queued) = (transfer(0).await.unwrap(), transfer(2).await.unwrap());
+        blockchain.storage().add_to_mempool(&included).unwrap();
+        blockchain.storage().add_to_mempool(&queued).unwrap();
+
+        let parent = get_last_block(blockchain.storage()).unwrap();
+        let block = blockchain.propose_block(vec![included], vec![], &parent, &wallet).unwrap();
+        assert_eq!(blockchain.storage().get_mempool().unwrap().len(), 2);
+        blockchain.commit_block(block).unwrap();
+
+        let mempool = blockchain.storage().get_mempool().unwrap();
+        assert_eq!(mempool.iter().map(|tx| tx.nonce).collect::<Vec<_>>(), [2]);
+    }
+
+    #[test]
+    fn proves_accounts_of_past_blocks_from_stored_nodes() {
+        let mut blockchain = blockchain();
+        mine(&mut blockchain, 2, KEY);
+        mine(&mut blockchain, 2, OTHER_KEY);
+
+        let miners = [KEY, OTHER_KEY].map(|key| key.parse::<LocalWallet>().unwrap().address());
+        for number in 0..=4 {
+            let block = blockchain.storage().get_block_by_number(number).unwrap().unwrap();
+            let state = blockchain.get_state_at(number).unwrap();
+            for address in miners.map(Address::from).into_iter().chain([Address::new([9; 20])]) {
+                let proof = blockchain.get_account_proof(&address, number).unwrap();
+                verify_account_proof(&block.header.state_root, &proof).unwrap();
+                assert_eq!(proof.account, state.get_account_proof(&address).0);
+            }
+        }
+        assert!(blockchain.get_account_proof(&Address::ZERO, 5).is_err());
+    }
+
+    #[test]
+    fn stores_the_certificate_of_a_block_that_made_it_before_a_crash() {
+        let mut blockchain = blockchain();
+        mine(&mut blockchain, 1, KEY);
+        let parent = get_last_block(blockchain.storage()).unwrap();
+        let wallet: LocalWallet = KEY.parse().unwrap();
+        let block = blockchain.propose_block(vec![], vec![], &parent, &wallet).unwrap();
+        let state = State::load(blockchain.storage()).unwrap();
+        let post_state = blockchain.validate_block(&block).unwrap();
+        let journal = StateJournal {
+            number: block.header.number,
+            accounts: post_state.changed_accounts(&state),
+            removed: vec![],
+            metadata: vec![("certificate".to_string(), "votes".to_string())],
+        };
+
+        // The node goes down right after appending the block.
+        let journal = serde_json::to_string(&Some(&journal)).unwrap();
+        blockchain.storage().put_metadata(STATE_JOURNAL_KEY, &journal).unwrap();
+        blockchain.storage().append_block(&block).unwrap();
+        assert_eq!(blockchain.storage().get_metadata("certificate").unwrap(), None);
+
+        let reopened =
+            Blockchain::open(blockchain.storage.clone(), blockchain.spec.clone()).unwrap();
+        let certificate = reopened.storage().get_metadata("certificate").unwrap();
+        assert_eq!(certificate.as_deref(), Some("votes"));
+        let state_root = State::load(reopened.storage()).unwrap().get_state_root();
+        assert_eq!(state_root, block.header.state_root);
+    }
+
+    #[test]
+    fn weighs_stakes_that_add_up_past_a_u64() {
+        let validators = [Address::new([1; 20]), Address::new([2; 20]), Address::new([3; 20])];
+        let stake = GenesisAccount { balance: 0, stake: u64::MAX };
+        let spec = ChainSpec {
+            consensus: ConsensusConfig::ProofOfStake { epoch: 100, min_stake: 1 },
+            genesis: Genesis {
+                alloc: validators.iter().map(|address| (*address, stake.clone())).collect(),
+                ..Genesis::default()
+            },
+            ..ChainSpec::default()
+        };
+        let blockchain = Blockchain::init(Arc::new(MemoryStorage::new()), spec).unwrap();
+
+        assert!(!blockchain.has_quorum(&validators[..2]).unwrap());
+        assert!(blockchain.has_quorum(&validators).unwrap());
+    }
+
+    #[test]
+    fn switches_to_a_branch_with_more_work_only() {
+        let mut local = blockchain();
+        let mut remote = blockchain();
+        let dropped = mine(&mut local, 2, KEY);
+        let branch = mine(&mut remote, 3, OTHER_KEY);
+
+        // A branch with less work than the blocks it replaces is ignored.
+        assert!(!remote.import_branch(dropped.clone()).unwrap());
+        assert_eq!(get_last_block(remote.storage()).unwrap().header.number, 3);
+
+        assert!(local.import_branch(branch).unwrap());
+        assert_eq!(head_hash(&local), head_hash(&remote));
+        assert_eq!(State::load(local.storage()).unwrap(), State::load(remote.storage()).unwrap());
+        let old_hash = dropped[1].get_block_hash(HashScheme::Rlp).unwrap();
+        assert!(local.storage().get_block_by_hash(&old_hash).unwrap().is_none());
+        assert_eq!(local.get_state_at(1).unwrap().total_supply(), u128::from(BLOCK_REWARD));
+    }
+
+    #[test]
+    fn refuses_an_invalid_branch_before_dropping_any_block() {
+        let mut local = blockchain();
+        let mut remote = blockchain();
+        mine(&mut local, 2, KEY);
+        let mut branch = mine(&mut remote, 3, OTHER_KEY);
+        let (head, state) = (head_hash(&local), State::load(local.storage()).unwrap());
+
+        branch[2].header.total_supply += 1;
+        let sealer = blockchain();
+        sealer.consensus().seal(&mut branch[2].header, &OTHER_KEY.parse().unwrap()).unwrap();
+        let err = local.import_branch(branch).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BlockValidationError>(),
+            Some(BlockValidationError::TotalSupplyMismatch { .. })
+        ));
+        assert_eq!(head_hash(&local), head);
+        assert_eq!(State::load(local.storage()).unwrap(), state);
+    }
 }
@@ -0,0 +1,302 @@
+use crate::{
+    account::Account, consensus::MIN_DIFFICULTY, state::State, storage::Storage,
+    validator::ValidatorSet,
+};
+use eyre::{bail, eyre, Result};
+use helper::Address;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, fs, path::Path, str::FromStr};
+
+/// Chain id of the default network.
+pub const DEFAULT_CHAIN_ID: u64 = 1;
+
+/// Seconds between two blocks of the default network.
+pub const DEFAULT_BLOCK_TIME: u64 = 5;
+
+/// Blocks between two Clique checkpoints of the default network, and between two proof of
+/// stake epochs of chains whose spec sets no other.
+pub const DEFAULT_EPOCH: u64 = 30000;
+
+/// Least stake that makes an account a validator under proof of stake, unless the spec sets
+/// another one.
+pub const DEFAULT_MIN_STAKE: u64 = 1;
+
+/// Difficulty of the genesis block of proof of work chains, unless their spec sets another one.
+pub const DEFAULT_DIFFICULTY: u64 = 1 << 18;
+
+/// Validators of the default network.
+pub const DEFAULT_VALIDATORS: [&str; 5] = [
+    "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266",
+    "0x70997970C51812dc3A010C7d01b50e0d17dc79C8",
+    "0x3C44CdDdB6a900fa2b585dd299e03d12FA4293BC",
+    "0x90F79bf6EB2c4f870365E785982E1f101E93b906",
+    "0x15d34AAf54267DB7D7c367839AAf71A00a2C6A65",
+];
+
+const CHAIN_SPEC_KEY: &str = "chain_spec";
+
+/// Parameters that tell one network apart from another. Every node of a network must run with
+/// the same spec.
+///
+/// A spec can be read from a JSON file with [`ChainSpec::from_file`]. Only `chain_id` is
+/// required, every other field falls back to the one of the default network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainSpec {
+    /// Chain id transactions are signed for, following EIP-155, so that a transaction of one
+    /// network cannot be replayed on another.
+    pub chain_id: u64,
+    /// How blocks and transactions are hashed.
+    #[serde(default)]
+    pub hash_scheme: HashScheme,
+    /// The first block of the chain, which every node derives from the spec alone.
+    #[serde(default)]
+    pub genesis: Genesis,
+    /// Who may propose blocks and how they take turns.
+    #[serde(default)]
+    pub consensus: ConsensusConfig,
+    /// Addresses allowed to propose blocks. Under Clique they are only the first signers, which
+    /// vote on who joins or leaves after them. Proof of stake takes its validators from the
+    /// stakes instead.
+    #[serde(default = "default_validators")]
+    pub validators: ValidatorSet,
+    /// Seconds between two blocks.
+    #[serde(default = "default_block_time")]
+    pub block_time: u64,
+    /// Amount every block after genesis issues to its miner, on top of the fees of its
+    /// transactions.
+    #[serde(default)]
+    pub block_reward: u64,
+}
+
+/// The consensus engine of a chain and its parameters.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "engine", rename_all = "snake_case")]
+pub enum ConsensusConfig {
+    /// The validators take turns in the order of the set, which never changes. Only kept for
+    /// chains created before [`ConsensusConfig::Clique`].
+    RoundRobin,
+    /// Proof of authority modeled on Clique, see [`crate::consensus::Clique`].
+    Clique {
+        /// Blocks between two checkpoints.
+        #[serde(default = "default_epoch")]
+        epoch: u64,
+    },
+    /// Byzantine fault tolerant rounds modeled on Tendermint, see
+    /// [`crate::consensus::Tendermint`]. Round timeouts grow by a block time per round.
+    Tendermint,
+    /// Anyone may mine blocks by finding a nonce whose hash meets the difficulty, see
+    /// [`crate::consensus::ProofOfWork`].
+    ProofOfWork {
+        /// Difficulty of the genesis block, from which every later difficulty is retargeted.
+        #[serde(default = "default_difficulty")]
+        difficulty: u64,
+    },
+    /// Accounts that stake become validators, which propose in proportion to their stake, see
+    /// [`crate::consensus::ProofOfStake`].
+    ProofOfStake {
+        /// Blocks between two epochs, at whose start the validators are taken from the stakes.
+        #[serde(default = "default_epoch")]
+        epoch: u64,
+        /// Least stake that makes an account a validator.
+        #[serde(default = "default_min_stake")]
+        min_stake: u64,
+    },
+}
+
+/// Contents of the genesis block.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Genesis {
+    /// Timestamp of the genesis block, fixed so that its hash does not depend on when or where
+    /// the chain was started.
+    #[serde(default)]
+    pub timestamp: u64,
+    /// Accounts the chain starts out with.
+    #[serde(default)]
+    pub alloc: BTreeMap<Address, GenesisAccount>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenesisAccount {
+    pub balance: u64,
+    /// Stake of the account on top of its balance, which makes the validators of the first
+    /// epoch under proof of stake.
+    #[serde(default)]
+    pub stake: u64,
+}
+
+/// The ways a chain can hash its blocks and transactions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashScheme {
+    /// Keccak of the concatenated string forms of the fields, with `extra_data` and the
+    /// transactions of a block as JSON. Only kept to read chains created before [`HashScheme::Rlp`].
+    Legacy,
+    /// Keccak of the RLP encoding. A block is hashed through its header, which commits to the
+    /// transactions by their root, and the miner signs the header without its seal.
+    #[default]
+    Rlp,
+}
+
+impl Default for ConsensusConfig {
+    fn default() -> Self {
+        ConsensusConfig::Clique { epoch: DEFAULT_EPOCH }
+    }
+}
+
+impl Default for ChainSpec {
+    fn default() -> Self {
+        ChainSpec::new(DEFAULT_CHAIN_ID, HashScheme::default())
+    }
+}
+
+impl ChainSpec {
+    /// The spec of the default network with another chain id and hash scheme. Chains hashed with
+    /// the legacy scheme predate Clique and keep taking turns round robin.
+    pub fn new(chain_id: u64, hash_scheme: HashScheme) -> Self {
+        let consensus = match hash_scheme {
+            HashScheme::Legacy => ConsensusConfig::RoundRobin,
+            HashScheme::Rlp => ConsensusConfig::default(),
+        };
+
+        ChainSpec {
+            chain_id,
+            hash_scheme,
+            genesis: Genesis::default(),
+            consensus,
+            validators: default_validators(),
+            block_time: DEFAULT_BLOCK_TIME,
+            block_reward: 0,
+        }
+    }
+
+    /// Reads a spec from the JSON file at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let spec: ChainSpec = serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|err| eyre!("invalid chain spec {}: {}", path.display(), err))?;
+
+        if spec.block_time == 0 {
+            bail!("chain spec {} has a block time of 0", path.display());
+        }
+        match spec.consensus {
+            ConsensusConfig::Clique { epoch: 0 } => {
+                bail!("chain spec {} has a Clique epoch of 0", path.display())
+            }
+            ConsensusConfig::ProofOfStake { epoch: 0, .. } => {
+                bail!("chain spec {} has a proof of stake epoch of 0", path.display())
+            }
+            ConsensusConfig::ProofOfStake { min_stake, .. }
+                if spec.genesis_state().stakers(min_stake).is_empty() =>
+            {
+                bail!("chain spec {} allocates no stake to start proof of stake", path.display())
+            }
+            ConsensusConfig::ProofOfWork { difficulty } if difficulty < MIN_DIFFICULTY => bail!(
+                "chain spec {} has a difficulty below the minimum of {}",
+                path.display(),
+                MIN_DIFFICULTY
+            ),
+            _ => (),
+        }
+        // The legacy seal hash leaves out the fields Clique votes and checkpoints with and the
+        // ones proof of work and proof of stake are checked with.
+        if spec.hash_scheme == HashScheme::Legacy {
+            match spec.consensus {
+                ConsensusConfig::Clique { .. } => {
+                    bail!("chain spec {} runs Clique with the legacy hash scheme", path.display())
+                }
+                ConsensusConfig::ProofOfWork { .. } => bail!(
+                    "chain spec {} runs proof of work with the legacy hash scheme",
+                    path.display()
+                ),
+                ConsensusConfig::ProofOfStake { .. } => bail!(
+                    "chain spec {} runs proof of stake with the legacy hash scheme",
+                    path.display()
+                ),
+                _ => (),
+            }
+        }
+
+        Ok(spec)
+    }
+
+    /// The spec a data directory was created with, if any.
+    pub fn load<S: Storage + ?Sized>(storage: &S) -> Result<Option<Self>> {
+        let Some(spec) = storage.get_metadata(CHAIN_SPEC_KEY)? else {
+            return Ok(None);
+        };
+
+        let mut spec: serde_json::Value = serde_json::from_str(&spec)?;
+        // Specs stored before the hash scheme and the consensus engine were recorded belong to
+        // chains that were hashed with the legacy scheme and took turns round robin.
+        if let Some(fields) = spec.as_object_mut() {
+            fields.entry("hash_scheme").or_insert_with(|| HashScheme::Legacy.to_string().into());
+            fields.entry("consensus").or_insert(serde_json::to_value(ConsensusConfig::RoundRobin)?);
+        }
+
+        Ok(Some(serde_json::from_value(spec)?))
+    }
+
+    pub fn store(&self, storage: &dyn Storage) -> Result<()> {
+        storage.put_metadata(CHAIN_SPEC_KEY, &serde_json::to_string(self)?)
+    }
+
+    /// The state after the genesis block, holding the allocated accounts.
+    pub fn genesis_state(&self) -> State {
+        State::from_accounts(
+            self.genesis
+                .alloc
+                .iter()
+                .map(|(address, account)| Account {
+                    address: *address,
+                    balance: account.balance,
+                    nonce: 0,
+                    stake: account.stake,
+                    unbonding: 0,
+                    unbonding_until: 0,
+                })
+                .collect(),
+        )
+    }
+}
+
+impl HashScheme {
+    /// The scheme the blocks in `storage` are hashed with.
+    pub fn load<S: Storage + ?Sized>(storage: &S) -> Result<Self> {
+        Ok(ChainSpec::load(storage)?.map_or_else(HashScheme::default, |spec| spec.hash_scheme))
+    }
+}
+
+impl fmt::Display for HashScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashScheme::Legacy => write!(f, "legacy"),
+            HashScheme::Rlp => write!(f, "rlp"),
+        }
+    }
+}
+
+fn default_validators() -> ValidatorSet {
+    let validators = DEFAULT_VALIDATORS
+        .iter()
+        .map(|validator| Address::from_str(validator))
+        .collect::<Result<Vec<_>, _>>()
+        .expect("DEFAULT_VALIDATORS only holds valid addresses");
+
+    ValidatorSet::new(validators).expect("DEFAULT_VALIDATORS is a valid validator set")
+}
+
+fn default_block_time() -> u64 {
+    DEFAULT_BLOCK_TIME
+}
+
+fn default_epoch() -> u64 {
+    DEFAULT_EPOCH
+}
+
+fn default_difficulty() -> u64 {
+    DEFAULT_DIFFICULTY
+}
+
+fn default_min_stake() -> u64 {
+    DEFAULT_MIN_STAKE
+}
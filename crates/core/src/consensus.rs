@@ -0,0 +1,132 @@
+use crate::{
+    block::{Block, Header},
+    blockchain::BlockValidationError,
+    chainspec::{ChainSpec, ConsensusConfig},
+    state::State,
+    storage::Storage,
+};
+use ethers::signers::LocalWallet;
+use eyre::{bail, Result};
+use helper::{Address, B256};
+use std::sync::Arc;
+
+mod clique;
+mod proof_of_stake;
+mod proof_of_work;
+mod round_robin;
+mod tendermint;
+
+pub use clique::{Clique, Snapshot, Tally, Vote, DIFF_IN_TURN, DIFF_NO_TURN};
+pub use proof_of_stake::ProofOfStake;
+pub use proof_of_work::{ProofOfWork, MIN_DIFFICULTY};
+pub use round_robin::RoundRobin;
+pub use tendermint::Tendermint;
+
+/// A way of agreeing on blocks, from who may propose the next block to what has to be true of a
+/// block before it is committed.
+///
+/// [`crate::blockchain::Blockchain`] runs a block through the steps of the engine of its chain
+/// spec, so the binaries work the same whichever engine the chain runs. A proposer builds the
+/// header, lets the engine [`prepare`](ConsensusEngine::prepare) it, executes the transactions,
+/// [`finalize`](ConsensusEngine::finalize)s the state and [`seal`](ConsensusEngine::seal)s the
+/// header. Everyone else checks the block with
+/// [`verify_header`](ConsensusEngine::verify_header) and
+/// [`verify_seal`](ConsensusEngine::verify_seal), executes it and finalizes the state the same
+/// way, and commits it once [`has_quorum`](ConsensusEngine::has_quorum) says enough of the
+/// [`voting_powers`](ConsensusEngine::voting_powers) of the validators voted for it.
+///
+/// Engines are shared between tasks like [`Storage`], so everything takes `&self` and engines
+/// that keep state in memory are expected to use interior mutability.
+pub trait ConsensusEngine: Send + Sync {
+    /// Whether `signer` may propose the child of `parent`, the head of the chain, when the chain
+    /// has not made progress for `waited` seconds.
+    fn can_propose(
+        &self,
+        storage: &dyn Storage,
+        parent: &Block,
+        signer: &Address,
+        waited: u64,
+    ) -> Result<bool>;
+
+    /// Fills in the consensus fields of `header`, the child of `parent` proposed by
+    /// `header.miner`, before its transactions are executed.
+    fn prepare(&self, storage: &dyn Storage, parent: &Block, header: &mut Header) -> Result<()>;
+
+    /// Makes the changes the engine itself makes to the state after the transactions of the
+    /// block of `header`. Called by proposers and validators alike, before the state root is
+    /// computed.
+    fn finalize(&self, _header: &Header, _state: &mut State) -> Result<()> {
+        Ok(())
+    }
+
+    /// Seals the finished `header` with the key of its miner.
+    fn seal(&self, header: &mut Header, miner_wallet: &LocalWallet) -> Result<()>;
+
+    /// Makes every [`seal`](ConsensusEngine::seal) of a header that is not a child of `head`, the
+    /// new head of the chain, give up, for engines whose seals take a while, such as proof of work
+    /// once someone else found the block first. Seals that only start later give up too.
+    fn abort_seal(&self, _head: &B256) {}
+
+    /// Checks the consensus fields of `header` as the child of `parent`, except for its seal.
+    fn verify_header(
+        &self,
+        storage: &dyn Storage,
+        parent: &Block,
+        header: &Header,
+    ) -> Result<(), BlockValidationError>;
+
+    /// Checks that `header`, the child of `parent`, was sealed by someone allowed to seal it.
+    fn verify_seal(
+        &self,
+        storage: &dyn Storage,
+        parent: &Block,
+        header: &Header,
+    ) -> Result<(), BlockValidationError>;
+
+    /// The validators whose votes count for the child of `parent`.
+    fn validators(&self, storage: &dyn Storage, parent: &Block) -> Result<Vec<Address>>;
+
+    /// How much the vote of each of the [`validators`](ConsensusEngine::validators) for the child
+    /// of `parent` weighs. Defaults to one vote each.
+    fn voting_powers(&self, storage: &dyn Storage, parent: &Block) -> Result<Vec<(Address, u64)>> {
+        Ok(self.validators(storage, parent)?.into_iter().map(|validator| (validator, 1)).collect())
+    }
+
+    /// Whether yes votes with a voting power of `yes_votes` out of a total of `validators` are
+    /// enough to commit a proposed block. Defaults to more than two thirds.
+    fn has_quorum(&self, yes_votes: u128, validators: u128) -> bool {
+        3 * yes_votes > 2 * validators
+    }
+
+    /// Whether blocks after genesis are only final with a [`crate::bft::CommitCertificate`], so
+    /// that they can only be committed with
+    /// [`crate::blockchain::Blockchain::commit_certified_block`].
+    fn requires_certificate(&self) -> bool {
+        false
+    }
+
+    /// Whether a branch holding more work than the blocks it replaces may take over committed
+    /// blocks, for engines whose blocks are never final, such as proof of work.
+    fn allows_reorgs(&self) -> bool {
+        false
+    }
+
+    /// Queues a vote to add `candidate` to the signers (`authorize`) or to remove it, cast in the
+    /// blocks this node proposes.
+    fn propose_vote(&self, candidate: Address, _authorize: bool) -> Result<()> {
+        bail!("cannot vote on {}, the consensus engine of the chain has no signer votes", candidate)
+    }
+}
+
+/// The engine of the chain `spec`.
+pub fn build(spec: &ChainSpec) -> Arc<dyn ConsensusEngine> {
+    match spec.consensus {
+        ConsensusConfig::RoundRobin => Arc::new(RoundRobin::new(spec)),
+        ConsensusConfig::Clique { epoch } => Arc::new(Clique::new(spec, epoch)),
+        ConsensusConfig::Tendermint => Arc::new(Tendermint::new(spec)),
+        ConsensusConfig::ProofOfWork { .. } => Arc::new(ProofOfWork::new(spec)),
+        ConsensusConfig::ProofOfStake { epoch, min_stake } => {
+            Arc::new(ProofOfStake::new(spec, epoch, min_stake))
+        }
+    }
+}
@@ -0,0 +1,546 @@
+use super::ConsensusEngine;
+use crate::{
+    block::{Block, Header},
+    blockchain::BlockValidationError,
+    chainspec::{ChainSpec, HashScheme},
+    storage::Storage,
+};
+use ethers::signers::LocalWallet;
+use eyre::{bail, eyre, Result};
+use helper::{Address, B256};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Difficulty of a block sealed by the signer whose turn it is.
+pub const DIFF_IN_TURN: u64 = 2;
+
+/// Difficulty of a block sealed by any other signer.
+pub const DIFF_NO_TURN: u64 = 1;
+
+/// Seconds a block may be ahead of the local clock on top of the block period.
+const MAX_FUTURE_DRIFT: u64 = 15;
+
+/// Snapshots kept in memory before the cache is dropped and rebuilt from the chain.
+const SNAPSHOT_CACHE_SIZE: usize = 128;
+
+/// Proof of authority modeled on Clique (EIP-225).
+///
+/// The signers take turns in the order of their addresses, and the one whose turn it is seals
+/// with difficulty [`DIFF_IN_TURN`]. When it misses its slot, any other signer may step in with
+/// difficulty [`DIFF_NO_TURN`], but no signer may seal more than one of any `len / 2 + 1`
+/// consecutive blocks. Signers vote to add or remove a signer through the `candidate` and
+/// `authorize` fields of the headers they seal, and a candidate that gets the votes of more than
+/// half of the signers is added or removed right away. Every `epoch` blocks a checkpoint block
+/// drops the pending votes and lists the signers in its `signers` field.
+pub struct Clique {
+    epoch: u64,
+    period: u64,
+    scheme: HashScheme,
+    proposals: Mutex<BTreeMap<Address, bool>>,
+    snapshots: Mutex<HashMap<B256, Snapshot>>,
+}
+
+/// The signers after a block, together with what it takes to check the blocks after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Number of the block the snapshot was taken after.
+    pub number: u64,
+    pub signers: BTreeSet<Address>,
+    /// Signers of the latest blocks by block number, who may not seal again yet.
+    pub recents: BTreeMap<u64, Address>,
+    /// Votes cast since the last checkpoint, in the order they were cast.
+    pub votes: Vec<Vote>,
+    /// Pending votes per candidate.
+    pub tally: BTreeMap<Address, Tally>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vote {
+    pub signer: Address,
+    pub block: u64,
+    pub candidate: Address,
+    pub authorize: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tally {
+    pub authorize: bool,
+    pub votes: usize,
+}
+
+impl Clique {
+    /// Clique for the chain `spec`, whose validators are the signers of the genesis block.
+    pub fn new(spec: &ChainSpec, epoch: u64) -> Self {
+        Clique {
+            epoch,
+            period: spec.block_time,
+            scheme: spec.hash_scheme,
+            proposals: Mutex::new(BTreeMap::new()),
+            snapshots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn snapshots(&self) -> MutexGuard<'_, HashMap<B256, Snapshot>> {
+        self.snapshots.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn proposals(&self) -> MutexGuard<'_, BTreeMap<Address, bool>> {
+        self.proposals.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The signers after `parent`, which must be part of the chain in `storage`. Rebuilt by
+    /// applying the headers since the latest cached snapshot, or since genesis.
+    pub fn snapshot(&self, storage: &dyn Storage, parent: &Block) -> Result<Snapshot> {
+        let parent_hash = parent.get_block_hash(self.scheme)?;
+        let mut headers = vec![];
+        let mut block = parent.clone();
+        let mut snapshot = loop {
+            let hash = block.get_block_hash(self.scheme)?;
+            if let Some(snapshot) = self.snapshots().get(&hash) {
+                break snapshot.clone();
+            }
+            if block.header.number == 0 {
+                break Snapshot::genesis(&block.header)?;
+            }
+
+            let number = block.header.number - 1;
+            headers.push(block.header);
+            block = storage
+                .get_block_by_number(number)?
+                .ok_or_else(|| eyre!("block {} is missing from storage", number))?;
+        };
+
+        for header in headers.iter().rev() {
+            snapshot.apply(header, self.epoch)?;
+        }
+
+        let mut snapshots = self.snapshots();
+        if snapshots.len() >= SNAPSHOT_CACHE_SIZE {
+            snapshots.clear();
+        }
+        snapshots.insert(parent_hash, snapshot.clone());
+
+        Ok(snapshot)
+    }
+}
+
+impl ConsensusEngine for Clique {
+    fn can_propose(
+        &self,
+        storage: &dyn Storage,
+        parent: &Block,
+        signer: &Address,
+        waited: u64,
+    ) -> Result<bool> {
+        let snapshot = self.snapshot(storage, parent)?;
+        let number = parent.header.number + 1;
+        let Some(offset) = snapshot.turn_offset(number, signer) else {
+            return Ok(false);
+        };
+        if snapshot.is_recent(number, signer) {
+            return Ok(false);
+        }
+
+        // The others only step in once the in-turn signer missed its slot, the further they are
+        // from their turn the later, so that they do not all step in at once.
+        Ok(offset == 0 || waited >= 2 * self.period * (offset + 1))
+    }
+
+    fn prepare(&self, storage: &dyn Storage, parent: &Block, header: &mut Header) -> Result<()> {
+        let snapshot = self.snapshot(storage, parent)?;
+        if !snapshot.signers.contains(&header.miner) {
+            bail!("{} is not an authorized signer", header.miner);
+        }
+
+        let earliest = parent.header.timestamp.checked_add(self.period).ok_or_else(|| {
+            eyre!("block {} has no timestamp after that of its parent", header.number)
+        })?;
+        header.timestamp = header.timestamp.max(earliest);
+        header.difficulty = snapshot.difficulty(header.number, &header.miner);
+
+        if header.number.is_multiple_of(self.epoch) {
+            header.signers = snapshot.signers.iter().copied().collect();
+        } else if let Some((candidate, authorize)) = self
+            .proposals()
+            .iter()
+            .find(|(candidate, authorize)| snapshot.signers.contains(candidate) != **authorize)
+        {
+            header.candidate = *candidate;
+            header.authorize = *authorize;
+        }
+
+        Ok(())
+    }
+
+    fn validators(&self, storage: &dyn Storage, parent: &Block) -> Result<Vec<Address>> {
+        Ok(self.snapshot(storage, parent)?.signers.into_iter().collect())
+    }
+
+    fn seal(&self, header: &mut Header, miner_wallet: &LocalWallet) -> Result<()> {
+        header.seal(self.scheme, miner_wallet)
+    }
+
+    fn verify_header(
+        &self,
+        storage: &dyn Storage,
+        parent: &Block,
+        header: &Header,
+    ) -> Result<(), BlockValidationError> {
+        let earliest = parent.header.timestamp.checked_add(self.period).ok_or(
+            BlockValidationError::InvalidTimestamp {
+                parent: parent.header.timestamp,
+                got: header.timestamp,
+            },
+        )?;
+        if header.timestamp < earliest {
+            return Err(BlockValidationError::TooEarly { earliest, got: header.timestamp });
+        }
+        // A signer could otherwise claim its slot early, or make the blocks after it wait.
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let latest = now.saturating_add(self.period).saturating_add(MAX_FUTURE_DRIFT);
+        if header.timestamp > latest {
+            return Err(BlockValidationError::FutureTimestamp { latest, got: header.timestamp });
+        }
+
+        let snapshot = self
+            .snapshot(storage, parent)
+            .map_err(|err| BlockValidationError::Snapshot(err.to_string()))?;
+
+        if header.number.is_multiple_of(self.epoch) {
+            if header.candidate != Address::ZERO || header.authorize {
+                return Err(BlockValidationError::InvalidCheckpoint(
+                    "checkpoint block casts a vote".to_string(),
+                ));
+            }
+            if !header.signers.iter().eq(snapshot.signers.iter()) {
+                return Err(BlockValidationError::InvalidCheckpoint(format!(
+                    "checkpoint block lists signers {:?} instead of {:?}",
+                    header.signers, snapshot.signers
+                )));
+            }
+        } else if !header.signers.is_empty() {
+            return Err(BlockValidationError::InvalidCheckpoint(
+                "block outside a checkpoint lists signers".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn verify_seal(
+        &self,
+        storage: &dyn Storage,
+        parent: &Block,
+        header: &Header,
+    ) -> Result<(), BlockValidationError> {
+        header
+            .verify_seal(self.scheme)
+            .map_err(|err| BlockValidationError::InvalidSeal(err.to_string()))?;
+
+        let mut snapshot = self
+            .snapshot(storage, parent)
+            .map_err(|err| BlockValidationError::Snapshot(err.to_string()))?;
+        let expected = snapshot.difficulty(header.number, &header.miner);
+        if header.difficulty != expected {
+            return Err(BlockValidationError::InvalidDifficulty {
+                expected,
+                got: header.difficulty,
+            });
+        }
+
+        snapshot.apply(header, self.epoch)
+    }
+
+    fn propose_vote(&self, candidate: Address, authorize: bool) -> Result<()> {
+        if candidate == Address::ZERO {
+            bail!("cannot vote on the zero address");
+        }
+        self.proposals().insert(candidate, authorize);
+
+        Ok(())
+    }
+}
+
+impl Snapshot {
+    /// The snapshot after the genesis block, whose `signers` are the first signers of the chain.
+    pub fn genesis(header: &Header) -> Result<Self> {
+        if header.signers.is_empty() {
+            bail!("genesis block lists no signers");
+        }
+
+        Ok(Snapshot {
+            number: header.number,
+            signers: header.signers.iter().copied().collect(),
+            recents: BTreeMap::new(),
+            votes: vec![],
+            tally: BTreeMap::new(),
+        })
+    }
+
+    /// How many blocks a signer has to wait after sealing one before it may seal again.
+    pub fn signer_limit(&self) -> u64 {
+        self.signers.len() as u64 / 2 + 1
+    }
+
+    /// Whether `signer` sealed one of the blocks before block `number` that are too recent for it
+    /// to seal `number`.
+    pub fn is_recent(&self, number: u64, signer: &Address) -> bool {
+        let limit = self.signer_limit();
+        self.recents.iter().any(|(block, recent)| recent == signer && block + limit > number)
+    }
+
+    /// How many turns `signer` is away from sealing block `number` in turn, or `None` if it is
+    /// not a signer.
+    pub fn turn_offset(&self, number: u64, signer: &Address) -> Option<u64> {
+        let position = self.signers.iter().position(|address| address == signer)? as u64;
+        let len = self.signers.len() as u64;
+
+        Some((position + len - number % len) % len)
+    }
+
+    /// The difficulty `signer` has to seal block `number` with.
+    pub fn difficulty(&self, number: u64, signer: &Address) -> u64 {
+        match self.turn_offset(number, signer) {
+            Some(0) => DIFF_IN_TURN,
+            _ => DIFF_NO_TURN,
+        }
+    }
+
+    /// Moves the snapshot past `header`, the block after the one it was taken after, counting
+    /// the vote it casts.
+    pub fn apply(&mut self, header: &Header, epoch: u64) -> Result<(), BlockValidationError> {
+        let number = header.number;
+        if number.is_multiple_of(epoch) {
+            self.votes.clear();
+            self.tally.clear();
+        }
+
+        let limit = self.signer_limit();
+        self.recents.retain(|block, _| block + limit > number);
+
+        let signer = header.miner;
+        if !self.signers.contains(&signer) {
+            return Err(BlockValidationError::UnauthorizedSigner(signer));
+        }
+        if self.recents.values().any(|recent| *recent == signer) {
+            return Err(BlockValidationError::RecentlySigned(signer));
+        }
+        self.recents.insert(number, signer);
+        self.number = number;
+
+        let candidate = header.candidate;
+        if candidate == Address::ZERO {
+            return Ok(());
+        }
+
+        // A signer's newer vote on a candidate replaces its older one, and votes that would not
+        // change anything are not counted.
+        self.uncast(&signer, &candidate);
+        if self.signers.contains(&candidate) != header.authorize {
+            self.votes.push(Vote { signer, block: number, candidate, authorize: header.authorize });
+            self.tally
+                .entry(candidate)
+                .or_insert(Tally { authorize: header.authorize, votes: 0 })
+                .votes += 1;
+        }
+
+        let Some(tally) = self.tally.get(&candidate).copied() else {
+            return Ok(());
+        };
+        if tally.votes <= self.signers.len() / 2 {
+            return Ok(());
+        }
+
+        if tally.authorize {
+            self.signers.insert(candidate);
+        } else {
+            self.signers.remove(&candidate);
+            // The votes of a removed signer no longer count.
+            let cast: Vec<Address> = self
+                .votes
+                .iter()
+                .filter(|vote| vote.signer == candidate)
+                .map(|vote| vote.candidate)
+                .collect();
+            for voted in cast {
+                self.uncast(&candidate, &voted);
+            }
+        }
+        self.votes.retain(|vote| vote.candidate != candidate);
+        self.tally.remove(&candidate);
+
+        Ok(())
+    }
+
+    /// Drops the vote of `signer` on `candidate`, if it cast one.
+    fn uncast(&mut self, signer: &Address, candidate: &Address) {
+        let Some(index) = self
+            .votes
+            .iter()
+            .position(|vote| vote.signer == *signer && vote.candidate == *candidate)
+        else {
+            return;
+        };
+        self.votes.remove(index);
+
+        if let Some(tally) = self.tally.get_mut(candidate) {
+            tally.votes -= 1;
+            if tally.votes == 0 {
+                self.tally.remove(candidate);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    const EPOCH: u64 = 30_000;
+
+    fn signer(n: u8) -> Address {
+        Address::new([n; 20])
+    }
+
+    fn header(number: u64, miner: Address, vote: Option<(Address, bool)>) -> Header {
+        let mut header =
+            Header::new(B256::ZERO, miner, B256::ZERO, B256::ZERO, number, number, vec![]);
+        if let Some((candidate, authorize)) = vote {
+            header.candidate = candidate;
+            header.authorize = authorize;
+        }
+        header
+    }
+
+    fn snapshot(signers: &[u8]) -> Snapshot {
+        let mut genesis = header(0, Address::ZERO, None);
+        genesis.signers = signers.iter().copied().map(signer).collect();
+        Snapshot::genesis(&genesis).unwrap()
+    }
+
+    fn apply(snapshot: &mut Snapshot, headers: &[Header]) {
+        for header in headers {
+            snapshot.apply(header, EPOCH).unwrap();
+        }
+    }
+
+    #[test]
+    fn takes_turns_in_address_order() {
+        let snapshot = snapshot(&[1, 2, 3]);
+
+        assert_eq!(snapshot.difficulty(1, &signer(2)), DIFF_IN_TURN);
+        assert_eq!(snapshot.difficulty(1, &signer(1)), DIFF_NO_TURN);
+        assert_eq!(snapshot.difficulty(2, &signer(3)), DIFF_IN_TURN);
+        assert_eq!(snapshot.turn_offset(1, &signer(9)), None);
+    }
+
+    #[test]
+    fn adds_a_candidate_with_votes_of_more_than_half() {
+        let mut snapshot = snapshot(&[1, 2, 3]);
+        let candidate = Some((signer(4), true));
+
+        apply(&mut snapshot, &[header(1, signer(1), candidate)]);
+        assert!(!snapshot.signers.contains(&signer(4)));
+        assert_eq!(snapshot.tally[&signer(4)], Tally { authorize: true, votes: 1 });
+
+        apply(&mut snapshot, &[header(2, signer(2), candidate)]);
+        assert!(snapshot.signers.contains(&signer(4)));
+        assert!(snapshot.tally.is_empty());
+        assert!(snapshot.votes.is_empty());
+    }
+
+    #[test]
+    fn a_newer_vote_replaces_an_older_one() {
+        let mut snapshot = snapshot(&[1, 2, 3]);
+
+        apply(
+            &mut snapshot,
+            &[
+                header(1, signer(1), Some((signer(4), true))),
+                header(2, signer(2), None),
+                // Removing someone who is not a signer changes nothing, so it is not counted.
+                header(3, signer(1), Some((signer(4), false))),
+            ],
+        );
+        assert!(snapshot.tally.is_empty());
+        assert!(snapshot.votes.is_empty());
+    }
+
+    #[test]
+    fn removing_a_signer_drops_its_votes() {
+        let mut snapshot = snapshot(&[1, 2, 3]);
+
+        apply(
+            &mut snapshot,
+            &[
+                header(1, signer(3), Some((signer(5), true))),
+                header(2, signer(1), Some((signer(3), false))),
+                header(3, signer(2), Some((signer(3), false))),
+            ],
+        );
+        assert_eq!(snapshot.signers, [signer(1), signer(2)].into_iter().collect());
+        assert!(snapshot.tally.is_empty());
+        assert!(snapshot.votes.is_empty());
+    }
+
+    #[test]
+    fn checkpoints_drop_pending_votes() {
+        let mut snapshot = snapshot(&[1, 2, 3]);
+        let epoch = 3;
+
+        snapshot.apply(&header(1, signer(1), Some((signer(4), true))), epoch).unwrap();
+        snapshot.apply(&header(2, signer(2), None), epoch).unwrap();
+        assert_eq!(snapshot.votes.len(), 1);
+
+        snapshot.apply(&header(3, signer(3), None), epoch).unwrap();
+        assert!(snapshot.votes.is_empty());
+        assert!(snapshot.tally.is_empty());
+    }
+
+    #[test]
+    fn refuses_unauthorized_and_recent_signers() {
+        let mut snapshot = snapshot(&[1, 2, 3]);
+
+        assert_eq!(
+            snapshot.clone().apply(&header(1, signer(4), None), EPOCH),
+            Err(BlockValidationError::UnauthorizedSigner(signer(4)))
+        );
+
+        apply(&mut snapshot, &[header(1, signer(1), None)]);
+        assert!(snapshot.is_recent(2, &signer(1)));
+        assert_eq!(
+            snapshot.clone().apply(&header(2, signer(1), None), EPOCH),
+            Err(BlockValidationError::RecentlySigned(signer(1)))
+        );
+
+        // With three signers a signer has to sit out one block.
+        apply(&mut snapshot, &[header(2, signer(2), None), header(3, signer(1), None)]);
+    }
+
+    #[test]
+    fn refuses_timestamps_too_far_ahead_or_past_the_last_second() {
+        let clique = Clique::new(&ChainSpec::default(), EPOCH);
+        let storage = MemoryStorage::new();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let parent = |timestamp| {
+            let mut parent = header(0, Address::ZERO, None);
+            parent.timestamp = timestamp;
+            Block { header: parent, txs: vec![], evidence: vec![] }
+        };
+        let child = |timestamp| Header { timestamp, ..header(1, signer(1), None) };
+
+        let latest = now + clique.period + MAX_FUTURE_DRIFT;
+        assert!(matches!(
+            clique.verify_header(&storage, &parent(now), &child(latest + 60)),
+            Err(BlockValidationError::FutureTimestamp { got, .. }) if got == latest + 60
+        ));
+        assert_eq!(
+            clique.verify_header(&storage, &parent(u64::MAX), &child(u64::MAX)),
+            Err(BlockValidationError::InvalidTimestamp { parent: u64::MAX, got: u64::MAX })
+        );
+    }
+}
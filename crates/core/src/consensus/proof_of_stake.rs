@@ -0,0 +1,233 @@
+use super::ConsensusEngine;
+use crate::{
+    block::{Block, Header},
+    blockchain::BlockValidationError,
+    chainspec::{ChainSpec, HashScheme},
+    state::State,
+    storage::Storage,
+};
+use ethers::{signers::LocalWallet, types::U256};
+use eyre::{bail, eyre, OptionExt, Result};
+use helper::{keccak256, Address, B256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Block times a proposer has to propose before its slot passes to another validator. A block
+/// takes a tick to propose and another to collect its votes, so this leaves room for both.
+const SLOT_BLOCK_TIMES: u64 = 4;
+
+/// Proof of stake with stake-weighted proposers.
+///
+/// Accounts stake and unstake with transactions to [`crate::transaction::STAKE_ADDRESS`] and
+/// [`crate::transaction::UNSTAKE_ADDRESS`], and every `epoch` blocks the accounts with at least
+/// `min_stake` become the validators of the next epoch. The block that starts an epoch lists them
+/// in its `signers` and their stakes in its `stakes`, taken from the state before it, so stakes
+/// only count once an epoch starts. If nobody has enough stake, the validators stay on.
+///
+/// The proposer of a block is drawn from the validators in proportion to their stake, by the
+/// `seed` of the parent block. Every block mixes its miner into the seed, so the draw is
+/// deterministic and cannot be steered by what goes into a block. Every [`SLOT_BLOCK_TIMES`] block
+/// times after the parent, the slot passes to the validator of another draw, so an offline
+/// validator only stalls the chain for a while. Which slot a block is in follows from its
+/// timestamp.
+pub struct ProofOfStake {
+    epoch: u64,
+    min_stake: u64,
+    period: u64,
+    scheme: HashScheme,
+}
+
+impl ProofOfStake {
+    pub fn new(spec: &ChainSpec, epoch: u64, min_stake: u64) -> Self {
+        ProofOfStake { epoch, min_stake, period: spec.block_time, scheme: spec.hash_scheme }
+    }
+
+    /// The validators of the epoch of the child of `parent` and their stakes, as listed by the
+    /// block that started the epoch.
+    pub fn epoch_validators(
+        &self,
+        storage: &dyn Storage,
+        parent: &Block,
+    ) -> Result<Vec<(Address, u64)>> {
+        let start = parent.header.number - parent.header.number % self.epoch;
+        let header = if start == parent.header.number {
+            parent.header.clone()
+        } else {
+            storage
+                .get_block_by_number(start)?
+                .ok_or_else(|| eyre!("block {} is missing from storage", start))?
+                .header
+        };
+
+        Ok(header.signers.into_iter().zip(header.stakes).collect())
+    }
+
+    /// The validator that may propose the child of `parent` in `slot`.
+    pub fn proposer(&self, storage: &dyn Storage, parent: &Block, slot: u64) -> Result<Address> {
+        let validators = self.epoch_validators(storage, parent)?;
+        let total = validators.iter().fold(U256::zero(), |total, (_, stake)| total + *stake);
+        if total.is_zero() {
+            bail!("the epoch of block {} has no validators", parent.header.number + 1);
+        }
+
+        let mut input = parent.header.seed.as_bytes().to_vec();
+        input.extend_from_slice(&slot.to_be_bytes());
+        let mut draw = U256::from_big_endian(keccak256(input).as_bytes()) % total;
+        for (validator, stake) in validators {
+            if draw < U256::from(stake) {
+                return Ok(validator);
+            }
+            draw -= U256::from(stake);
+        }
+
+        unreachable!("the draw is below the total stake")
+    }
+
+    /// Slot of the child of `parent` with `timestamp`.
+    pub fn slot(&self, parent: &Header, timestamp: u64) -> u64 {
+        timestamp.saturating_sub(parent.timestamp + 1) / (SLOT_BLOCK_TIMES * self.period)
+    }
+
+    /// The validators the child of `parent` lists if it starts an epoch, from the state after
+    /// `parent`, which has to be the head of the chain.
+    fn next_validators(
+        &self,
+        storage: &dyn Storage,
+        parent: &Block,
+    ) -> Result<Option<Vec<(Address, u64)>>> {
+        if !(parent.header.number + 1).is_multiple_of(self.epoch) {
+            return Ok(None);
+        }
+
+        let head = storage.get_last_block()?.ok_or_eyre("the chain has no blocks")?;
+        if head.get_block_hash(self.scheme)? != parent.get_block_hash(self.scheme)? {
+            bail!("the stakes after block {} are not at hand", parent.header.number);
+        }
+        let stakers = State::load(storage)?.stakers(self.min_stake);
+        if stakers.is_empty() {
+            return Ok(Some(self.epoch_validators(storage, parent)?));
+        }
+
+        Ok(Some(stakers))
+    }
+}
+
+impl ConsensusEngine for ProofOfStake {
+    fn can_propose(
+        &self,
+        storage: &dyn Storage,
+        parent: &Block,
+        signer: &Address,
+        _waited: u64,
+    ) -> Result<bool> {
+        let timestamp = unix_time().max(parent.header.timestamp + self.period);
+        let slot = self.slot(&parent.header, timestamp);
+
+        Ok(self.proposer(storage, parent, slot)? == *signer)
+    }
+
+    fn prepare(&self, storage: &dyn Storage, parent: &Block, header: &mut Header) -> Result<()> {
+        header.timestamp = header.timestamp.max(parent.header.timestamp + self.period);
+        let slot = self.slot(&parent.header, header.timestamp);
+        if self.proposer(storage, parent, slot)? != header.miner {
+            bail!(
+                "{} is not the proposer of slot {} of block {}",
+                header.miner,
+                slot,
+                header.number
+            );
+        }
+
+        header.seed = next_seed(&parent.header.seed, &header.miner);
+        if let Some(validators) = self.next_validators(storage, parent)? {
+            (header.signers, header.stakes) = validators.into_iter().unzip();
+        }
+
+        Ok(())
+    }
+
+    fn validators(&self, storage: &dyn Storage, parent: &Block) -> Result<Vec<Address>> {
+        Ok(self
+            .epoch_validators(storage, parent)?
+            .into_iter()
+            .map(|(address, _)| address)
+            .collect())
+    }
+
+    /// Votes weigh by the stake of the validator, so that validators with little at stake cannot
+    /// hold up the chain.
+    fn voting_powers(&self, storage: &dyn Storage, parent: &Block) -> Result<Vec<(Address, u64)>> {
+        self.epoch_validators(storage, parent)
+    }
+
+    fn seal(&self, header: &mut Header, miner_wallet: &LocalWallet) -> Result<()> {
+        header.seal(self.scheme, miner_wallet)
+    }
+
+    fn verify_header(
+        &self,
+        storage: &dyn Storage,
+        parent: &Block,
+        header: &Header,
+    ) -> Result<(), BlockValidationError> {
+        let earliest = parent.header.timestamp + self.period;
+        if header.timestamp < earliest {
+            return Err(BlockValidationError::TooEarly { earliest, got: header.timestamp });
+        }
+        // A proposer could otherwise claim a later slot right away.
+        let latest = unix_time() + self.period;
+        if header.timestamp > latest {
+            return Err(BlockValidationError::FutureTimestamp { latest, got: header.timestamp });
+        }
+
+        let slot = self.slot(&parent.header, header.timestamp);
+        let expected = self
+            .proposer(storage, parent, slot)
+            .map_err(|err| BlockValidationError::Snapshot(err.to_string()))?;
+        if header.miner != expected {
+            return Err(BlockValidationError::WrongProposer { expected, got: header.miner });
+        }
+
+        let seed = next_seed(&parent.header.seed, &header.miner);
+        if header.seed != seed {
+            return Err(BlockValidationError::InvalidSeed { expected: seed, got: header.seed });
+        }
+
+        let validators = self
+            .next_validators(storage, parent)
+            .map_err(|err| BlockValidationError::Snapshot(err.to_string()))?
+            .unwrap_or_default();
+        let listed: Vec<_> =
+            header.signers.iter().copied().zip(header.stakes.iter().copied()).collect();
+        if header.signers.len() != header.stakes.len() || listed != validators {
+            return Err(BlockValidationError::InvalidCheckpoint(format!(
+                "block lists validators {:?} with stakes {:?} instead of {:?}",
+                header.signers, header.stakes, validators
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn verify_seal(
+        &self,
+        _storage: &dyn Storage,
+        _parent: &Block,
+        header: &Header,
+    ) -> Result<(), BlockValidationError> {
+        header
+            .verify_seal(self.scheme)
+            .map_err(|err| BlockValidationError::InvalidSeal(err.to_string()))
+    }
+}
+
+/// Seed of a block mined by `miner` whose parent has `seed`.
+fn next_seed(seed: &B256, miner: &Address) -> B256 {
+    let mut input = seed.as_bytes().to_vec();
+    input.extend_from_slice(miner.as_bytes());
+
+    keccak256(input)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
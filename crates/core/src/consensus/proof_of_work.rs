@@ -0,0 +1,192 @@
+use super::ConsensusEngine;
+use crate::{
+    block::{Block, Header},
+    blockchain::BlockValidationError,
+    chainspec::{ChainSpec, HashScheme},
+    miner::{self, Miner},
+    storage::Storage,
+    validator::ValidatorSet,
+};
+use ethers::signers::LocalWallet;
+use eyre::{bail, Result};
+use helper::{Address, B256};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Lowest difficulty a block may have, however slowly the blocks before it came.
+pub const MIN_DIFFICULTY: u64 = 1024;
+
+/// The difficulty moves by this fraction of the parent difficulty per step.
+const DIFFICULTY_BOUND_DIVISOR: u64 = 16;
+
+/// Most steps the difficulty is lowered by at once, which halves it.
+const MAX_DOWNWARD_STEPS: u64 = 8;
+
+/// Seconds a block may be ahead of the local clock.
+const MAX_FUTURE_DRIFT: u64 = 15;
+
+/// Proof of work with Keccak, see [`crate::miner`].
+///
+/// Anyone may mine a block by finding a nonce that meets its difficulty, and the block is sealed
+/// by the miner's key so that it is clear who mined it. The difficulty is retargeted from block to
+/// block like in Ethereum's Homestead: a block at most one block time after its parent raises it
+/// by 1/16, one that took up to twice as long keeps it, and every block time beyond that lowers
+/// it by another 1/16, never below [`MIN_DIFFICULTY`]. Nobody votes on blocks, so they are never
+/// final: the chain follows whichever branch holds the most work, see
+/// [`crate::blockchain::Blockchain::import_branch`].
+pub struct ProofOfWork {
+    block_time: u64,
+    scheme: HashScheme,
+    validators: ValidatorSet,
+    miner: Miner,
+    jobs: Mutex<Jobs>,
+}
+
+/// The mining jobs that are running and the head they have to build on.
+#[derive(Default)]
+struct Jobs {
+    /// The head of the chain as of the last [`ConsensusEngine::abort_seal`], unknown before it.
+    head: Option<B256>,
+    /// The parent hash of the header each job mines, with the flag that makes it give up.
+    running: Vec<(B256, Arc<AtomicBool>)>,
+}
+
+impl ProofOfWork {
+    pub fn new(spec: &ChainSpec) -> Self {
+        ProofOfWork {
+            block_time: spec.block_time,
+            scheme: spec.hash_scheme,
+            validators: spec.validators.clone(),
+            miner: Miner::with_available_parallelism(),
+            jobs: Mutex::default(),
+        }
+    }
+
+    fn jobs(&self) -> MutexGuard<'_, Jobs> {
+        self.jobs.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Difficulty of the child of `parent` with `timestamp`.
+    pub fn difficulty(&self, parent: &Header, timestamp: u64) -> u64 {
+        let elapsed = timestamp.saturating_sub(parent.timestamp).max(1);
+        let step = parent.difficulty / DIFFICULTY_BOUND_DIVISOR;
+        let difficulty = match (elapsed - 1) / self.block_time.max(1) {
+            0 => parent.difficulty.saturating_add(step),
+            1 => parent.difficulty,
+            slots => parent.difficulty - step * (slots - 1).min(MAX_DOWNWARD_STEPS),
+        };
+
+        difficulty.max(MIN_DIFFICULTY)
+    }
+}
+
+impl ConsensusEngine for ProofOfWork {
+    fn can_propose(
+        &self,
+        _storage: &dyn Storage,
+        _parent: &Block,
+        _signer: &Address,
+        _waited: u64,
+    ) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn prepare(&self, _storage: &dyn Storage, parent: &Block, header: &mut Header) -> Result<()> {
+        header.timestamp = header.timestamp.max(parent.header.timestamp + 1);
+        header.difficulty = self.difficulty(&parent.header, header.timestamp);
+
+        Ok(())
+    }
+
+    /// Everyone may mine, so there are no validators, but the spec's are kept for the binaries
+    /// that show them.
+    fn validators(&self, _storage: &dyn Storage, _parent: &Block) -> Result<Vec<Address>> {
+        Ok(self.validators.iter().copied().collect())
+    }
+
+    /// Mines `header` before signing it, which blocks until a nonce is found or
+    /// [`abort_seal`](ConsensusEngine::abort_seal) moves the head away from its parent. Every
+    /// call has a flag of its own, so aborting one job never stops a later one.
+    fn seal(&self, header: &mut Header, miner_wallet: &LocalWallet) -> Result<()> {
+        let abort = Arc::new(AtomicBool::new(false));
+        {
+            let mut jobs = self.jobs();
+            if jobs.head.is_some_and(|head| head != header.parent_hash) {
+                bail!("stopped mining block {}, its parent is no longer the head", header.number);
+            }
+            jobs.running.push((header.parent_hash, abort.clone()));
+        }
+        let found = self.miner.mine(header, self.scheme, &abort);
+        self.jobs().running.retain(|(_, job)| !Arc::ptr_eq(job, &abort));
+        if !found {
+            bail!("stopped mining block {}", header.number);
+        }
+
+        header.seal(self.scheme, miner_wallet)
+    }
+
+    fn abort_seal(&self, head: &B256) {
+        let mut jobs = self.jobs();
+        jobs.head = Some(*head);
+        for (parent_hash, abort) in &jobs.running {
+            if parent_hash != head {
+                abort.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn allows_reorgs(&self) -> bool {
+        true
+    }
+
+    fn verify_header(
+        &self,
+        _storage: &dyn Storage,
+        parent: &Block,
+        header: &Header,
+    ) -> Result<(), BlockValidationError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let latest = now + MAX_FUTURE_DRIFT;
+        if header.timestamp > latest {
+            return Err(BlockValidationError::FutureTimestamp { latest, got: header.timestamp });
+        }
+
+        let expected = self.difficulty(&parent.header, header.timestamp);
+        if header.difficulty != expected {
+            return Err(BlockValidationError::InvalidDifficulty {
+                expected,
+                got: header.difficulty,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn verify_seal(
+        &self,
+        _storage: &dyn Storage,
+        _parent: &Block,
+        header: &Header,
+    ) -> Result<(), BlockValidationError> {
+        header
+            .verify_seal(self.scheme)
+            .map_err(|err| BlockValidationError::InvalidSeal(err.to_string()))?;
+        if !miner::verify_work(header, self.scheme) {
+            return Err(BlockValidationError::InsufficientWork {
+                nonce: header.nonce,
+                difficulty: header.difficulty,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn has_quorum(&self, _yes_votes: u128, _validators: u128) -> bool {
+        true
+    }
+}
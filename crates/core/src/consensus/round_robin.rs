@@ -0,0 +1,73 @@
+use super::ConsensusEngine;
+use crate::{
+    block::{Block, Header},
+    blockchain::BlockValidationError,
+    chainspec::{ChainSpec, HashScheme},
+    storage::Storage,
+    validator::ValidatorSet,
+};
+use ethers::signers::LocalWallet;
+use eyre::Result;
+use helper::Address;
+
+/// Validators take turns in the order of the validator set, which never changes. Chains started
+/// before [`super::Clique`] run this engine.
+pub struct RoundRobin {
+    validators: ValidatorSet,
+    scheme: HashScheme,
+}
+
+impl RoundRobin {
+    pub fn new(spec: &ChainSpec) -> Self {
+        RoundRobin { validators: spec.validators.clone(), scheme: spec.hash_scheme }
+    }
+}
+
+impl ConsensusEngine for RoundRobin {
+    fn can_propose(
+        &self,
+        _storage: &dyn Storage,
+        parent: &Block,
+        signer: &Address,
+        _waited: u64,
+    ) -> Result<bool> {
+        Ok(self.validators.get_proposer(parent) == *signer)
+    }
+
+    fn prepare(&self, _storage: &dyn Storage, _parent: &Block, _header: &mut Header) -> Result<()> {
+        Ok(())
+    }
+
+    fn validators(&self, _storage: &dyn Storage, _parent: &Block) -> Result<Vec<Address>> {
+        Ok(self.validators.iter().copied().collect())
+    }
+
+    fn seal(&self, header: &mut Header, miner_wallet: &LocalWallet) -> Result<()> {
+        header.seal(self.scheme, miner_wallet)
+    }
+
+    fn verify_header(
+        &self,
+        _storage: &dyn Storage,
+        parent: &Block,
+        header: &Header,
+    ) -> Result<(), BlockValidationError> {
+        let expected = self.validators.get_proposer(parent);
+        if header.miner != expected {
+            return Err(BlockValidationError::WrongProposer { expected, got: header.miner });
+        }
+
+        Ok(())
+    }
+
+    fn verify_seal(
+        &self,
+        _storage: &dyn Storage,
+        _parent: &Block,
+        header: &Header,
+    ) -> Result<(), BlockValidationError> {
+        header
+            .verify_seal(self.scheme)
+            .map_err(|err| BlockValidationError::InvalidSeal(err.to_string()))
+    }
+}
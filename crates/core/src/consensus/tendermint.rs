@@ -0,0 +1,81 @@
+use super::ConsensusEngine;
+use crate::{
+    block::{Block, Header},
+    blockchain::BlockValidationError,
+    chainspec::{ChainSpec, HashScheme},
+    storage::Storage,
+    validator::ValidatorSet,
+};
+use ethers::signers::LocalWallet;
+use eyre::Result;
+use helper::Address;
+
+/// Byzantine fault tolerant consensus modeled on Tendermint.
+///
+/// Blocks are agreed on in rounds of proposals, prevotes and precommits, run by
+/// [`crate::bft::BftState`], and a block is only final with the precommits of more than two thirds
+/// of the validators. The engine itself only checks that a block was sealed by one of the
+/// validators, since which one proposes depends on the round the block was agreed on in, and
+/// leaves the rest to the [`crate::bft::CommitCertificate`] committed with the block.
+pub struct Tendermint {
+    validators: ValidatorSet,
+    scheme: HashScheme,
+}
+
+impl Tendermint {
+    pub fn new(spec: &ChainSpec) -> Self {
+        Tendermint { validators: spec.validators.clone(), scheme: spec.hash_scheme }
+    }
+}
+
+impl ConsensusEngine for Tendermint {
+    fn can_propose(
+        &self,
+        _storage: &dyn Storage,
+        _parent: &Block,
+        signer: &Address,
+        _waited: u64,
+    ) -> Result<bool> {
+        Ok(self.validators.contains(signer))
+    }
+
+    fn prepare(&self, _storage: &dyn Storage, _parent: &Block, _header: &mut Header) -> Result<()> {
+        Ok(())
+    }
+
+    fn validators(&self, _storage: &dyn Storage, _parent: &Block) -> Result<Vec<Address>> {
+        Ok(self.validators.iter().copied().collect())
+    }
+
+    fn seal(&self, header: &mut Header, miner_wallet: &LocalWallet) -> Result<()> {
+        header.seal(self.scheme, miner_wallet)
+    }
+
+    fn verify_header(
+        &self,
+        _storage: &dyn Storage,
+        _parent: &Block,
+        header: &Header,
+    ) -> Result<(), BlockValidationError> {
+        if !self.validators.contains(&header.miner) {
+            return Err(BlockValidationError::UnauthorizedSigner(header.miner));
+        }
+
+        Ok(())
+    }
+
+    fn verify_seal(
+        &self,
+        _storage: &dyn Storage,
+        _parent: &Block,
+        header: &Header,
+    ) -> Result<(), BlockValidationError> {
+        header
+            .verify_seal(self.scheme)
+            .map_err(|err| BlockValidationError::InvalidSeal(err.to_string()))
+    }
+
+    fn requires_certificate(&self) -> bool {
+        true
+    }
+}
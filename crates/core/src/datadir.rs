@@ -0,0 +1,116 @@
+use crate::storage::{JsonStorage, KvStorage, Storage};
+use eyre::{eyre, Result};
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    path::{Path, PathBuf},
+};
+
+const LOCK_FILE: &str = "node.lock";
+
+/// Metadata key that tracks an import of [`JsonStorage`] files, `started` until every record was
+/// copied and `done` afterwards.
+const JSON_IMPORT_KEY: &str = "json_import";
+
+/// Root directory of everything a node persists.
+///
+/// Each node gets its own data directory, so several nodes, or nodes of different networks, can
+/// run side by side on one host as long as they are pointed at different directories.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataDir {
+    path: PathBuf,
+}
+
+/// Exclusive hold on a [`DataDir`], released when dropped or when the process exits.
+#[derive(Debug)]
+pub struct DataDirLock {
+    _file: File,
+}
+
+impl DataDir {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        DataDir { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.path.join(name)
+    }
+
+    /// Takes the data directory for this process, failing if another node is already using it.
+    pub fn lock(&self) -> Result<DataDirLock> {
+        fs::create_dir_all(&self.path)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.join(LOCK_FILE))?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(DataDirLock { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(eyre!(
+                "data directory {} is already in use by another node",
+                self.path.display()
+            )),
+            Err(TryLockError::Error(err)) => Err(err.into()),
+        }
+    }
+
+    /// Opens the key/value logs of the directory. A directory that an older node kept in
+    /// [`JsonStorage`] files is imported into them the first time it is opened.
+    pub fn open_storage(&self) -> Result<KvStorage> {
+        let storage = KvStorage::open(&self.path)?;
+
+        if JsonStorage::exists(&self.path) {
+            let import = match storage.get_metadata(JSON_IMPORT_KEY)?.as_deref() {
+                Some("done") => false,
+                Some(_) => true,
+                // Never take over the JSON files of a directory the logs already hold a chain of.
+                None => storage.get_last_block()?.is_none(),
+            };
+            if import {
+                import_json(&JsonStorage::open(&self.path)?, &storage)?;
+            }
+        }
+
+        Ok(storage)
+    }
+}
+
+/// Copies everything from `json` into `storage`. Every write overwrites the same key, so an
+/// import that was cut short is simply run again.
+fn import_json(json: &JsonStorage, storage: &KvStorage) -> Result<()> {
+    println!("📦 Importing the JSON files of the data directory 📦");
+    json.recover()?;
+    storage.put_metadata(JSON_IMPORT_KEY, "started")?;
+
+    // The hash scheme of the chain lives in the metadata and is needed to index the blocks.
+    for (key, value) in json.metadata()? {
+        storage.put_metadata(&key, &value)?;
+    }
+    for account in json.get_accounts()? {
+        storage.put_account(&account)?;
+    }
+    for tx in json.get_mempool()? {
+        storage.add_to_mempool(&tx)?;
+    }
+    for block in json.get_blocks()? {
+        storage.append_block(&block)?;
+    }
+
+    storage.put_metadata(JSON_IMPORT_KEY, "done")
+}
+
+impl Default for DataDir {
+    fn default() -> Self {
+        DataDir::new(".")
+    }
+}
+
+impl From<PathBuf> for DataDir {
+    fn from(path: PathBuf) -> Self {
+        DataDir::new(path)
+    }
+}
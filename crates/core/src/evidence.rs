@@ -0,0 +1,369 @@
+use crate::{bft::RoundVote, block::Header, chainspec::HashScheme, p2p::VoteOnBlock};
+use alloy_rlp::{BufMut, Decodable, Encodable};
+use eyre::{bail, Result};
+use helper::{keccak256, Address, B256};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Blocks after the misbehavior that evidence of it may still be included in. Older evidence is
+/// refused, so that checking whether it was punished before only takes a look at recent blocks.
+pub const MAX_EVIDENCE_AGE: u64 = 256;
+
+/// Percentage of the stake and of the unbonding stake, or of the balance if it has neither, an
+/// offender loses.
+pub const SLASHING_PERCENTAGE: u64 = 50;
+
+/// Proof that a validator signed two conflicting messages, which no honest validator ever does.
+///
+/// Two headers conflict if the same miner sealed them for the same block number, whatever their
+/// timestamps, since a miner that shifts the timestamp of its second block signs two blocks at
+/// one height all the same. Two votes conflict if they are on the same height and either both
+/// approve different blocks or disagree on the same one. The round of a vote does not matter,
+/// as the proposer picks it. A rejection followed by a vote for another block is what an honest
+/// validator does after an invalid proposal, so it is no evidence. Two Tendermint votes conflict
+/// if they are of the same step, height and round but for different blocks, or for a block and
+/// for nil.
+///
+/// Evidence is encoded in RLP as a list of its kind, 1 for [`Evidence::DoubleSign`], 2 for
+/// [`Evidence::DoubleVote`] and 3 for [`Evidence::DoubleRoundVote`], and the two signed
+/// messages.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Evidence {
+    DoubleSign { first: Header, second: Header },
+    DoubleVote { first: VoteOnBlock, second: VoteOnBlock },
+    DoubleRoundVote { first: RoundVote, second: RoundVote },
+}
+
+impl Evidence {
+    /// The validator that signed both messages.
+    pub fn offender(&self) -> Address {
+        match self {
+            Evidence::DoubleSign { first, .. } => first.miner,
+            Evidence::DoubleVote { first, .. } => first.voter,
+            Evidence::DoubleRoundVote { first, .. } => first.voter,
+        }
+    }
+
+    /// The block number the validator misbehaved at.
+    pub fn height(&self) -> u64 {
+        match self {
+            Evidence::DoubleSign { first, .. } => first.number,
+            Evidence::DoubleVote { first, .. } => first.block_number,
+            Evidence::DoubleRoundVote { first, .. } => first.height,
+        }
+    }
+
+    /// Checks that both messages were signed by the offender and that they conflict.
+    pub fn verify(&self, scheme: HashScheme) -> Result<()> {
+        match self {
+            Evidence::DoubleSign { first, second } => {
+                if first.miner != second.miner || first.number != second.number {
+                    bail!("headers were not sealed by the same miner at the same height");
+                }
+                if first.get_seal_hash(scheme) == second.get_seal_hash(scheme) {
+                    bail!("headers of block {} do not differ", first.number);
+                }
+                first.verify_seal(scheme)?;
+                second.verify_seal(scheme)?;
+            }
+            Evidence::DoubleVote { first, second } => {
+                if first.voter != second.voter || first.block_number != second.block_number {
+                    bail!("votes were not cast by the same voter at the same height");
+                }
+                if !first.conflicts_with(second) {
+                    bail!("votes on block {} do not conflict", first.block_number);
+                }
+                first.verify()?;
+                second.verify()?;
+            }
+            Evidence::DoubleRoundVote { first, second } => {
+                if first.voter != second.voter
+                    || first.step != second.step
+                    || first.height != second.height
+                    || first.round != second.round
+                {
+                    bail!("votes were not cast by the same voter in the same step and round");
+                }
+                if first.block_hash == second.block_hash {
+                    bail!("votes on block {} do not differ", first.height);
+                }
+                first.verify()?;
+                second.verify()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Encodable for Evidence {
+    fn encode(&self, out: &mut dyn BufMut) {
+        let mut payload = Vec::new();
+        match self {
+            Evidence::DoubleSign { first, second } => {
+                1u8.encode(&mut payload);
+                first.encode(&mut payload);
+                second.encode(&mut payload);
+            }
+            Evidence::DoubleVote { first, second } => {
+                2u8.encode(&mut payload);
+                first.encode(&mut payload);
+                second.encode(&mut payload);
+            }
+            Evidence::DoubleRoundVote { first, second } => {
+                3u8.encode(&mut payload);
+                first.encode(&mut payload);
+                second.encode(&mut payload);
+            }
+        }
+
+        alloy_rlp::Header { list: true, payload_length: payload.len() }.encode(out);
+        out.put_slice(&payload);
+    }
+}
+
+impl Decodable for Evidence {
+    fn decode(buf: &mut &[u8]) -> alloy_rlp::Result<Self> {
+        let header = alloy_rlp::Header::decode(buf)?;
+        if !header.list {
+            return Err(alloy_rlp::Error::UnexpectedString);
+        }
+        if buf.len() < header.payload_length {
+            return Err(alloy_rlp::Error::InputTooShort);
+        }
+        let mut payload = &buf[..header.payload_length];
+        *buf = &buf[header.payload_length..];
+
+        let evidence = match u8::decode(&mut payload)? {
+            1 => Evidence::DoubleSign {
+                first: Header::decode(&mut payload)?,
+                second: Header::decode(&mut payload)?,
+            },
+            2 => Evidence::DoubleVote {
+                first: VoteOnBlock::decode(&mut payload)?,
+                second: VoteOnBlock::decode(&mut payload)?,
+            },
+            3 => Evidence::DoubleRoundVote {
+                first: RoundVote::decode(&mut payload)?,
+                second: RoundVote::decode(&mut payload)?,
+            },
+            _ => return Err(alloy_rlp::Error::Custom("unknown evidence kind")),
+        };
+        if !payload.is_empty() {
+            return Err(alloy_rlp::Error::Custom("evidence has trailing fields"));
+        }
+
+        Ok(evidence)
+    }
+}
+
+/// Root of the evidence of a block: zero if it has none, so that blocks without evidence keep
+/// their hashes, and the Keccak hash of the RLP list of it otherwise.
+pub fn get_evidence_root(evidence: &[Evidence]) -> B256 {
+    if evidence.is_empty() {
+        return B256::ZERO;
+    }
+
+    let mut out = Vec::new();
+    alloy_rlp::encode_list::<_, Evidence>(evidence, &mut out);
+
+    keccak256(out)
+}
+
+/// Evidence a node found or received that still has to go into a block, and the headers it
+/// has seen, to catch a miner that seals a second one at the same height.
+#[derive(Debug, Default)]
+pub struct EvidencePool {
+    pending: Vec<Evidence>,
+    /// The first header seen of each miner, by block number.
+    headers: BTreeMap<(u64, Address), Header>,
+}
+
+impl EvidencePool {
+    pub fn new() -> Self {
+        EvidencePool::default()
+    }
+
+    /// Records `header` and returns evidence if its miner already sealed another header for the
+    /// same height. The evidence is also added to the pool.
+    pub fn observe_header(&mut self, header: &Header, scheme: HashScheme) -> Option<Evidence> {
+        if header.verify_seal(scheme).is_err() {
+            return None;
+        }
+        let key = (header.number, header.miner);
+        let Some(seen) = self.headers.get(&key) else {
+            self.headers.insert(key, header.clone());
+            return None;
+        };
+
+        let evidence = Evidence::DoubleSign { first: seen.clone(), second: header.clone() };
+        if seen.get_seal_hash(scheme) == header.get_seal_hash(scheme) || !self.add(evidence.clone())
+        {
+            return None;
+        }
+
+        Some(evidence)
+    }
+
+    /// Adds `evidence`, which must have been verified, unless there already is evidence against
+    /// its offender at its height. Returns whether it was new.
+    pub fn add(&mut self, evidence: Evidence) -> bool {
+        let known = self.pending.iter().any(|pending| {
+            pending.offender() == evidence.offender() && pending.height() == evidence.height()
+        });
+        if known {
+            return false;
+        }
+
+        self.pending.push(evidence);
+        true
+    }
+
+    /// The evidence that has not been included in a block yet, at most one per offender and
+    /// height.
+    pub fn pending(&self) -> &[Evidence] {
+        &self.pending
+    }
+
+    /// Keeps only the evidence for which `keep` holds, such as the evidence that was not
+    /// included yet, and forgets the headers that are too old to be punished for by the child of
+    /// block `number`.
+    pub fn retain(&mut self, number: u64, keep: impl FnMut(&Evidence) -> bool) {
+        self.pending.retain(keep);
+        self.headers.retain(|(height, _), _| height + MAX_EVIDENCE_AGE > number);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bft::VoteStep;
+    use ethers::signers::{LocalWallet, Signer};
+
+    const KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+    const OTHER_KEY: &str = "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d";
+
+    fn wallet(key: &str) -> LocalWallet {
+        key.parse().unwrap()
+    }
+
+    fn vote(round: u64, block_hash: u8, approve: bool, key: &str) -> VoteOnBlock {
+        VoteOnBlock::new(7, round, B256::from([block_hash; 32]), approve, &wallet(key)).unwrap()
+    }
+
+    fn header(timestamp: u64, state_root: u8, key: &str) -> Header {
+        let miner = Address::from(wallet(key).address());
+        let mut header = Header::new(
+            B256::ZERO,
+            miner,
+            B256::from([state_root; 32]),
+            B256::ZERO,
+            7,
+            timestamp,
+            vec![],
+        );
+        header.seal(HashScheme::Rlp, &wallet(key)).unwrap();
+        header
+    }
+
+    #[test]
+    fn conflicting_votes_at_one_height_are_a_double_vote() {
+        // The round is the timestamp the proposer picked, so it does not tell the votes apart.
+        let for_another_block =
+            Evidence::DoubleVote { first: vote(0, 1, true, KEY), second: vote(1, 2, true, KEY) };
+        for_another_block.verify(HashScheme::Rlp).unwrap();
+        assert_eq!(for_another_block.offender(), Address::from(wallet(KEY).address()));
+        assert_eq!(for_another_block.height(), 7);
+
+        let with_another_verdict =
+            Evidence::DoubleVote { first: vote(0, 1, true, KEY), second: vote(1, 1, false, KEY) };
+        with_another_verdict.verify(HashScheme::Rlp).unwrap();
+    }
+
+    #[test]
+    fn votes_that_do_not_conflict_or_of_different_voters_are_no_double_vote() {
+        // Voting for another block after rejecting an invalid one is what honest validators do.
+        let after_a_rejection =
+            Evidence::DoubleVote { first: vote(0, 1, false, KEY), second: vote(1, 2, true, KEY) };
+        assert!(after_a_rejection.verify(HashScheme::Rlp).is_err());
+
+        let other_voter = Evidence::DoubleVote {
+            first: vote(0, 1, true, KEY),
+            second: vote(0, 2, true, OTHER_KEY),
+        };
+        assert!(other_voter.verify(HashScheme::Rlp).is_err());
+
+        let same_vote =
+            Evidence::DoubleVote { first: vote(0, 1, true, KEY), second: vote(0, 1, true, KEY) };
+        assert!(same_vote.verify(HashScheme::Rlp).is_err());
+
+        let mut forged = vote(0, 2, true, KEY);
+        forged.voter = Address::from(wallet(OTHER_KEY).address());
+        let forged = Evidence::DoubleVote { first: vote(0, 1, true, OTHER_KEY), second: forged };
+        assert!(forged.verify(HashScheme::Rlp).is_err());
+    }
+
+    #[test]
+    fn two_round_votes_for_different_blocks_are_a_double_round_vote() {
+        let precommit = |round: u64, block_hash: Option<u8>| {
+            let block_hash = block_hash.map(|byte| B256::from([byte; 32]));
+            RoundVote::new(VoteStep::Precommit, 7, round, block_hash, &wallet(KEY)).unwrap()
+        };
+
+        let evidence =
+            Evidence::DoubleRoundVote { first: precommit(0, Some(1)), second: precommit(0, None) };
+        evidence.verify(HashScheme::Rlp).unwrap();
+        assert_eq!(evidence.height(), 7);
+        let encoded = alloy_rlp::encode(&evidence);
+        let decoded = Evidence::decode(&mut encoded.as_slice()).unwrap();
+        assert_eq!(alloy_rlp::encode(&decoded), encoded);
+
+        // Validators vote again in every round.
+        let later_round = Evidence::DoubleRoundVote {
+            first: precommit(0, Some(1)),
+            second: precommit(1, Some(2)),
+        };
+        assert!(later_round.verify(HashScheme::Rlp).is_err());
+
+        let prevote = RoundVote::new(VoteStep::Prevote, 7, 0, None, &wallet(KEY)).unwrap();
+        let other_step =
+            Evidence::DoubleRoundVote { first: precommit(0, Some(1)), second: prevote };
+        assert!(other_step.verify(HashScheme::Rlp).is_err());
+    }
+
+    #[test]
+    fn two_headers_at_one_height_are_a_double_sign() {
+        let evidence = Evidence::DoubleSign { first: header(9, 1, KEY), second: header(9, 2, KEY) };
+        evidence.verify(HashScheme::Rlp).unwrap();
+
+        let encoded = alloy_rlp::encode(&evidence);
+        let decoded = Evidence::decode(&mut encoded.as_slice()).unwrap();
+        assert_eq!(alloy_rlp::encode(&decoded), encoded);
+        assert_eq!(get_evidence_root(&[decoded]), keccak256(alloy_rlp::encode(vec![evidence])));
+
+        // Shifting the timestamp does not make the second block any less of a second block.
+        let later = Evidence::DoubleSign { first: header(9, 1, KEY), second: header(10, 2, KEY) };
+        later.verify(HashScheme::Rlp).unwrap();
+
+        let same = Evidence::DoubleSign { first: header(9, 1, KEY), second: header(9, 1, KEY) };
+        assert!(same.verify(HashScheme::Rlp).is_err());
+    }
+
+    #[test]
+    fn the_pool_catches_a_second_header_at_a_height_once() {
+        let mut pool = EvidencePool::new();
+
+        assert!(pool.observe_header(&header(9, 1, KEY), HashScheme::Rlp).is_none());
+        assert!(pool.observe_header(&header(9, 1, KEY), HashScheme::Rlp).is_none());
+        let evidence = pool.observe_header(&header(10, 2, KEY), HashScheme::Rlp).unwrap();
+        evidence.verify(HashScheme::Rlp).unwrap();
+        assert!(pool.observe_header(&header(9, 3, KEY), HashScheme::Rlp).is_none());
+        assert_eq!(pool.pending().len(), 1);
+
+        pool.retain(7 + MAX_EVIDENCE_AGE, |_| false);
+        assert!(pool.pending().is_empty());
+        assert!(pool.observe_header(&header(9, 2, KEY), HashScheme::Rlp).is_none());
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{
+    account::get_account_by_address,
+    chainspec::ChainSpec,
+    mempool::{add_transaction, get_next_nonce},
+    storage::Storage,
+    transaction::Transaction,
+};
+use ethers::signers::{LocalWallet, Signer};
+use eyre::{bail, Result};
+use helper::Address;
+use std::{
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Hands out tokens from a funded account on devnets, where addresses start out empty.
+///
+/// Every address, and every requester such as the IP address a request came from, can only be
+/// funded once per `cooldown`, so that nobody drains the faucet by asking for fresh addresses.
+/// The time of the last payout of each is kept in the node's metadata, so the limit holds across
+/// runs of the `faucet` command.
+#[derive(Debug, Clone)]
+pub struct Faucet {
+    pk: String,
+    address: Address,
+    amount: u64,
+    cooldown: u64,
+}
+
+impl Faucet {
+    /// A faucet paying out `amount` from the account of the private key `pk`, which should be
+    /// funded in the genesis block of the chain spec.
+    pub fn new(pk: &str, amount: u64, cooldown: u64) -> Result<Self> {
+        let address = LocalWallet::from_str(pk)?.address().into();
+
+        Ok(Faucet { pk: pk.to_string(), address, amount, cooldown })
+    }
+
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Adds a transfer of `amount` to `to` to the mempool, unless `to` or `requester` was funded
+    /// less than `cooldown` seconds ago.
+    pub async fn fund(
+        &self,
+        storage: &dyn Storage,
+        spec: &ChainSpec,
+        to: &Address,
+        requester: Option<&str>,
+    ) -> Result<Transaction> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut keys = vec![(payout_key(to), to.to_string())];
+        if let Some(requester) = requester {
+            keys.push((requester_key(requester), requester.to_string()));
+        }
+        for (key, name) in &keys {
+            if let Some(last_payout) = storage.get_metadata(key)? {
+                let elapsed = now.saturating_sub(last_payout.parse()?);
+                if elapsed < self.cooldown {
+                    bail!(
+                        "{} was funded {}s ago, try again in {}s",
+                        name,
+                        elapsed,
+                        self.cooldown - elapsed
+                    );
+                }
+            }
+        }
+
+        // Payouts still waiting in the mempool are as good as spent.
+        let pending = storage
+            .get_mempool()?
+            .iter()
+            .filter(|tx| tx.sender == self.address)
+            .map(|tx| u128::from(tx.value) + u128::from(tx.fee))
+            .sum::<u128>();
+        let balance = u128::from(get_account_by_address(storage, &self.address)?.balance)
+            .saturating_sub(pending);
+        if balance < u128::from(self.amount) {
+            bail!(
+                "faucet {} has {} left and cannot pay out {}",
+                self.address,
+                balance,
+                self.amount
+            );
+        }
+
+        let nonce = get_next_nonce(storage, &self.address)?;
+        let tx =
+            Transaction::new(self.address, *to, self.amount, 0, nonce, spec.chain_id, &self.pk)
+                .await?;
+        add_transaction(storage, spec, tx.clone())?;
+        for (key, _) in &keys {
+            storage.put_metadata(key, &now.to_string())?;
+        }
+
+        Ok(tx)
+    }
+}
+
+fn payout_key(address: &Address) -> String {
+    format!("faucet:{}", address)
+}
+
+fn requester_key(requester: &str) -> String {
+    format!("faucet-requester:{}", requester)
+}
@@ -1,6 +1,19 @@
 pub mod account;
+pub mod bft;
 pub mod block;
 pub mod blockchain;
+pub mod chainspec;
+pub mod consensus;
+pub mod datadir;
+pub mod evidence;
+#[cfg(feature = "faucet")]
+pub mod faucet;
 pub mod mempool;
+pub mod miner;
 pub mod p2p;
+pub mod state;
+pub mod storage;
 pub mod transaction;
+pub mod trie;
+pub mod validator;
+pub mod vote;
@@ -1,61 +1,46 @@
-use eyre::Result;
-use serde::{Deserialize, Serialize};
-use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Write},
-    path::Path,
+use crate::{
+    account::get_account_by_address, chainspec::ChainSpec, state::State, storage::Storage,
+    transaction::Transaction,
 };
+use eyre::{eyre, Result};
+use helper::Address;
 
-const MEMPOOL_JSON: &str = "./mempool.json";
+/// Signed transactions waiting to be included in a block. Clients sign their transactions
+/// themselves, so private keys are never written to the mempool or seen by the node.
+pub type Mempool = Vec<Transaction>;
 
-pub type Mempool = Vec<TransactionRequest>;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TransactionRequest {
-    pub from: String,
-    pub to: String,
-    pub value: u64,
-    pub pk: String,
-}
-
-pub fn mempool_init() -> Result<()> {
-    let path = Path::new(MEMPOOL_JSON);
-    if !path.exists() {
-        let mempool: Mempool = vec![];
-        let mempool_json = serde_json::to_string_pretty(&mempool)?;
-        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
-        file.write_all(mempool_json.as_bytes())?;
-    }
-
-    Ok(())
+/// Every transaction of the mempool, which keeps them until a committed block uses their nonce,
+/// see [`prune_mempool`].
+pub fn get_all_transactions(storage: &dyn Storage) -> Result<Mempool> {
+    storage.get_mempool()
 }
 
-pub fn get_all_transaction_reqs() -> Result<Mempool> {
-    let path = Path::new(MEMPOOL_JSON);
-    let mut file = File::open(path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let mempool: Mempool = serde_json::from_str(&contents)?;
-    let empty_mempool = vec![];
-    update_mempool(&empty_mempool)?;
-
-    Ok(mempool)
+/// Drops the transactions of the mempool whose nonce the accounts of `state` already used, the
+/// ones a block included among them. Transactions with a later nonce wait for the ones before
+/// them.
+pub fn prune_mempool(storage: &dyn Storage, state: &State) -> Result<()> {
+    storage.remove_from_mempool(&|tx| tx.nonce < state.get_account(&tx.sender).nonce)
 }
 
-pub fn add_transaction_req(from: String, to: String, value: u64, pk: String) -> Result<()> {
-    let mut mempool = get_all_transaction_reqs()?;
-    let tx_req = TransactionRequest { from, to, value, pk };
-    mempool.push(tx_req);
-    update_mempool(&mempool)?;
+/// Adds `tx` to the mempool after checking that it was signed by its `sender` for the chain
+/// `spec`.
+pub fn add_transaction(storage: &dyn Storage, spec: &ChainSpec, tx: Transaction) -> Result<()> {
+    tx.verify(spec.chain_id)
+        .map_err(|err| eyre!("invalid transaction from {}: {}", tx.sender, err))?;
 
-    Ok(())
+    storage.add_to_mempool(&tx)
 }
 
-pub fn update_mempool(mempool: &Mempool) -> Result<()> {
-    let path = Path::new(MEMPOOL_JSON);
-    let mempool_json = serde_json::to_string_pretty(mempool)?;
-    let mut file = OpenOptions::new().write(true).truncate(true).open(path)?;
-    file.write_all(mempool_json.as_bytes())?;
-
-    Ok(())
+/// The nonce the next transaction of `address` should use, counting the transactions it
+/// already has waiting in the mempool.
+pub fn get_next_nonce(storage: &dyn Storage, address: &Address) -> Result<u64> {
+    let nonce = get_account_by_address(storage, address)?.nonce;
+    let next_nonce = storage
+        .get_mempool()?
+        .iter()
+        .filter(|tx| tx.sender == *address)
+        .map(|tx| tx.nonce + 1)
+        .fold(nonce, u64::max);
+
+    Ok(next_nonce)
 }
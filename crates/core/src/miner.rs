@@ -0,0 +1,95 @@
+use crate::{block::Header, chainspec::HashScheme};
+use ethers::types::U256;
+use helper::{keccak256, B256};
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    thread,
+};
+
+/// Searches for the proof of work of headers with a number of threads.
+///
+/// The work of a header is the Keccak hash of its [`mining_hash`] followed by its `nonce`, and it
+/// is done when that hash, read as a number, is at most [`target`] of the header's difficulty. A
+/// difficulty of `d` thus takes `d` hashes on average. Each thread tries every `threads`-th
+/// nonce, so they never try the same one twice.
+#[derive(Debug, Clone, Copy)]
+pub struct Miner {
+    threads: u64,
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Self {
+        Miner { threads: threads.max(1) as u64 }
+    }
+
+    /// A miner with a thread for every core of the machine.
+    pub fn with_available_parallelism() -> Self {
+        Miner::new(thread::available_parallelism().map_or(1, NonZeroUsize::get))
+    }
+
+    /// Finds a nonce for `header` that meets its difficulty and writes it to the header. Returns
+    /// `false`, leaving the header as it was, if `stop` was set before one was found.
+    pub fn mine(&self, header: &mut Header, scheme: HashScheme, stop: &AtomicBool) -> bool {
+        let mining_hash = mining_hash(header, scheme);
+        let target = target(header.difficulty);
+        let found = OnceLock::new();
+
+        thread::scope(|scope| {
+            for first in 0..self.threads {
+                let found = &found;
+                scope.spawn(move || {
+                    let mut nonce = first;
+                    while found.get().is_none() && !stop.load(Ordering::Relaxed) {
+                        if meets_target(&work_hash(&mining_hash, nonce), target) {
+                            let _ = found.set(nonce);
+                            return;
+                        }
+                        nonce = nonce.wrapping_add(self.threads);
+                    }
+                });
+            }
+        });
+
+        match found.get() {
+            Some(nonce) => {
+                header.nonce = *nonce;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Hash of `header` the work is done over. It covers the same fields as the seal hash, but with
+/// a `nonce` of zero, so that trying a nonce takes a single hash.
+pub fn mining_hash(header: &Header, scheme: HashScheme) -> B256 {
+    Header { nonce: 0, ..header.clone() }.get_seal_hash(scheme)
+}
+
+/// Hash of the work of trying `nonce` for the header of `mining_hash`.
+pub fn work_hash(mining_hash: &B256, nonce: u64) -> B256 {
+    let mut input = [0u8; 40];
+    input[..32].copy_from_slice(mining_hash.as_bytes());
+    input[32..].copy_from_slice(&nonce.to_be_bytes());
+
+    keccak256(input)
+}
+
+/// The largest work hash that meets `difficulty`.
+pub fn target(difficulty: u64) -> U256 {
+    U256::MAX / U256::from(difficulty.max(1))
+}
+
+/// Whether `hash`, read as a big endian number, is at most `target`.
+pub fn meets_target(hash: &B256, target: U256) -> bool {
+    U256::from_big_endian(hash.as_bytes()) <= target
+}
+
+/// Whether the `nonce` of `header` meets its difficulty.
+pub fn verify_work(header: &Header, scheme: HashScheme) -> bool {
+    meets_target(&work_hash(&mining_hash(header, scheme), header.nonce), target(header.difficulty))
+}
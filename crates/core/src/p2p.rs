@@ -1,5 +1,10 @@
-use crate::block::Block;
+use crate::{
+    bft::CommitCertificate, block::Block, transaction::verify_hash_signature, vote::VoteCertificate,
+};
 use alloy_rlp::{RlpDecodable, RlpEncodable};
+use ethers::signers::{LocalWallet, Signer};
+use eyre::Result;
+use helper::{keccak256, Address, Signature, B256};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -10,13 +15,109 @@ pub struct P2PMessage {
     pub data: Option<Vec<u8>>,
     pub random: u64,
 }
-#[derive(Debug, Serialize, Deserialize, RlpEncodable, RlpDecodable)]
+
+/// A validator's vote on a proposed block, signed with the validator's key so that nobody can
+/// vote in its name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, RlpEncodable, RlpDecodable)]
 pub struct VoteOnBlock {
     pub block_number: u64,
-    pub vote: String,
+    /// The proposal the vote is on, which is the timestamp of the proposed block. The proposer
+    /// picks it, so votes are only ever told apart by their height.
+    pub round: u64,
+    pub block_hash: B256,
+    /// Whether the voter found the block valid.
+    pub approve: bool,
+    pub voter: Address,
+    pub signature: Signature,
+}
+
+/// The part of a vote its signature covers.
+#[derive(RlpEncodable)]
+struct VoteData {
+    block_number: u64,
+    round: u64,
+    block_hash: B256,
+    approve: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, RlpEncodable, RlpDecodable)]
 pub struct NBlocks {
     pub blocks: Vec<Block>,
+    /// Commit certificates of the blocks that have one, for chains whose blocks are only final
+    /// with one.
+    pub certificates: Vec<CommitCertificate>,
+    /// The votes that finalized the blocks that have them, for chains that vote on blocks.
+    pub votes: Vec<VoteCertificate>,
+}
+
+impl NBlocks {
+    /// The commit certificate of block `number`, if it came along.
+    pub fn certificate(&self, number: u64) -> Option<&CommitCertificate> {
+        self.certificates.iter().find(|certificate| certificate.height == number)
+    }
+
+    /// The votes that finalized block `number`, if they came along.
+    pub fn votes(&self, number: u64) -> Option<&VoteCertificate> {
+        self.votes.iter().find(|votes| votes.block_number == number)
+    }
+}
+
+impl VoteOnBlock {
+    /// Votes on the block `block_hash` proposed at height `block_number` in round `round` as the
+    /// validator of `voter_wallet`.
+    pub fn new(
+        block_number: u64,
+        round: u64,
+        block_hash: B256,
+        approve: bool,
+        voter_wallet: &LocalWallet,
+    ) -> Result<Self> {
+        let hash = VoteOnBlock::get_signing_hash(block_number, round, block_hash, approve);
+        let signature = Signature::from(voter_wallet.sign_hash(hash.into())?);
+        let voter = Address::from(voter_wallet.address());
+
+        Ok(VoteOnBlock { block_number, round, block_hash, approve, voter, signature })
+    }
+
+    /// Votes on `block`, whose hash is `block_hash`, as the validator of `voter_wallet`.
+    pub fn on_block(
+        block: &Block,
+        block_hash: B256,
+        approve: bool,
+        voter_wallet: &LocalWallet,
+    ) -> Result<Self> {
+        let header = &block.header;
+        VoteOnBlock::new(header.number, header.timestamp, block_hash, approve, voter_wallet)
+    }
+
+    /// Hash a validator signs to cast a vote.
+    pub fn get_signing_hash(
+        block_number: u64,
+        round: u64,
+        block_hash: B256,
+        approve: bool,
+    ) -> B256 {
+        keccak256(alloy_rlp::encode(VoteData { block_number, round, block_hash, approve }))
+    }
+
+    /// Whether `other`, a vote of the same voter at the same height, contradicts this one: both
+    /// approve different blocks, or they disagree on the same block.
+    pub fn conflicts_with(&self, other: &VoteOnBlock) -> bool {
+        if self.block_hash == other.block_hash {
+            self.approve != other.approve
+        } else {
+            self.approve && other.approve
+        }
+    }
+
+    /// Checks that the vote was signed by `voter`.
+    pub fn verify(&self) -> Result<()> {
+        let hash = VoteOnBlock::get_signing_hash(
+            self.block_number,
+            self.round,
+            self.block_hash,
+            self.approve,
+        );
+        verify_hash_signature(self.signature, hash, self.voter)
+    }
 }
@@ -0,0 +1,449 @@
+use crate::{
+    account::{new_account, Account, Accounts},
+    block::{Block, Header},
+    consensus::ConsensusEngine,
+    evidence::{Evidence, MAX_EVIDENCE_AGE, SLASHING_PERCENTAGE},
+    storage::Storage,
+    transaction::{Transaction, TransactionKind, Transactions},
+    trie::{MerkleProof, SparseMerkleTree},
+};
+use eyre::{bail, eyre, Result};
+use helper::{keccak256, Address, B256};
+use std::collections::BTreeMap;
+
+/// Blocks unstaked tokens stay locked for before they return to the balance. Evidence may go into
+/// a block up to [`MAX_EVIDENCE_AGE`] blocks after the misbehavior and offenders are slashed before
+/// the tokens are released, so unstaking right after misbehaving does not escape the slashing.
+pub const UNBONDING_PERIOD: u64 = MAX_EVIDENCE_AGE;
+
+/// Account state of the chain after some block, keyed by address.
+///
+/// Every account is also committed to a [`SparseMerkleTree`] under the Keccak hash of its
+/// checksummed address, which is kept up to date on every change and whose root is the state root.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct State {
+    accounts: BTreeMap<Address, Account>,
+    trie: SparseMerkleTree,
+}
+
+impl State {
+    pub fn load(storage: &dyn Storage) -> Result<Self> {
+        Ok(State::from_accounts(storage.get_accounts()?))
+    }
+
+    pub fn from_accounts(accounts: Accounts) -> Self {
+        let mut state = State::default();
+        for account in accounts {
+            state.set_account(account);
+        }
+
+        state
+    }
+
+    pub fn get_account(&self, address: &Address) -> Account {
+        self.accounts.get(address).cloned().unwrap_or_else(|| new_account(address))
+    }
+
+    /// Whether the account at `address` was ever written to state.
+    pub fn contains(&self, address: &Address) -> bool {
+        self.accounts.contains_key(address)
+    }
+
+    pub fn set_account(&mut self, account: Account) {
+        self.trie.insert(get_account_key(&account.address), account.get_account_hash());
+        self.accounts.insert(account.address, account);
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values()
+    }
+
+    /// Returns the accounts that are new or different compared to `previous`.
+    pub fn changed_accounts(&self, previous: &State) -> Accounts {
+        self.accounts
+            .values()
+            .filter(|acc| previous.accounts.get(&acc.address) != Some(*acc))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the addresses of the accounts of `previous` that this state does not have.
+    pub fn removed_accounts(&self, previous: &State) -> Vec<Address> {
+        previous
+            .accounts
+            .keys()
+            .filter(|address| !self.accounts.contains_key(address))
+            .copied()
+            .collect()
+    }
+
+    /// Moves `tx.value` from the sender to the receiver, from the balance of the sender to its
+    /// stake, or from its stake to its unbonding stake, takes the fee from the sender's balance and
+    /// bumps the sender's nonce. `number` is the block the transaction goes into. The fee goes to
+    /// the miner once the whole block is executed, see [`reward_miner`], and unstaked tokens
+    /// return to the balance [`UNBONDING_PERIOD`] blocks later, see [`release_unbonded`].
+    pub fn apply_transaction(&mut self, tx: &Transaction, number: u64) -> Result<()> {
+        let mut sender = self.get_account(&tx.sender);
+
+        if tx.nonce != sender.nonce {
+            bail!("expected nonce {} for {} but got {}", sender.nonce, tx.sender, tx.nonce);
+        }
+        sender.nonce += 1;
+
+        if sender.balance < tx.fee {
+            bail!("{} has a balance of {} but pays a fee of {}", tx.sender, sender.balance, tx.fee);
+        }
+        sender.balance -= tx.fee;
+
+        match tx.kind() {
+            TransactionKind::Transfer => {
+                if sender.balance < tx.value {
+                    bail!(
+                        "{} has a balance of {} but sends {}",
+                        tx.sender,
+                        sender.balance,
+                        tx.value
+                    );
+                }
+                sender.balance -= tx.value;
+                self.set_account(sender);
+
+                let mut receiver = self.get_account(&tx.receiver);
+                receiver.balance = receiver
+                    .balance
+                    .checked_add(tx.value)
+                    .ok_or_else(|| eyre!("balance of {} would overflow", tx.receiver))?;
+                self.set_account(receiver);
+            }
+            TransactionKind::Stake => {
+                if sender.balance < tx.value {
+                    bail!(
+                        "{} has a balance of {} but stakes {}",
+                        tx.sender,
+                        sender.balance,
+                        tx.value
+                    );
+                }
+                sender.balance -= tx.value;
+                sender.stake = sender
+                    .stake
+                    .checked_add(tx.value)
+                    .ok_or_else(|| eyre!("stake of {} would overflow", tx.sender))?;
+                self.set_account(sender);
+            }
+            TransactionKind::Unstake => {
+                if sender.stake < tx.value {
+                    bail!(
+                        "{} has a stake of {} but unstakes {}",
+                        tx.sender,
+                        sender.stake,
+                        tx.value
+                    );
+                }
+                sender.stake -= tx.value;
+                sender.unbonding = sender
+                    .unbonding
+                    .checked_add(tx.value)
+                    .filter(|unbonding| sender.balance.checked_add(*unbonding).is_some())
+                    .ok_or_else(|| eyre!("balance of {} would overflow", tx.sender))?;
+                // Unstaking again locks what is still unbonding for the full period too.
+                sender.unbonding_until = number + UNBONDING_PERIOD;
+                self.set_account(sender);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Burns [`SLASHING_PERCENTAGE`] of the stake and of the unbonding stake of `offender`, or of
+    /// its balance if it has neither, as under engines without stake, and returns how much it
+    /// lost.
+    pub fn slash(&mut self, offender: &Address) -> u64 {
+        let mut account = self.get_account(offender);
+        let funds = if account.stake > 0 || account.unbonding > 0 {
+            vec![&mut account.stake, &mut account.unbonding]
+        } else {
+            vec![&mut account.balance]
+        };
+        let mut penalty = 0;
+        for funds in funds {
+            let cut = (u128::from(*funds) * u128::from(SLASHING_PERCENTAGE) / 100) as u64;
+            *funds -= cut;
+            penalty += cut;
+        }
+        if penalty > 0 {
+            self.set_account(account);
+        }
+
+        penalty
+    }
+
+    /// The accounts with a stake of at least `min_stake` and their stakes, by address.
+    pub fn stakers(&self, min_stake: u64) -> Vec<(Address, u64)> {
+        self.accounts
+            .values()
+            .filter(|account| account.stake > 0 && account.stake >= min_stake)
+            .map(|account| (account.address, account.stake))
+            .collect()
+    }
+
+    /// Sum of the balances, stakes and unbonding stakes of every account, which only changes by
+    /// what blocks issue and burn.
+    pub fn total_supply(&self) -> u128 {
+        self.accounts
+            .values()
+            .map(|account| {
+                u128::from(account.balance)
+                    + u128::from(account.stake)
+                    + u128::from(account.unbonding)
+            })
+            .sum()
+    }
+
+    pub fn get_state_root(&self) -> B256 {
+        self.trie.root()
+    }
+
+    pub fn trie(&self) -> &SparseMerkleTree {
+        &self.trie
+    }
+
+    /// Proves the account at `address`, or its absence if it was never written to state.
+    pub fn get_account_proof(&self, address: &Address) -> (Option<Account>, MerkleProof) {
+        let proof = self.trie.prove(get_account_key(address));
+        (self.accounts.get(address).cloned(), proof)
+    }
+}
+
+/// Position of an account in the state trie.
+pub fn get_account_key(address: &Address) -> B256 {
+    keccak256(address.to_string())
+}
+
+/// The state transition function: applies every transaction of `block` on top of `state`,
+/// slashes the offender of every piece of its evidence, releases the unbonding stakes that are
+/// due and pays its miner `block_reward` and the fees.
+///
+/// Fails without touching `state` if any transaction has a wrong nonce or spends more than the
+/// sender owns.
+pub fn execute_block(state: &State, block: &Block, block_reward: u64) -> Result<State> {
+    let mut state = execute_transactions(state, &block.txs, block.header.number)
+        .map_err(|err| eyre!("block {}: {}", block.header.number, err))?;
+    slash_offenders(&mut state, &block.evidence);
+    release_unbonded(&mut state, block.header.number)?;
+    reward_miner(&mut state, &block.header, &block.txs, block_reward)?;
+
+    Ok(state)
+}
+
+/// Credits `block_reward` and the fees of `txs` to the miner of the block of `header`. The
+/// genesis block has no miner and pays nothing.
+pub fn reward_miner(
+    state: &mut State,
+    header: &Header,
+    txs: &Transactions,
+    block_reward: u64,
+) -> Result<()> {
+    if header.number == 0 {
+        return Ok(());
+    }
+
+    let reward = txs
+        .iter()
+        .try_fold(block_reward, |reward, tx| reward.checked_add(tx.fee))
+        .ok_or_else(|| eyre!("fees of block {} overflow", header.number))?;
+    if reward == 0 {
+        return Ok(());
+    }
+
+    let mut miner = state.get_account(&header.miner);
+    miner.balance = miner
+        .balance
+        .checked_add(reward)
+        .ok_or_else(|| eyre!("balance of {} would overflow", header.miner))?;
+    state.set_account(miner);
+
+    Ok(())
+}
+
+/// Slashes the offender of every piece of `evidence`, see [`State::slash`].
+pub fn slash_offenders(state: &mut State, evidence: &[Evidence]) {
+    for evidence in evidence {
+        state.slash(&evidence.offender());
+    }
+}
+
+/// Returns the unbonding stakes whose lock ends with block `number` to the balances of their
+/// accounts.
+pub fn release_unbonded(state: &mut State, number: u64) -> Result<()> {
+    let due: Accounts = state
+        .accounts()
+        .filter(|account| account.unbonding > 0 && account.unbonding_until <= number)
+        .cloned()
+        .collect();
+    for mut account in due {
+        account.balance = account
+            .balance
+            .checked_add(account.unbonding)
+            .ok_or_else(|| eyre!("balance of {} would overflow", account.address))?;
+        account.unbonding = 0;
+        account.unbonding_until = 0;
+        state.set_account(account);
+    }
+
+    Ok(())
+}
+
+/// Executes `block` on top of `state` with `block_reward`, finalizes it with `consensus` and
+/// checks the result against the block's `state_root`.
+pub fn verify_state_transition(
+    state: &State,
+    block: &Block,
+    block_reward: u64,
+    consensus: &dyn ConsensusEngine,
+) -> Result<State> {
+    let mut post_state = execute_block(state, block, block_reward)?;
+    consensus.finalize(&block.header, &mut post_state)?;
+
+    let state_root = post_state.get_state_root();
+    if state_root != block.header.state_root {
+        bail!(
+            "block {} has state root {} but executing it gives {}",
+            block.header.number,
+            block.header.state_root,
+            state_root
+        );
+    }
+
+    Ok(post_state)
+}
+
+/// Applies `txs` on top of `state` as the transactions of block `number`.
+pub fn execute_transactions(state: &State, txs: &Transactions, number: u64) -> Result<State> {
+    let mut state = state.clone();
+
+    for (index, tx) in txs.iter().enumerate() {
+        state
+            .apply_transaction(tx, number)
+            .map_err(|err| eyre!("transaction {}: {}", index, err))?;
+    }
+
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{STAKE_ADDRESS, UNSTAKE_ADDRESS};
+    use helper::Signature;
+
+    const ALICE: Address = Address::new([1; 20]);
+    const BOB: Address = Address::new([2; 20]);
+    const MINER: Address = Address::new([3; 20]);
+
+    /// A transaction as the state applies it. Signatures are checked before a transaction gets
+    /// here, so it carries none.
+    fn tx(sender: Address, receiver: Address, value: u64, fee: u64, nonce: u64) -> Transaction {
+        let signature = Signature { r: B256::ZERO, s: B256::ZERO, v: 0 };
+        Transaction { sender, receiver, value, fee, nonce, signature }
+    }
+
+    fn state(balance: u64, stake: u64) -> State {
+        State::from_accounts(vec![Account { balance, stake, ..new_account(&ALICE) }])
+    }
+
+    fn header(number: u64) -> Header {
+        Header::new(B256::ZERO, MINER, B256::ZERO, B256::ZERO, number, number, vec![])
+    }
+
+    #[test]
+    fn transfers_and_bumps_the_nonce() {
+        let mut state = state(100, 0);
+
+        state.apply_transaction(&tx(ALICE, BOB, 30, 2, 0), 1).unwrap();
+        assert_eq!((state.get_account(&ALICE).balance, state.get_account(&ALICE).nonce), (68, 1));
+        assert_eq!(state.get_account(&BOB).balance, 30);
+
+        let before = state.clone();
+        assert!(state.apply_transaction(&tx(ALICE, BOB, 1, 0, 0), 1).is_err());
+        assert!(state.apply_transaction(&tx(ALICE, BOB, 68, 1, 1), 1).is_err());
+        assert!(execute_transactions(&before, &vec![tx(ALICE, BOB, 69, 0, 1)], 1).is_err());
+    }
+
+    #[test]
+    fn pays_the_reward_and_the_fees_to_the_miner() {
+        let txs = vec![tx(ALICE, BOB, 10, 3, 0), tx(ALICE, BOB, 10, 4, 1)];
+        let block = Block { header: header(1), txs, evidence: vec![] };
+
+        let post_state = execute_block(&state(100, 0), &block, 5).unwrap();
+        assert_eq!(post_state.get_account(&MINER).balance, 12);
+        assert_eq!(post_state.get_account(&ALICE).balance, 73);
+        // Only the block reward is new, the fees just change hands.
+        assert_eq!(post_state.total_supply(), 105);
+
+        let genesis = Block { header: header(0), txs: vec![], evidence: vec![] };
+        let post_state = execute_block(&state(100, 0), &genesis, 5).unwrap();
+        assert_eq!(post_state.total_supply(), 100);
+    }
+
+    #[test]
+    fn unstaked_tokens_count_and_return_after_the_unbonding_period() {
+        let mut state = state(100, 0);
+        state.apply_transaction(&tx(ALICE, STAKE_ADDRESS, 40, 0, 0), 1).unwrap();
+        state.apply_transaction(&tx(ALICE, UNSTAKE_ADDRESS, 30, 0, 1), 10).unwrap();
+
+        let alice = state.get_account(&ALICE);
+        assert_eq!((alice.balance, alice.stake, alice.unbonding), (60, 10, 30));
+        assert_eq!(alice.unbonding_until, 10 + UNBONDING_PERIOD);
+        assert_eq!(state.stakers(1), [(ALICE, 10)]);
+        assert_eq!(state.total_supply(), 100);
+
+        release_unbonded(&mut state, 9 + UNBONDING_PERIOD).unwrap();
+        assert_eq!(state.get_account(&ALICE).unbonding, 30);
+        release_unbonded(&mut state, 10 + UNBONDING_PERIOD).unwrap();
+        let alice = state.get_account(&ALICE);
+        assert_eq!((alice.balance, alice.stake, alice.unbonding), (90, 10, 0));
+        assert_eq!(state.total_supply(), 100);
+    }
+
+    #[test]
+    fn refuses_stakes_that_overflow() {
+        let mut state = State::from_accounts(vec![Account {
+            balance: u64::MAX,
+            stake: u64::MAX,
+            ..new_account(&ALICE)
+        }]);
+
+        let err = state.apply_transaction(&tx(ALICE, STAKE_ADDRESS, 1, 0, 0), 1).unwrap_err();
+        assert!(err.to_string().contains("would overflow"), "{}", err);
+        let err = state.apply_transaction(&tx(ALICE, UNSTAKE_ADDRESS, 1, 0, 0), 1).unwrap_err();
+        assert!(err.to_string().contains("would overflow"), "{}", err);
+    }
+
+    #[test]
+    fn slashing_burns_stake_and_unbonding_stake_or_else_the_balance() {
+        let mut state = State::from_accounts(vec![Account {
+            balance: 100,
+            stake: 40,
+            unbonding: 20,
+            unbonding_until: 300,
+            ..new_account(&ALICE)
+        }]);
+
+        assert_eq!(state.slash(&ALICE), 30);
+        let alice = state.get_account(&ALICE);
+        assert_eq!((alice.balance, alice.stake, alice.unbonding), (100, 20, 10));
+        assert_eq!(state.total_supply(), 130);
+
+        // Offenders under engines without stake lose half of their balance instead.
+        let mut without_stake =
+            State::from_accounts(vec![Account { balance: 100, ..new_account(&ALICE) }]);
+        assert_eq!(without_stake.slash(&ALICE), 50);
+        assert_eq!(without_stake.get_account(&ALICE).balance, 50);
+
+        let mut broke = State::from_accounts(vec![new_account(&ALICE)]);
+        let root = broke.get_state_root();
+        assert_eq!(broke.slash(&ALICE), 0);
+        assert_eq!(broke.get_state_root(), root);
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{
+    account::{Account, Accounts},
+    block::{Block, Blocks},
+    chainspec::HashScheme,
+    mempool::Mempool,
+    transaction::Transaction,
+};
+use eyre::Result;
+use helper::{Address, B256};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+mod json;
+mod kv;
+mod memory;
+
+pub use json::JsonStorage;
+pub use kv::{KvStorage, KvStore};
+pub use memory::MemoryStorage;
+
+/// Persistence backend for blocks, account state, the mempool and node metadata.
+///
+/// Implementations must be safe to share between tasks, so everything takes `&self` and
+/// backends that keep state in memory are expected to use interior mutability.
+pub trait Storage: Send + Sync {
+    fn get_blocks(&self) -> Result<Blocks>;
+
+    fn append_block(&self, block: &Block) -> Result<()>;
+
+    /// Drops every block after block `number`, which becomes the last block again. Readers never
+    /// see a block after the new last block, even if this fails half way through.
+    fn truncate_blocks(&self, number: u64) -> Result<()>;
+
+    fn get_last_block(&self) -> Result<Option<Block>> {
+        Ok(self.get_blocks()?.pop())
+    }
+
+    fn get_last_n_blocks(&self, n: usize) -> Result<Blocks> {
+        let mut blocks = self.get_blocks()?;
+        let start_index = blocks.len().saturating_sub(n);
+        Ok(blocks.split_off(start_index))
+    }
+
+    fn get_block_by_number(&self, number: u64) -> Result<Option<Block>> {
+        Ok(self.get_blocks()?.into_iter().find(|block| block.header.number == number))
+    }
+
+    fn get_block_by_hash(&self, hash: &B256) -> Result<Option<Block>> {
+        let scheme = HashScheme::load(self)?;
+        for block in self.get_blocks()? {
+            if block.get_block_hash(scheme)? == *hash {
+                return Ok(Some(block));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn get_accounts(&self) -> Result<Accounts>;
+
+    fn get_account(&self, address: &Address) -> Result<Option<Account>> {
+        Ok(self.get_accounts()?.into_iter().find(|acc| acc.address == *address))
+    }
+
+    fn put_account(&self, account: &Account) -> Result<()>;
+
+    fn delete_account(&self, address: &Address) -> Result<()>;
+
+    fn get_mempool(&self) -> Result<Mempool>;
+
+    fn add_to_mempool(&self, tx: &Transaction) -> Result<()>;
+
+    /// Removes the transactions of the mempool that `drop` holds for, such as the ones a block
+    /// included. Transactions added while this runs stay in the mempool.
+    fn remove_from_mempool(&self, drop: &dyn Fn(&Transaction) -> bool) -> Result<()>;
+
+    fn get_metadata(&self, key: &str) -> Result<Option<String>>;
+
+    fn put_metadata(&self, key: &str, value: &str) -> Result<()>;
+
+    /// Repairs whatever a crash in the middle of a write left behind. Called once on startup by
+    /// [`crate::blockchain::Blockchain::init`] before anything is read.
+    fn recover(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Replaces the file at `path` so that readers only ever see either the old or the new contents.
+///
+/// The data is written to a sibling temp file and synced before being renamed over `path`, then
+/// the directory is synced so the rename itself survives a power loss.
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = tmp_path(path);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path)
+}
+
+pub(crate) fn tmp_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".tmp")
+}
+
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{account::new_account, chainspec::ChainSpec};
+    use helper::Signature;
+
+    /// A directory of its own under the system temp directory, removed again when dropped.
+    pub(crate) struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("storage-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        pub(crate) fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn block(number: u64) -> Block {
+        let mut block = Block::genesis(&ChainSpec::default());
+        block.header.number = number;
+        block
+    }
+
+    fn transfer(nonce: u64) -> Transaction {
+        let signature = Signature { r: B256::ZERO, s: B256::ZERO, v: 0 };
+        let (sender, receiver) = (Address::new([1; 20]), Address::new([2; 20]));
+        Transaction { sender, receiver, value: 1, fee: 0, nonce, signature }
+    }
+
+    /// Runs `storage`, which must start out empty, through everything the trait promises.
+    fn check_backend(storage: &dyn Storage) {
+        assert!(storage.get_last_block().unwrap().is_none());
+        for number in 0..3 {
+            storage.append_block(&block(number)).unwrap();
+        }
+        assert_eq!(storage.get_blocks().unwrap().len(), 3);
+        assert_eq!(storage.get_last_block().unwrap().unwrap().header.number, 2);
+        let last_two = storage.get_last_n_blocks(2).unwrap();
+        assert_eq!(last_two.iter().map(|block| block.header.number).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(storage.get_last_n_blocks(5).unwrap().len(), 3);
+        assert_eq!(storage.get_block_by_number(1).unwrap().unwrap().header.number, 1);
+        assert!(storage.get_block_by_number(3).unwrap().is_none());
+        let hash = block(1).get_block_hash(HashScheme::default()).unwrap();
+        assert_eq!(storage.get_block_by_hash(&hash).unwrap().unwrap().header.number, 1);
+        assert!(storage.get_block_by_hash(&B256::ZERO).unwrap().is_none());
+
+        let address = Address::new([1; 20]);
+        assert!(storage.get_account(&address).unwrap().is_none());
+        storage.put_account(&Account { balance: 5, ..new_account(&address) }).unwrap();
+        storage.put_account(&Account { balance: 7, ..new_account(&address) }).unwrap();
+        assert_eq!(storage.get_accounts().unwrap().len(), 1);
+        assert_eq!(storage.get_account(&address).unwrap().unwrap().balance, 7);
+
+        storage.add_to_mempool(&transfer(0)).unwrap();
+        storage.add_to_mempool(&transfer(1)).unwrap();
+        assert_eq!(storage.get_mempool().unwrap().len(), 2);
+        storage.add_to_mempool(&transfer(2)).unwrap();
+        storage.remove_from_mempool(&|tx| tx.nonce == 1).unwrap();
+        let nonces: Vec<u64> = storage.get_mempool().unwrap().iter().map(|tx| tx.nonce).collect();
+        assert_eq!(nonces, [0, 2]);
+
+        assert!(storage.get_metadata("key").unwrap().is_none());
+        storage.put_metadata("key", "first").unwrap();
+        storage.put_metadata("key", "second").unwrap();
+        assert_eq!(storage.get_metadata("key").unwrap().as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn memory_storage_keeps_the_contract() {
+        check_backend(&MemoryStorage::new());
+    }
+
+    #[test]
+    fn json_storage_keeps_the_contract() {
+        let dir = TempDir::new("json");
+        check_backend(&JsonStorage::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn kv_storage_keeps_the_contract() {
+        let dir = TempDir::new("kv");
+        check_backend(&KvStorage::open(dir.path()).unwrap());
+    }
+}
@@ -0,0 +1,217 @@
+use crate::{
+    account::{Account, Accounts},
+    block::{Block, Blocks},
+    mempool::Mempool,
+    storage::{tmp_path, with_suffix, write_atomic, Storage},
+    transaction::Transaction,
+};
+use eyre::{eyre, Result};
+use helper::Address;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+const BLOCKCHAIN_JSON: &str = "blockchain.json";
+const ACCOUNTS_JSON: &str = "accounts.json";
+const MEMPOOL_JSON: &str = "mempool.json";
+const METADATA_JSON: &str = "metadata.json";
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BlockchainFile {
+    blocks: Blocks,
+}
+
+/// The original on-disk layout: one pretty-printed JSON file per kind of data.
+///
+/// Files are always replaced atomically and the previous version of each is kept next to it
+/// as `<file>.bak`, which is what [`Storage::recover`] falls back to if a file written by an
+/// older version of the node was torn.
+#[derive(Debug, Clone)]
+pub struct JsonStorage {
+    blockchain: PathBuf,
+    accounts: PathBuf,
+    mempool: PathBuf,
+    metadata: PathBuf,
+}
+
+impl JsonStorage {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let storage = JsonStorage {
+            blockchain: dir.join(BLOCKCHAIN_JSON),
+            accounts: dir.join(ACCOUNTS_JSON),
+            mempool: dir.join(MEMPOOL_JSON),
+            metadata: dir.join(METADATA_JSON),
+        };
+
+        create_if_missing(&storage.accounts, &Accounts::new())?;
+        create_if_missing(&storage.mempool, &Mempool::new())?;
+        create_if_missing(&storage.metadata, &BTreeMap::<String, String>::new())?;
+
+        Ok(storage)
+    }
+
+    /// Whether `dir` holds a chain in this layout.
+    pub fn exists(dir: impl AsRef<Path>) -> bool {
+        dir.as_ref().join(BLOCKCHAIN_JSON).exists()
+    }
+
+    /// Every metadata key and its value.
+    pub fn metadata(&self) -> Result<BTreeMap<String, String>> {
+        read_json(&self.metadata)
+    }
+}
+
+impl Storage for JsonStorage {
+    fn get_blocks(&self) -> Result<Blocks> {
+        if !self.blockchain.exists() {
+            return Ok(vec![]);
+        }
+        let blockchain: BlockchainFile = read_json(&self.blockchain)?;
+
+        Ok(blockchain.blocks)
+    }
+
+    fn append_block(&self, block: &Block) -> Result<()> {
+        let mut blocks = self.get_blocks()?;
+        blocks.push(block.clone());
+        write_json(&self.blockchain, &BlockchainFile { blocks })
+    }
+
+    fn truncate_blocks(&self, number: u64) -> Result<()> {
+        let mut blocks = self.get_blocks()?;
+        blocks.retain(|block| block.header.number <= number);
+        write_json(&self.blockchain, &BlockchainFile { blocks })
+    }
+
+    fn get_accounts(&self) -> Result<Accounts> {
+        read_json(&self.accounts)
+    }
+
+    fn put_account(&self, account: &Account) -> Result<()> {
+        let mut accounts = self.get_accounts()?;
+
+        if let Some(index) = accounts.iter().position(|acc| acc.address == account.address) {
+            accounts[index] = account.clone();
+        } else {
+            accounts.push(account.clone());
+        }
+
+        write_json(&self.accounts, &accounts)
+    }
+
+    fn delete_account(&self, address: &Address) -> Result<()> {
+        let mut accounts = self.get_accounts()?;
+        accounts.retain(|acc| acc.address != *address);
+        write_json(&self.accounts, &accounts)
+    }
+
+    fn get_mempool(&self) -> Result<Mempool> {
+        read_json(&self.mempool)
+    }
+
+    fn add_to_mempool(&self, tx: &Transaction) -> Result<()> {
+        let mut mempool = self.get_mempool()?;
+        mempool.push(tx.clone());
+        write_json(&self.mempool, &mempool)
+    }
+
+    fn remove_from_mempool(&self, drop: &dyn Fn(&Transaction) -> bool) -> Result<()> {
+        let mut mempool = self.get_mempool()?;
+        mempool.retain(|tx| !drop(tx));
+        write_json(&self.mempool, &mempool)
+    }
+
+    fn get_metadata(&self, key: &str) -> Result<Option<String>> {
+        let metadata: BTreeMap<String, String> = read_json(&self.metadata)?;
+        Ok(metadata.get(key).cloned())
+    }
+
+    fn put_metadata(&self, key: &str, value: &str) -> Result<()> {
+        let mut metadata: BTreeMap<String, String> = read_json(&self.metadata)?;
+        metadata.insert(key.to_string(), value.to_string());
+        write_json(&self.metadata, &metadata)
+    }
+
+    fn recover(&self) -> Result<()> {
+        if self.blockchain.exists() {
+            recover_json::<BlockchainFile>(&self.blockchain, None)?;
+        }
+        recover_json(&self.accounts, None::<Accounts>)?;
+        recover_json(&self.mempool, Some(Mempool::new()))?;
+        recover_json(&self.metadata, Some(BTreeMap::<String, String>::new()))?;
+
+        Ok(())
+    }
+}
+
+/// Makes sure `path` holds valid JSON, restoring the backup if it doesn't. Files that only hold
+/// transient data can be reset to `fallback` when the backup is unusable too.
+fn recover_json<T: Serialize + DeserializeOwned>(path: &Path, fallback: Option<T>) -> Result<()> {
+    let tmp_path = tmp_path(path);
+    if tmp_path.exists() {
+        fs::remove_file(&tmp_path)?;
+    }
+
+    if read_json::<T>(path).is_ok() {
+        return Ok(());
+    }
+
+    let backup_path = backup_path(path);
+    if let Ok(backup) = read_json::<T>(&backup_path) {
+        println!(
+            "⚠️ {} is corrupt, restoring it from {} ⚠️",
+            path.display(),
+            backup_path.display()
+        );
+        return write_atomic(path, serde_json::to_string_pretty(&backup)?.as_bytes());
+    }
+
+    match fallback {
+        Some(fallback) => {
+            println!("⚠️ {} is corrupt, resetting it ⚠️", path.display());
+            write_atomic(path, serde_json::to_string_pretty(&fallback)?.as_bytes())
+        }
+        None => Err(eyre!("{} is corrupt and has no usable backup", path.display())),
+    }
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bak")
+}
+
+fn create_if_missing<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    if !path.exists() {
+        let json = serde_json::to_string_pretty(value)?;
+        write_atomic(path, json.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let mut file = File::open(path)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let value: T = serde_json::from_str(&contents)?;
+
+    Ok(value)
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let json = serde_json::to_string_pretty(value)?;
+
+    if path.exists() {
+        let backup_path = backup_path(path);
+        if backup_path.exists() {
+            fs::remove_file(&backup_path)?;
+        }
+        fs::hard_link(path, &backup_path)?;
+    }
+
+    write_atomic(path, json.as_bytes())
+}
@@ -0,0 +1,751 @@
+use crate::{
+    account::{Account, Accounts},
+    block::{Block, Blocks},
+    chainspec::HashScheme,
+    mempool::Mempool,
+    storage::{tmp_path, with_suffix, write_atomic, Storage},
+    transaction::Transaction,
+};
+use eyre::{eyre, Result};
+use helper::{Address, B256};
+use serde::{de::DeserializeOwned, de::IgnoredAny, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+};
+
+const BLOCKS_LOG: &str = "blocks.log";
+const ACCOUNTS_LOG: &str = "accounts.log";
+const MEMPOOL_LOG: &str = "mempool.log";
+const METADATA_LOG: &str = "metadata.log";
+
+/// Every record starts with this, which lets a record appended after a torn write be told apart
+/// from the garbage in front of it.
+const RECORD_PREFIX: &[u8] = b"{\"key\":";
+
+const HEAD_KEY: &str = "head";
+/// Key the whole mempool was kept under before every transaction got a record of its own.
+const PENDING_KEY: &str = "pending";
+
+/// Logs smaller than this are never worth compacting.
+const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// Storage backend made of one [`KvStore`] per kind of data.
+///
+/// Blocks are appended under `number:<n>` with a `hash:<hash>` → number index and a `head`
+/// pointer, so committing a block or reading the tip costs the same no matter how long the chain
+/// is. `head` is written last, which makes it the commit marker: a crash half way through
+/// [`Storage::append_block`] leaves records that nothing points to yet. Truncating the chain
+/// moves `head` back first, so blocks after it are ignored until their records are gone or
+/// overwritten.
+///
+/// Every transaction in the mempool is a record of its own under `tx:<hash>`, the hash being
+/// that of its RLP encoding whatever the hash scheme of the chain. Adding one is a single append
+/// and removing transactions only deletes their own records, so other processes, like the HTTP
+/// server, can add transactions while the node removes the ones its blocks included.
+#[derive(Debug)]
+pub struct KvStorage {
+    blocks: KvStore,
+    accounts: KvStore,
+    mempool: KvStore,
+    metadata: KvStore,
+}
+
+impl KvStorage {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+
+        Ok(KvStorage {
+            blocks: KvStore::open(dir.join(BLOCKS_LOG))?,
+            accounts: KvStore::open(dir.join(ACCOUNTS_LOG))?,
+            mempool: KvStore::open(dir.join(MEMPOOL_LOG))?,
+            metadata: KvStore::open(dir.join(METADATA_LOG))?,
+        })
+    }
+
+    /// Rewrites every log that is mostly dead records. Only call this from the process that owns
+    /// the data directory, other processes reading the same logs will rescan on their next access.
+    pub fn compact(&self) -> Result<()> {
+        for store in [&self.blocks, &self.accounts, &self.mempool, &self.metadata] {
+            store.compact_if_needed()?;
+        }
+
+        Ok(())
+    }
+
+    fn get_head(&self) -> Result<Option<u64>> {
+        self.blocks.get(HEAD_KEY)
+    }
+}
+
+impl Storage for KvStorage {
+    fn get_blocks(&self) -> Result<Blocks> {
+        let Some(head) = self.get_head()? else {
+            return Ok(vec![]);
+        };
+        self.get_blocks_in_range(0, head)
+    }
+
+    fn append_block(&self, block: &Block) -> Result<()> {
+        let number = block.header.number;
+        self.blocks.put(&block_key(number), block)?;
+        let hash = block.get_block_hash(HashScheme::load(self)?)?;
+        self.blocks.put(&hash_key(&hash), &number)?;
+        self.blocks.put(HEAD_KEY, &number)
+    }
+
+    fn truncate_blocks(&self, number: u64) -> Result<()> {
+        let Some(head) = self.get_head()? else {
+            return Ok(());
+        };
+        if number >= head {
+            return Ok(());
+        }
+
+        let scheme = HashScheme::load(self)?;
+        let dropped = self.get_blocks_in_range(number + 1, head)?;
+        self.blocks.put(HEAD_KEY, &number)?;
+        for block in dropped {
+            self.blocks.delete(&hash_key(&block.get_block_hash(scheme)?))?;
+            self.blocks.delete(&block_key(block.header.number))?;
+        }
+
+        Ok(())
+    }
+
+    fn get_last_block(&self) -> Result<Option<Block>> {
+        match self.get_head()? {
+            Some(head) => self.get_block_by_number(head),
+            None => Ok(None),
+        }
+    }
+
+    fn get_last_n_blocks(&self, n: usize) -> Result<Blocks> {
+        let Some(head) = self.get_head()? else {
+            return Ok(vec![]);
+        };
+        if n == 0 {
+            return Ok(vec![]);
+        }
+        let start = head.saturating_sub(n as u64 - 1);
+        self.get_blocks_in_range(start, head)
+    }
+
+    fn get_block_by_number(&self, number: u64) -> Result<Option<Block>> {
+        match self.get_head()? {
+            Some(head) if number <= head => self.blocks.get(&block_key(number)),
+            _ => Ok(None),
+        }
+    }
+
+    fn get_block_by_hash(&self, hash: &B256) -> Result<Option<Block>> {
+        let Some(number) = self.blocks.get::<u64>(&hash_key(hash))? else {
+            return Ok(None);
+        };
+        // The index of a block a truncation dropped may outlive it.
+        match self.get_block_by_number(number)? {
+            Some(block) if block.get_block_hash(HashScheme::load(self)?)? == *hash => {
+                Ok(Some(block))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn get_accounts(&self) -> Result<Accounts> {
+        Ok(self.accounts.values()?.into_iter().map(|(_, account)| account).collect())
+    }
+
+    fn get_account(&self, address: &Address) -> Result<Option<Account>> {
+        self.accounts.get(&address.to_string())
+    }
+
+    fn put_account(&self, account: &Account) -> Result<()> {
+        self.accounts.put(&account.address.to_string(), account)
+    }
+
+    fn delete_account(&self, address: &Address) -> Result<()> {
+        self.accounts.delete(&address.to_string())
+    }
+
+    fn get_mempool(&self) -> Result<Mempool> {
+        Ok(self.get_mempool_records()?.into_iter().flat_map(|(_, txs)| txs).collect())
+    }
+
+    fn add_to_mempool(&self, tx: &Transaction) -> Result<()> {
+        self.mempool.put(&tx_key(&tx.get_transaction_hash(HashScheme::Rlp)), tx)
+    }
+
+    fn remove_from_mempool(&self, drop: &dyn Fn(&Transaction) -> bool) -> Result<()> {
+        for (key, txs) in self.get_mempool_records()? {
+            if !txs.iter().any(drop) {
+                continue;
+            }
+            // What is left of a mempool kept under `pending` moves to records of its own.
+            for tx in txs.iter().filter(|tx| !drop(tx)) {
+                self.add_to_mempool(tx)?;
+            }
+            self.mempool.delete(&key)?;
+        }
+
+        Ok(())
+    }
+
+    fn get_metadata(&self, key: &str) -> Result<Option<String>> {
+        self.metadata.get(key)
+    }
+
+    fn put_metadata(&self, key: &str, value: &str) -> Result<()> {
+        self.metadata.put(key, &value)
+    }
+
+    fn recover(&self) -> Result<()> {
+        for store in [&self.blocks, &self.accounts, &self.mempool, &self.metadata] {
+            store.recover()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl KvStorage {
+    /// The keys of the mempool records and the transactions they hold, in the order they were
+    /// added. A mempool still kept as one list under `pending` is a single record.
+    fn get_mempool_records(&self) -> Result<Vec<(String, Mempool)>> {
+        let mut records = Vec::new();
+        for (key, value) in self.mempool.values::<serde_json::Value>()? {
+            if key == PENDING_KEY {
+                records.push((key, serde_json::from_value(value)?));
+            } else if key.starts_with(TX_KEY_PREFIX) {
+                records.push((key, vec![serde_json::from_value(value)?]));
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Blocks `start` to `end`, which must not be past the head, read in one pass over the log.
+    fn get_blocks_in_range(&self, start: u64, end: u64) -> Result<Blocks> {
+        let keys: Vec<String> = (start..=end).map(block_key).collect();
+        self.blocks
+            .get_many(&keys)?
+            .into_iter()
+            .zip(start..=end)
+            .map(|(block, number)| {
+                block.ok_or_else(|| eyre!("block {} is missing from {}", number, BLOCKS_LOG))
+            })
+            .collect()
+    }
+}
+
+fn block_key(number: u64) -> String {
+    format!("number:{}", number)
+}
+
+fn hash_key(hash: &B256) -> String {
+    format!("hash:{}", hash)
+}
+
+const TX_KEY_PREFIX: &str = "tx:";
+
+fn tx_key(hash: &B256) -> String {
+    format!("{}{}", TX_KEY_PREFIX, hash)
+}
+
+/// An append-only, log-structured key/value file.
+///
+/// Every write appends one JSON line `{"key": .., "value": ..}` (a `null` value deletes the key)
+/// and an in-memory index maps each live key to the byte range of its latest record. Several
+/// processes may append to the same log: before every access the index picks up any records
+/// written since it was last read.
+///
+/// Appends hold a shared lock on `<log>.lock` and rewriting the log holds an exclusive one, so
+/// compaction never drops a record another process appends while it runs. Writers that cannot
+/// take the lock, like the HTTP server, append again if the log was replaced under their write,
+/// and compaction carries over whatever they appended to the old log before it was replaced.
+#[derive(Debug)]
+pub struct KvStore {
+    path: PathBuf,
+    lock_file: File,
+    state: Mutex<KvState>,
+}
+
+#[derive(Debug, Default)]
+struct KvState {
+    index: HashMap<String, Entry>,
+    /// Byte offset up to which the log has been indexed.
+    end: u64,
+    /// Bytes taken up by overwritten or deleted records.
+    dead: u64,
+    next_order: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    offset: u64,
+    len: u64,
+    /// Position of the key in first-insertion order, kept across overwrites.
+    order: u64,
+}
+
+#[derive(Serialize)]
+struct Record<'a, T> {
+    key: &'a str,
+    value: Option<&'a T>,
+}
+
+#[derive(Deserialize)]
+struct OwnedRecord<T> {
+    key: String,
+    value: Option<T>,
+}
+
+impl KvStore {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        OpenOptions::new().create(true).append(true).open(&path)?;
+        let lock_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(with_suffix(&path, ".lock"))?;
+
+        let store = KvStore { path, lock_file, state: Mutex::new(KvState::default()) };
+        store.refresh(&mut store.lock())?;
+
+        Ok(store)
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        Ok(self.get_many(&[key.to_string()])?.pop().flatten())
+    }
+
+    /// The values of `keys`, in the same order, read with a single open of the log.
+    pub fn get_many<T: DeserializeOwned>(&self, keys: &[String]) -> Result<Vec<Option<T>>> {
+        let mut state = self.lock();
+        self.refresh(&mut state)?;
+
+        let lines = match self.read_lines(&state, keys)? {
+            Some(lines) => lines,
+            // The log was rewritten underneath us by another process, start over.
+            None => {
+                *state = KvState::default();
+                self.refresh(&mut state)?;
+                self.read_lines(&state, keys)?
+                    .ok_or_else(|| eyre!("records changed while reading {}", self.path.display()))?
+            }
+        };
+
+        let mut values = Vec::with_capacity(lines.len());
+        for line in lines {
+            let value = match line {
+                Some(line) => serde_json::from_slice::<OwnedRecord<T>>(&line)?.value,
+                None => None,
+            };
+            values.push(value);
+        }
+
+        Ok(values)
+    }
+
+    pub fn put<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
+        self.append(&Record { key, value: Some(value) })
+    }
+
+    pub fn delete(&self, key: &str) -> Result<()> {
+        self.append(&Record::<()> { key, value: None })
+    }
+
+    /// Returns every live key/value pair in the order the keys were first written.
+    pub fn values<T: DeserializeOwned>(&self) -> Result<Vec<(String, T)>> {
+        let mut state = self.lock();
+        self.refresh(&mut state)?;
+
+        let mut contents = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut contents)?;
+
+        let mut entries: Vec<(&String, &Entry)> = state.index.iter().collect();
+        entries.sort_by_key(|(_, entry)| entry.order);
+
+        let mut values = Vec::with_capacity(entries.len());
+        for (key, entry) in entries {
+            let start = entry.offset as usize;
+            let line = contents
+                .get(start..start + entry.len as usize)
+                .ok_or_else(|| eyre!("{} changed while it was being read", self.path.display()))?;
+            let record: OwnedRecord<T> = serde_json::from_slice(line)?;
+            if let Some(value) = record.value {
+                values.push((key.clone(), value));
+            }
+        }
+
+        Ok(values)
+    }
+
+    fn append<T: Serialize>(&self, record: &Record<'_, T>) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut state = self.lock();
+        self.lock_file.lock_shared()?;
+        let written = OpenOptions::new().append(true).open(&self.path).and_then(|mut file| {
+            file.write_all(&line)?;
+            file.sync_data()
+        });
+        self.lock_file.unlock()?;
+        written?;
+
+        self.refresh(&mut state)
+    }
+
+    /// Cuts off a record that was only partially written when the process died and removes any
+    /// leftover compaction output. Only the process that owns the data directory should call
+    /// this, as a concurrent writer's in-flight record looks exactly like a torn one.
+    pub fn recover(&self) -> Result<()> {
+        let mut state = self.lock();
+        self.lock_file.lock()?;
+        let recovered = self.recover_locked(&mut state);
+        self.lock_file.unlock()?;
+
+        recovered
+    }
+
+    fn recover_locked(&self, state: &mut KvState) -> Result<()> {
+        let tmp_path = tmp_path(&self.path);
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)?;
+        }
+
+        let mut contents = Vec::new();
+        File::open(&self.path)?.read_to_end(&mut contents)?;
+        let complete = contents.iter().rposition(|byte| *byte == b'\n').map_or(0, |i| i + 1);
+
+        if complete < contents.len() {
+            println!(
+                "⚠️ Dropping {} bytes of a torn write at the end of {} ⚠️",
+                contents.len() - complete,
+                self.path.display()
+            );
+            let file = OpenOptions::new().write(true).open(&self.path)?;
+            file.set_len(complete as u64)?;
+            file.sync_all()?;
+        }
+
+        *state = KvState::default();
+        self.refresh(state)
+    }
+
+    fn compact_if_needed(&self) -> Result<()> {
+        let mut state = self.lock();
+        self.lock_file.lock()?;
+        let compacted = self.compact_locked(&mut state);
+        self.lock_file.unlock()?;
+
+        compacted
+    }
+
+    fn compact_locked(&self, state: &mut KvState) -> Result<()> {
+        self.refresh(state)?;
+
+        if state.end < COMPACTION_THRESHOLD || state.dead * 2 < state.end {
+            return Ok(());
+        }
+
+        let mut old = File::open(&self.path)?;
+        let mut contents = Vec::new();
+        (&old).take(state.end).read_to_end(&mut contents)?;
+
+        let mut entries: Vec<&Entry> = state.index.values().collect();
+        entries.sort_by_key(|entry| entry.order);
+
+        let mut compacted = Vec::with_capacity((state.end - state.dead) as usize);
+        for entry in entries {
+            let start = entry.offset as usize;
+            compacted.extend_from_slice(&contents[start..start + entry.len as usize]);
+        }
+
+        write_atomic(&self.path, &compacted)?;
+
+        // Writers that do not take the lock may have appended to the old log since it was read.
+        // Whatever they append to it after this is appended again by themselves, as they find the
+        // log replaced.
+        old.seek(SeekFrom::Start(state.end))?;
+        let mut tail = Vec::new();
+        old.read_to_end(&mut tail)?;
+        let complete = tail.iter().rposition(|byte| *byte == b'\n').map_or(0, |i| i + 1);
+        if complete > 0 {
+            let mut file = OpenOptions::new().append(true).open(&self.path)?;
+            file.write_all(&tail[..complete])?;
+            file.sync_data()?;
+        }
+
+        *state = KvState::default();
+        self.refresh(state)
+    }
+
+    /// Reads the raw records of `keys`, `None` for the keys that have none, or returns `None` if
+    /// any of them no longer holds its key.
+    fn read_lines(&self, state: &KvState, keys: &[String]) -> Result<Option<Vec<Option<Vec<u8>>>>> {
+        let mut file = File::open(&self.path)?;
+        let len = file.metadata()?.len();
+
+        let mut lines = Vec::with_capacity(keys.len());
+        for key in keys {
+            let Some(entry) = state.index.get(key) else {
+                lines.push(None);
+                continue;
+            };
+            if len < entry.offset + entry.len {
+                return Ok(None);
+            }
+            file.seek(SeekFrom::Start(entry.offset))?;
+            let mut line = vec![0; entry.len as usize];
+            file.read_exact(&mut line)?;
+
+            match serde_json::from_slice::<OwnedRecord<IgnoredAny>>(&line) {
+                Ok(record) if record.key == *key => lines.push(Some(line)),
+                _ => return Ok(None),
+            }
+        }
+
+        Ok(Some(lines))
+    }
+
+    /// Indexes every complete record appended since the last call.
+    fn refresh(&self, state: &mut KvState) -> Result<()> {
+        let mut file = File::open(&self.path)?;
+        let len = file.metadata()?.len();
+
+        if len < state.end {
+            // The log shrank, so it was compacted by someone else.
+            *state = KvState::default();
+        }
+        if len == state.end {
+            return Ok(());
+        }
+
+        file.seek(SeekFrom::Start(state.end))?;
+        let mut tail = Vec::with_capacity((len - state.end) as usize);
+        file.read_to_end(&mut tail)?;
+
+        let mut offset = state.end;
+        for line in tail.split_inclusive(|byte| *byte == b'\n') {
+            if line.last() != Some(&b'\n') {
+                // A record that is still being written, pick it up on the next refresh.
+                break;
+            }
+
+            let (skip, record) = parse_line(line).ok_or_else(|| {
+                eyre!("corrupt record at byte {} of {}", offset, self.path.display())
+            })?;
+            // Whatever precedes the record is the remains of a torn write.
+            state.dead += skip as u64;
+            let record_offset = offset + skip as u64;
+            let record_len = (line.len() - skip) as u64;
+
+            let previous = state.index.remove(&record.key);
+            if let Some(previous) = previous {
+                state.dead += previous.len;
+            }
+
+            if record.value.is_some() {
+                let order = match previous {
+                    Some(previous) => previous.order,
+                    None => {
+                        state.next_order += 1;
+                        state.next_order
+                    }
+                };
+                state
+                    .index
+                    .insert(record.key, Entry { offset: record_offset, len: record_len, order });
+            } else {
+                state.dead += record_len;
+            }
+
+            offset += line.len() as u64;
+        }
+        state.end = offset;
+
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, KvState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Parses one log line, returning how many leading bytes had to be skipped to find the record.
+///
+/// A process that dies mid-append leaves a fragment without a trailing newline, and the next
+/// append lands right after it. The record itself is intact, so rather than rejecting the whole
+/// line we fall back to the last record prefix in it.
+fn parse_line(line: &[u8]) -> Option<(usize, OwnedRecord<IgnoredAny>)> {
+    if let Ok(record) = serde_json::from_slice(line) {
+        return Some((0, record));
+    }
+
+    let skip = line.windows(RECORD_PREFIX.len()).rposition(|window| window == RECORD_PREFIX)?;
+    let record = serde_json::from_slice(&line[skip..]).ok()?;
+
+    Some((skip, record))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{chainspec::ChainSpec, storage::tests::TempDir};
+
+    fn log(dir: &TempDir) -> PathBuf {
+        dir.path().join("test.log")
+    }
+
+    fn append_raw(path: &Path, bytes: &[u8]) {
+        OpenOptions::new().append(true).open(path).unwrap().write_all(bytes).unwrap();
+    }
+
+    #[test]
+    fn replays_the_log_on_open() {
+        let dir = TempDir::new("kv-replay");
+        let store = KvStore::open(log(&dir)).unwrap();
+        store.put("a", &1u64).unwrap();
+        store.put("b", &2u64).unwrap();
+        store.put("c", &3u64).unwrap();
+        store.put("a", &4u64).unwrap();
+        store.delete("b").unwrap();
+        drop(store);
+
+        let store = KvStore::open(log(&dir)).unwrap();
+        assert_eq!(store.get::<u64>("a").unwrap(), Some(4));
+        assert_eq!(store.get::<u64>("b").unwrap(), None);
+        let values = store.values::<u64>().unwrap();
+        assert_eq!(values, vec![("a".to_string(), 4), ("c".to_string(), 3)]);
+    }
+
+    #[test]
+    fn sees_records_appended_by_another_handle() {
+        let dir = TempDir::new("kv-shared");
+        let reader = KvStore::open(log(&dir)).unwrap();
+        let writer = KvStore::open(log(&dir)).unwrap();
+
+        writer.put("a", &1u64).unwrap();
+        assert_eq!(reader.get::<u64>("a").unwrap(), Some(1));
+        writer.delete("a").unwrap();
+        assert_eq!(reader.get::<u64>("a").unwrap(), None);
+    }
+
+    #[test]
+    fn skips_a_torn_write_in_front_of_a_record() {
+        let dir = TempDir::new("kv-torn-line");
+        let store = KvStore::open(log(&dir)).unwrap();
+        store.put("a", &1u64).unwrap();
+        drop(store);
+
+        // A writer died half way through a record and the next one was appended right after it.
+        append_raw(&log(&dir), br#"{"key":"b","val"#);
+        let store = KvStore::open(log(&dir)).unwrap();
+        store.put("c", &3u64).unwrap();
+        drop(store);
+
+        let store = KvStore::open(log(&dir)).unwrap();
+        assert_eq!(store.get::<u64>("a").unwrap(), Some(1));
+        assert_eq!(store.get::<u64>("b").unwrap(), None);
+        assert_eq!(store.get::<u64>("c").unwrap(), Some(3));
+    }
+
+    #[test]
+    fn waits_for_a_record_that_is_still_being_written() {
+        let dir = TempDir::new("kv-partial");
+        let store = KvStore::open(log(&dir)).unwrap();
+        store.put("a", &1u64).unwrap();
+
+        append_raw(&log(&dir), br#"{"key":"b","value":"#);
+        assert_eq!(store.get::<u64>("b").unwrap(), None);
+        append_raw(&log(&dir), b"2}\n");
+        assert_eq!(store.get::<u64>("b").unwrap(), Some(2));
+    }
+
+    #[test]
+    fn recover_drops_a_torn_tail() {
+        let dir = TempDir::new("kv-recover");
+        let store = KvStore::open(log(&dir)).unwrap();
+        store.put("a", &1u64).unwrap();
+        let len = fs::metadata(log(&dir)).unwrap().len();
+
+        append_raw(&log(&dir), br#"{"key":"b","value":2"#);
+        store.recover().unwrap();
+        assert_eq!(fs::metadata(log(&dir)).unwrap().len(), len);
+
+        store.put("c", &3u64).unwrap();
+        let store = KvStore::open(log(&dir)).unwrap();
+        assert_eq!(store.get::<u64>("a").unwrap(), Some(1));
+        assert_eq!(store.get::<u64>("b").unwrap(), None);
+        assert_eq!(store.get::<u64>("c").unwrap(), Some(3));
+    }
+
+    #[test]
+    fn compaction_keeps_live_records_and_later_appends_of_other_handles() {
+        let dir = TempDir::new("kv-compact");
+        let store = KvStore::open(log(&dir)).unwrap();
+        let other = KvStore::open(log(&dir)).unwrap();
+        let filler = "x".repeat(1024);
+        for _ in 0..COMPACTION_THRESHOLD / 1024 {
+            store.put("filler", &filler).unwrap();
+        }
+        other.put("kept", &1u64).unwrap();
+        store.delete("filler").unwrap();
+
+        store.compact_if_needed().unwrap();
+        assert!(fs::metadata(log(&dir)).unwrap().len() < 1024);
+        assert!(with_suffix(&log(&dir), ".lock").exists());
+
+        other.put("after", &2u64).unwrap();
+        assert_eq!(store.get::<u64>("kept").unwrap(), Some(1));
+        assert_eq!(store.get::<u64>("after").unwrap(), Some(2));
+        assert_eq!(store.get::<String>("filler").unwrap(), None);
+        let keys = ["kept".to_string(), "missing".to_string(), "after".to_string()];
+        assert_eq!(store.get_many::<u64>(&keys).unwrap(), [Some(1), None, Some(2)]);
+    }
+
+    #[test]
+    fn truncating_blocks_hides_them_even_if_it_stops_half_way() {
+        let dir = TempDir::new("kv-truncate");
+        let storage = KvStorage::open(dir.path()).unwrap();
+        let spec = ChainSpec::default();
+        spec.store(&storage).unwrap();
+        let scheme = spec.hash_scheme;
+
+        let genesis = Block::genesis(&spec);
+        let child = |timestamp: u64| {
+            let mut block = genesis.clone();
+            block.header.number = 1;
+            block.header.timestamp = timestamp;
+            block
+        };
+        let (first, second) = (child(1), child(2));
+        storage.append_block(&genesis).unwrap();
+        storage.append_block(&first).unwrap();
+
+        storage.truncate_blocks(0).unwrap();
+        assert_eq!(storage.get_blocks().unwrap().len(), 1);
+        assert!(storage.get_block_by_number(1).unwrap().is_none());
+        let first_hash = first.get_block_hash(scheme).unwrap();
+        assert!(storage.get_block_by_hash(&first_hash).unwrap().is_none());
+
+        // Moving the head back is all that has to reach the log for the blocks after it to be
+        // gone, whatever records of them are left.
+        storage.append_block(&first).unwrap();
+        storage.blocks.put(HEAD_KEY, &0u64).unwrap();
+        assert!(storage.get_block_by_number(1).unwrap().is_none());
+        assert!(storage.get_block_by_hash(&first_hash).unwrap().is_none());
+
+        storage.append_block(&second).unwrap();
+        assert!(storage.get_block_by_hash(&first_hash).unwrap().is_none());
+        let second_hash = second.get_block_hash(scheme).unwrap();
+        assert_eq!(storage.get_last_block().unwrap().unwrap().header.timestamp, 2);
+        assert!(storage.get_block_by_hash(&second_hash).unwrap().is_some());
+    }
+}
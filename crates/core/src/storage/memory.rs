@@ -0,0 +1,112 @@
+use crate::{
+    account::{Account, Accounts},
+    block::{Block, Blocks},
+    mempool::Mempool,
+    storage::Storage,
+    transaction::Transaction,
+};
+use eyre::Result;
+use helper::Address;
+use std::{
+    collections::HashMap,
+    sync::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+/// Keeps everything in process memory. Nothing survives a restart, which makes it a good fit
+/// for tests and for running several nodes inside one process.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    blocks: Blocks,
+    accounts: Accounts,
+    mempool: Mempool,
+    metadata: HashMap<String, String>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Inner> {
+        self.inner.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Inner> {
+        self.inner.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get_blocks(&self) -> Result<Blocks> {
+        Ok(self.read().blocks.clone())
+    }
+
+    fn append_block(&self, block: &Block) -> Result<()> {
+        self.write().blocks.push(block.clone());
+        Ok(())
+    }
+
+    fn truncate_blocks(&self, number: u64) -> Result<()> {
+        self.write().blocks.retain(|block| block.header.number <= number);
+        Ok(())
+    }
+
+    fn get_last_block(&self) -> Result<Option<Block>> {
+        Ok(self.read().blocks.last().cloned())
+    }
+
+    fn get_last_n_blocks(&self, n: usize) -> Result<Blocks> {
+        let inner = self.read();
+        let start_index = inner.blocks.len().saturating_sub(n);
+        Ok(inner.blocks[start_index..].to_vec())
+    }
+
+    fn get_accounts(&self) -> Result<Accounts> {
+        Ok(self.read().accounts.clone())
+    }
+
+    fn put_account(&self, account: &Account) -> Result<()> {
+        let accounts = &mut self.write().accounts;
+
+        if let Some(index) = accounts.iter().position(|acc| acc.address == account.address) {
+            accounts[index] = account.clone();
+        } else {
+            accounts.push(account.clone());
+        }
+
+        Ok(())
+    }
+
+    fn delete_account(&self, address: &Address) -> Result<()> {
+        self.write().accounts.retain(|acc| acc.address != *address);
+        Ok(())
+    }
+
+    fn get_mempool(&self) -> Result<Mempool> {
+        Ok(self.read().mempool.clone())
+    }
+
+    fn add_to_mempool(&self, tx: &Transaction) -> Result<()> {
+        self.write().mempool.push(tx.clone());
+        Ok(())
+    }
+
+    fn remove_from_mempool(&self, drop: &dyn Fn(&Transaction) -> bool) -> Result<()> {
+        self.write().mempool.retain(|tx| !drop(tx));
+        Ok(())
+    }
+
+    fn get_metadata(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.read().metadata.get(key).cloned())
+    }
+
+    fn put_metadata(&self, key: &str, value: &str) -> Result<()> {
+        self.write().metadata.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+}
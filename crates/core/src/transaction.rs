@@ -1,122 +1,521 @@
-use crate::account::get_account_by_address;
+use crate::chainspec::HashScheme;
 use alloy_rlp::{RlpDecodable, RlpEncodable};
-use base16ct::lower::encode_string;
 use ethers::{
     core::types::{transaction::eip2718::TypedTransaction, TransactionRequest},
     signers::{LocalWallet, Signer},
-    types::Address,
+    types::{H160, H256, U256},
 };
-use eyre::Result;
+use eyre::{bail, Result};
+use helper::{keccak256, Address, Signature, B256};
 use serde::{Deserialize, Serialize};
-use sha3::{Digest, Keccak256};
 use std::str::FromStr;
 
+/// Order of the secp256k1 curve.
+const SECP256K1_N: U256 =
+    U256([0xbfd25e8cd0364141, 0xbaaedce6af48a03b, 0xfffffffffffffffe, 0xffffffffffffffff]);
+
+/// Half the order of the secp256k1 curve, the largest `s` a signature may have.
+const SECP256K1_HALF_N: U256 =
+    U256([0xdfe92f46681b20a0, 0x5d576e7357a4501d, 0xffffffffffffffff, 0x7fffffffffffffff]);
+
+/// Address that transactions staking their `value` are sent to, `stake` in ASCII.
+pub const STAKE_ADDRESS: Address =
+    Address::new([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, b's', b't', b'a', b'k', b'e']);
+
+/// Address that transactions unstaking their `value` are sent to, `unstake` in ASCII.
+pub const UNSTAKE_ADDRESS: Address =
+    Address::new([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, b'u', b'n', b's', b't', b'a', b'k', b'e']);
+
 pub type Transactions = Vec<Transaction>;
 
+/// What a transaction does, told apart by its receiver so that every kind is signed the same way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionKind {
+    /// Moves `value` from the sender to the receiver.
+    Transfer,
+    /// Moves `value` from the balance of the sender to its stake. Sent to [`STAKE_ADDRESS`].
+    Stake,
+    /// Moves `value` from the stake of the sender back to its balance. Sent to
+    /// [`UNSTAKE_ADDRESS`].
+    Unstake,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, RlpEncodable, RlpDecodable)]
 pub struct Transaction {
-    pub sender: String,
-    pub receiver: String,
+    pub sender: Address,
+    pub receiver: Address,
     pub value: u64,
+    /// Paid by the sender on top of `value` to the miner of the block that includes the
+    /// transaction. Left out of the JSON form when zero, which the legacy block hash is taken
+    /// over, so that blocks of transactions without a fee keep the hashes they had before fees.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub fee: u64,
     pub nonce: u64,
-    pub v: String,
-    pub r: String,
-    pub s: String,
+    #[serde(flatten)]
+    pub signature: Signature,
 }
 
 impl Transaction {
-    pub async fn new(from: String, to: String, value: u64, pk: String) -> Result<Self> {
-        let nonce = get_account_by_address(&from)?.nonce;
-        let (v, r, s) = sign_transaction(&from, &to, value, &pk).await?;
+    /// Signs a transfer for the chain `chain_id` with the sender's private key `pk`. Meant to be
+    /// called by clients, the node itself only ever handles transactions that are already signed.
+    pub async fn new(
+        from: Address,
+        to: Address,
+        value: u64,
+        fee: u64,
+        nonce: u64,
+        chain_id: u64,
+        pk: &str,
+    ) -> Result<Self> {
+        let signature = sign_transaction(&from, &to, value, fee, nonce, chain_id, pk).await?;
+
+        Ok(Transaction { sender: from, receiver: to, value, fee, nonce, signature })
+    }
+
+    pub fn kind(&self) -> TransactionKind {
+        match self.receiver {
+            STAKE_ADDRESS => TransactionKind::Stake,
+            UNSTAKE_ADDRESS => TransactionKind::Unstake,
+            _ => TransactionKind::Transfer,
+        }
+    }
+
+    /// Recovers the address that signed this transfer from its signature.
+    ///
+    /// Only canonical signatures are accepted: `v` must carry an EIP-155 chain id and `s` must be
+    /// in the lower half of the curve order as required by EIP-2, so that no other encoding of
+    /// the same signature gives another hash.
+    pub fn recover_sender(&self) -> Result<Address> {
+        let signature = self.checked_signature()?;
+
+        let tx = legacy_transaction(
+            &self.sender,
+            &self.receiver,
+            self.value,
+            self.fee,
+            self.nonce,
+            self.chain_id()?,
+        );
+        Ok(signature.recover(tx.sighash())?.into())
+    }
+
+    /// The chain id the transfer was signed for, taken from its EIP-155 `v`.
+    pub fn chain_id(&self) -> Result<u64> {
+        let v = self.signature.v;
+        if v < 35 {
+            bail!("v {} is not an EIP-155 signature", v);
+        }
 
-        Ok(Transaction { sender: from, receiver: to, value, nonce, v, r, s })
+        Ok((v - 35) / 2)
     }
 
-    pub fn get_transaction_hash(&self) -> Result<String> {
-        let hash = Keccak256::new()
-            .chain_update(self.sender.clone())
-            .chain_update(self.receiver.clone())
-            .chain_update(self.value.to_string())
-            .chain_update(self.nonce.to_string())
-            .chain_update(self.v.clone())
-            .chain_update(self.r.clone())
-            .chain_update(self.s.clone())
-            .finalize();
-        let hash_hex = encode_string(&hash);
-        Ok(hash_hex)
+    /// Checks that the transfer carries a canonical signature by its `sender` for the chain
+    /// `chain_id`.
+    pub fn verify(&self, chain_id: u64) -> Result<()> {
+        let tx_chain_id = self.chain_id()?;
+        if tx_chain_id != chain_id {
+            bail!("transaction is signed for chain {} instead of chain {}", tx_chain_id, chain_id);
+        }
+
+        let signer = self.recover_sender()?;
+        if signer != self.sender {
+            bail!("transaction from {} is signed by {}", self.sender, signer);
+        }
+
+        Ok(())
+    }
+
+    fn checked_signature(&self) -> Result<ethers::types::Signature> {
+        self.chain_id()?;
+        let signature = ethers::types::Signature::from(self.signature);
+        check_r_and_s(&signature)?;
+
+        Ok(signature)
+    }
+
+    pub fn get_transaction_hash(&self, scheme: HashScheme) -> B256 {
+        match scheme {
+            HashScheme::Legacy => {
+                let mut data = vec![
+                    self.sender.to_string(),
+                    self.receiver.to_string(),
+                    self.value.to_string(),
+                    self.nonce.to_string(),
+                    self.signature.v.to_string(),
+                    self.signature.r_u256().to_string(),
+                    self.signature.s_u256().to_string(),
+                ];
+                // Transactions without a fee keep the hashes they had before there were fees.
+                if self.fee > 0 {
+                    data.push(self.fee.to_string());
+                }
+                keccak256(data.concat())
+            }
+            HashScheme::Rlp => keccak256(alloy_rlp::encode(self)),
+        }
     }
 }
 
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+/// Checks that `signature` is a signature by `signer` over `hash`, for the messages validators
+/// sign directly, such as seals and votes. Like for transactions, `r` must be in range and `s` in
+/// the lower half of the curve order, and `v` must be 27 or 28, so that nobody but the signer can
+/// turn a valid signature into another one.
+pub fn verify_hash_signature(signature: Signature, hash: B256, signer: Address) -> Result<()> {
+    if signature.v != 27 && signature.v != 28 {
+        bail!("v is {}, not 27 or 28", signature.v);
+    }
+    let signature = ethers::types::Signature::from(signature);
+    check_r_and_s(&signature)?;
+    signature.verify(H256::from(hash), signer)?;
+
+    Ok(())
+}
+
+fn check_r_and_s(signature: &ethers::types::Signature) -> Result<()> {
+    if signature.r.is_zero() || signature.r >= SECP256K1_N {
+        bail!("r is out of range");
+    }
+    if signature.s.is_zero() || signature.s > SECP256K1_HALF_N {
+        bail!("s is not in the lower half of the curve order");
+    }
+
+    Ok(())
+}
+
 pub async fn sign_transaction(
-    from: &str,
-    to: &str,
+    from: &Address,
+    to: &Address,
     value: u64,
+    fee: u64,
+    nonce: u64,
+    chain_id: u64,
     pk: &str,
-) -> Result<(String, String, String)> {
-    let nonce = get_account_by_address(from)?.nonce;
-    let from = Address::from_str(from)?;
-    let to = Address::from_str(to)?;
+) -> Result<Signature> {
     let wallet = LocalWallet::from_str(pk)?;
+    if Address::from(wallet.address()) != *from {
+        bail!("the private key does not belong to {}", from);
+    }
 
-    let tx = TypedTransaction::Legacy(
-        TransactionRequest::new().from(from).to(to).value(value).nonce(nonce),
-    );
+    let tx = legacy_transaction(from, to, value, fee, nonce, chain_id);
     let signature = wallet.sign_transaction(&tx).await?;
 
-    let v = signature.v.to_string();
-    let r = signature.r.to_string();
-    let s = signature.s.to_string();
+    Ok(signature.into())
+}
 
-    Ok((v, r, s))
+/// The payload a transfer is signed over. The chain id makes it an EIP-155 transaction. The fee
+/// is signed as its gas price, which encodes a fee of zero the same way as no gas price at all,
+/// so transactions signed before there were fees stay valid.
+fn legacy_transaction(
+    from: &Address,
+    to: &Address,
+    value: u64,
+    fee: u64,
+    nonce: u64,
+    chain_id: u64,
+) -> TypedTransaction {
+    TypedTransaction::Legacy(
+        TransactionRequest::new()
+            .from(H160::from(*from))
+            .to(H160::from(*to))
+            .value(value)
+            .gas_price(fee)
+            .nonce(nonce)
+            .chain_id(chain_id),
+    )
+}
+
+pub fn get_transactions_root(txs: &Transactions, scheme: HashScheme) -> B256 {
+    TransactionsTree::new(get_transaction_hashes(txs, scheme), scheme).root()
 }
 
-pub fn get_transactions_root(txs: &mut Transactions) -> Result<String> {
-    if txs.is_empty() {
-        let mut hasher = Keccak256::new();
-        hasher.update(String::default());
-        let hash = hasher.finalize();
-        let root = format!("0x{}", encode_string(&hash));
-        return Ok(root);
+/// Proves that the transaction at `index` of `txs` is part of their `transactions_root`.
+pub fn get_transaction_proof(
+    txs: &Transactions,
+    index: usize,
+    scheme: HashScheme,
+) -> Result<TransactionProof> {
+    TransactionsTree::new(get_transaction_hashes(txs, scheme), scheme).prove(index)
+}
+
+/// Checks that `proof` places the transaction with hash `tx_hash` under `root`, the
+/// `transactions_root` of a block header hashed with `scheme`. Needs nothing but the header.
+pub fn verify_tx_proof(
+    root: &B256,
+    tx_hash: &B256,
+    proof: &TransactionProof,
+    scheme: HashScheme,
+) -> Result<()> {
+    if proof.index >= proof.count {
+        bail!("index {} is out of range for {} transactions", proof.index, proof.count);
+    }
+
+    let mut hash = hash_leaf(tx_hash, scheme);
+    let mut index = proof.index;
+    let mut width = proof.count;
+    let mut siblings = proof.siblings.iter();
+    // A legacy tree of one transaction hashes it with itself.
+    let mut single = scheme == HashScheme::Legacy && width == 1;
+
+    while width > 1 || single {
+        single = false;
+        let Some(sibling) = siblings.next() else {
+            bail!("proof is too short for {} transactions", proof.count);
+        };
+        // The last node of a layer with an odd number of nodes is paired with itself.
+        if index == width - 1 && index.is_multiple_of(2) && *sibling != hash {
+            bail!("proof does not repeat the last node of a layer");
+        }
+        hash = if index.is_multiple_of(2) {
+            hash_pair(&hash, sibling, scheme)
+        } else {
+            hash_pair(sibling, &hash, scheme)
+        };
+        index /= 2;
+        width = width.div_ceil(2);
+    }
+    if siblings.next().is_some() {
+        bail!("proof is too long for {} transactions", proof.count);
     }
 
-    if txs.len() % 2 != 0 {
-        txs.push(txs[txs.len() - 1].clone());
+    if seal_root(proof.count, hash, scheme) != *root {
+        bail!("transaction {} is not at index {} under root {}", tx_hash, proof.index, root);
     }
 
-    let txs_hashes: Vec<String> = txs.iter().map(|tx| tx.get_transaction_hash().unwrap()).collect();
+    Ok(())
+}
+
+pub fn construct_root(leaves: Vec<B256>, scheme: HashScheme) -> B256 {
+    TransactionsTree::new(leaves, scheme).root()
+}
 
-    let root = format!("0x{}", construct_root(txs_hashes)?);
+/// Binary Keccak tree over transaction hashes. Every layer with an odd number of nodes repeats
+/// its last node.
+///
+/// Under [`HashScheme::Rlp`] leaves are hashed with a `0x00` prefix and inner nodes with `0x01`,
+/// so that no inner node can pass for a transaction, and the root is the hash of `0x02`, the
+/// number of transactions as a big-endian `u64` and the top node, zero if there are none. A
+/// proof therefore only holds for the exact number of transactions of the block. Legacy trees
+/// hash the hex digits of two children, pair a single transaction with itself and have the
+/// Keccak hash of the empty string as the root of no transactions.
+#[derive(Debug, Clone)]
+pub struct TransactionsTree {
+    layers: Vec<Vec<B256>>,
+    len: usize,
+    scheme: HashScheme,
+}
 
-    Ok(root)
+/// Hashes of the nodes next to a transaction's path, from its leaf up to the root.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionProof {
+    pub index: usize,
+    /// Number of transactions in the block.
+    pub count: usize,
+    pub siblings: Vec<B256>,
 }
 
-pub fn construct_root(leaves: Vec<String>) -> Result<String> {
-    let mut root = String::new();
-    let mut leaves = leaves.clone();
+impl TransactionsTree {
+    pub fn new(leaves: Vec<B256>, scheme: HashScheme) -> Self {
+        let len = leaves.len();
+        let mut layers = vec![];
+        let mut nodes: Vec<B256> = leaves.iter().map(|leaf| hash_leaf(leaf, scheme)).collect();
+        if scheme == HashScheme::Legacy && nodes.len() == 1 {
+            nodes.push(nodes[0]);
+        }
 
-    while leaves.len() > 1 {
-        let mut nodes = leaves.clone();
-        let mut parent_nodes = Vec::<String>::new();
-        for i in 0..nodes.len() / 2 {
-            let index = 2 * i;
-            let left = nodes[index].clone();
-            let right = nodes[index + 1].clone();
-            let hash = Keccak256::new().chain_update(left).chain_update(right).finalize();
-            parent_nodes.push(encode_string(&hash));
+        while nodes.len() > 1 {
+            if !nodes.len().is_multiple_of(2) {
+                nodes.push(nodes[nodes.len() - 1]);
+            }
+            let parent_nodes = nodes
+                .chunks(2)
+                .map(|pair| hash_pair(&pair[0], &pair[1], scheme))
+                .collect::<Vec<_>>();
+            layers.push(nodes);
+            nodes = parent_nodes;
         }
+        layers.push(nodes);
 
-        nodes = parent_nodes;
-        if nodes.len() == 1 {
-            root.clone_from(&nodes[0]);
-            break;
+        TransactionsTree { layers, len, scheme }
+    }
+
+    pub fn root(&self) -> B256 {
+        let top = self.layers.last().and_then(|layer| layer.first()).copied();
+        match (top, self.scheme) {
+            (Some(top), scheme) => seal_root(self.len, top, scheme),
+            (None, HashScheme::Legacy) => keccak256(""),
+            (None, HashScheme::Rlp) => seal_root(0, B256::ZERO, HashScheme::Rlp),
         }
+    }
 
-        if nodes.len() % 2 != 0 {
-            nodes.push(nodes[nodes.len() - 1].clone());
+    pub fn prove(&self, index: usize) -> Result<TransactionProof> {
+        if index >= self.len {
+            bail!("no transaction at index {} of {}", index, self.len);
         }
 
-        leaves = nodes;
+        let mut siblings = vec![];
+        let mut position = index;
+        for layer in &self.layers[..self.layers.len() - 1] {
+            siblings.push(layer[position ^ 1]);
+            position /= 2;
+        }
+
+        Ok(TransactionProof { index, count: self.len, siblings })
+    }
+}
+
+fn get_transaction_hashes(txs: &Transactions, scheme: HashScheme) -> Vec<B256> {
+    txs.iter().map(|tx| tx.get_transaction_hash(scheme)).collect()
+}
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+const ROOT_PREFIX: u8 = 0x02;
+
+fn hash_leaf(tx_hash: &B256, scheme: HashScheme) -> B256 {
+    match scheme {
+        HashScheme::Legacy => *tx_hash,
+        HashScheme::Rlp => keccak256([&[LEAF_PREFIX], tx_hash.as_bytes().as_slice()].concat()),
     }
+}
 
-    Ok(root)
+fn hash_pair(left: &B256, right: &B256, scheme: HashScheme) -> B256 {
+    match scheme {
+        HashScheme::Legacy => keccak256([left.to_hex(), right.to_hex()].concat()),
+        HashScheme::Rlp => keccak256(
+            [&[NODE_PREFIX], left.as_bytes().as_slice(), right.as_bytes().as_slice()].concat(),
+        ),
+    }
+}
+
+/// The root of a tree of `count` transactions with `top` as its top node.
+fn seal_root(count: usize, top: B256, scheme: HashScheme) -> B256 {
+    match scheme {
+        HashScheme::Legacy => top,
+        HashScheme::Rlp => keccak256(
+            [&[ROOT_PREFIX], (count as u64).to_be_bytes().as_slice(), top.as_bytes().as_slice()]
+                .concat(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80";
+    const CHAIN_ID: u64 = 1337;
+
+    fn sender() -> Address {
+        Address::from(LocalWallet::from_str(KEY).unwrap().address())
+    }
+
+    async fn transfer(value: u64, nonce: u64) -> Transaction {
+        let receiver = Address::new([7; 20]);
+        Transaction::new(sender(), receiver, value, 1, nonce, CHAIN_ID, KEY).await.unwrap()
+    }
+
+    /// The other signature over the same hash, with `s` mirrored into the upper half of the curve
+    /// order and the parity in `v` flipped to match.
+    fn malleate(signature: Signature, v: u64) -> Signature {
+        let mut mirrored = ethers::types::Signature::from(signature);
+        mirrored.s = SECP256K1_N - mirrored.s;
+        mirrored.v = v;
+        Signature::from(mirrored)
+    }
+
+    #[tokio::test]
+    async fn accepts_the_signatures_it_makes() {
+        let tx = transfer(5, 0).await;
+
+        assert_eq!(tx.chain_id().unwrap(), CHAIN_ID);
+        tx.verify(CHAIN_ID).unwrap();
+        assert!(tx.verify(CHAIN_ID + 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_transactions() {
+        let mut tx = transfer(5, 0).await;
+        tx.value = 6;
+
+        assert!(tx.verify(CHAIN_ID).is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_high_s_transaction_signatures() {
+        let mut tx = transfer(5, 0).await;
+        let flipped_v =
+            if (tx.signature.v - 35) % 2 == 0 { tx.signature.v + 1 } else { tx.signature.v - 1 };
+        tx.signature = malleate(tx.signature, flipped_v);
+
+        let err = tx.verify(CHAIN_ID).unwrap_err();
+        assert!(err.to_string().contains("lower half"), "{}", err);
+    }
+
+    #[test]
+    fn hash_signatures_must_be_canonical() {
+        let wallet = LocalWallet::from_str(KEY).unwrap();
+        let hash = keccak256("message");
+        let signature = Signature::from(wallet.sign_hash(H256::from(hash)).unwrap());
+
+        verify_hash_signature(signature, hash, sender()).unwrap();
+        assert!(verify_hash_signature(signature, keccak256("other"), sender()).is_err());
+        assert!(verify_hash_signature(signature, hash, Address::new([7; 20])).is_err());
+
+        let raw_v = Signature { v: signature.v - 27, ..signature };
+        let err = verify_hash_signature(raw_v, hash, sender()).unwrap_err();
+        assert!(err.to_string().contains("not 27 or 28"), "{}", err);
+
+        let high_s = malleate(signature, 55 - signature.v);
+        let err = verify_hash_signature(high_s, hash, sender()).unwrap_err();
+        assert!(err.to_string().contains("lower half"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn proves_every_transaction_under_both_schemes() {
+        let mut txs = vec![];
+        for nonce in 0..7 {
+            txs.push(transfer(5, nonce).await);
+        }
+
+        for scheme in [HashScheme::Legacy, HashScheme::Rlp] {
+            for count in 0..=txs.len() {
+                let txs = txs[..count].to_vec();
+                let root = get_transactions_root(&txs, scheme);
+                for (index, tx) in txs.iter().enumerate() {
+                    let hash = tx.get_transaction_hash(scheme);
+                    let proof = get_transaction_proof(&txs, index, scheme).unwrap();
+                    verify_tx_proof(&root, &hash, &proof, scheme).unwrap();
+
+                    if count > 1 {
+                        let moved =
+                            TransactionProof { index: (index + 1) % count, ..proof.clone() };
+                        assert!(verify_tx_proof(&root, &hash, &moved, scheme).is_err());
+                    }
+                    let out_of_range = TransactionProof { index: count, ..proof.clone() };
+                    assert!(verify_tx_proof(&root, &hash, &out_of_range, scheme).is_err());
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rlp_roots_commit_to_the_number_of_transactions() {
+        let tx = transfer(5, 0).await;
+        let hash = tx.get_transaction_hash(HashScheme::Rlp);
+        let one = vec![tx.clone()];
+        let two = vec![tx.clone(), tx];
+
+        // Two equal transactions make a tree whose halves are the tree of one, which only the
+        // count in the root tells apart.
+        let root = get_transactions_root(&one, HashScheme::Rlp);
+        assert_ne!(get_transactions_root(&two, HashScheme::Rlp), root);
+        let proof = get_transaction_proof(&two, 1, HashScheme::Rlp).unwrap();
+        assert!(verify_tx_proof(&root, &hash, &proof, HashScheme::Rlp).is_err());
+        let proof = TransactionProof { count: 1, ..proof };
+        assert!(verify_tx_proof(&root, &hash, &proof, HashScheme::Rlp).is_err());
+    }
 }
@@ -0,0 +1,413 @@
+use helper::B256;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+use std::sync::Arc;
+
+const LEAF_PREFIX: u8 = 0x00;
+const BRANCH_PREFIX: u8 = 0x01;
+
+/// A sparse Merkle tree over 256-bit keys, with every leaf hoisted to the shallowest depth
+/// where its key is unique.
+///
+/// The root only depends on the set of key/value pairs, not on the order they were inserted in.
+/// Nodes are shared between clones, so cloning the tree is cheap and an update only rehashes
+/// the path from the changed leaf up to the root.
+#[derive(Debug, Clone, Default)]
+pub struct SparseMerkleTree {
+    root: Arc<Node>,
+}
+
+#[derive(Debug, Default)]
+enum Node {
+    #[default]
+    Empty,
+    Leaf {
+        key: B256,
+        value: B256,
+        hash: B256,
+    },
+    Branch {
+        left: Arc<Node>,
+        right: Arc<Node>,
+        hash: B256,
+    },
+}
+
+impl SparseMerkleTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn root(&self) -> B256 {
+        self.root.hash()
+    }
+
+    pub fn get(&self, key: B256) -> Option<B256> {
+        let mut node = &self.root;
+        let mut depth = 0;
+
+        loop {
+            match node.as_ref() {
+                Node::Empty => return None,
+                Node::Leaf { key: leaf_key, value, .. } => {
+                    return (*leaf_key == key).then_some(*value)
+                }
+                Node::Branch { left, right, .. } => {
+                    node = if bit(key, depth) { right } else { left };
+                    depth += 1;
+                }
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: B256, value: B256) {
+        self.root = insert(&self.root, 0, key, value);
+    }
+
+    /// Builds a proof of the value at `key`, or of its absence if the key was never inserted.
+    pub fn prove(&self, key: B256) -> MerkleProof {
+        let mut node = &self.root;
+        let mut siblings = vec![];
+
+        loop {
+            match node.as_ref() {
+                Node::Empty => return MerkleProof { siblings, leaf: None },
+                Node::Leaf { key, value, .. } => {
+                    return MerkleProof {
+                        siblings,
+                        leaf: Some(ProofLeaf { key: *key, value: *value }),
+                    }
+                }
+                Node::Branch { left, right, .. } => {
+                    let (next, sibling) =
+                        if bit(key, siblings.len()) { (right, left) } else { (left, right) };
+                    siblings.push(sibling.hash());
+                    node = next;
+                }
+            }
+        }
+    }
+}
+
+/// A node of a [`SparseMerkleTree`] as it is persisted, with its children referenced by hash.
+/// Empty subtrees hash to zero and are never stored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StoredNode {
+    Leaf { key: B256, value: B256 },
+    Branch { left: B256, right: B256 },
+}
+
+impl StoredNode {
+    pub fn hash(&self) -> B256 {
+        match self {
+            StoredNode::Leaf { key, value } => hash_leaf(*key, *value),
+            StoredNode::Branch { left, right } => hash_branch(*left, *right),
+        }
+    }
+}
+
+/// Builds the proof of `key` in the tree with root `root` from persisted nodes. `load` returns
+/// the node with the given hash.
+pub fn prove_stored<E>(
+    root: B256,
+    key: B256,
+    mut load: impl FnMut(B256) -> Result<StoredNode, E>,
+) -> Result<MerkleProof, E> {
+    let mut hash = root;
+    let mut siblings = vec![];
+
+    loop {
+        if hash == B256::ZERO {
+            return Ok(MerkleProof { siblings, leaf: None });
+        }
+        match load(hash)? {
+            StoredNode::Leaf { key, value } => {
+                return Ok(MerkleProof { siblings, leaf: Some(ProofLeaf { key, value }) })
+            }
+            StoredNode::Branch { left, right } => {
+                let (next, sibling) =
+                    if bit(key, siblings.len()) { (right, left) } else { (left, right) };
+                siblings.push(sibling);
+                hash = next;
+            }
+        }
+    }
+}
+
+/// Merkle proof for a single key of a [`SparseMerkleTree`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    /// Hashes of the siblings along the path of the key, from the root down.
+    pub siblings: Vec<B256>,
+    /// The leaf the path ends in. A leaf of another key, or none at all, proves the key is absent.
+    pub leaf: Option<ProofLeaf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofLeaf {
+    pub key: B256,
+    pub value: B256,
+}
+
+impl MerkleProof {
+    /// Checks that the tree with root `root` holds `value` at `key`, where `None` means the key
+    /// is absent.
+    pub fn verify(&self, root: B256, key: B256, value: Option<B256>) -> bool {
+        let depth = self.siblings.len();
+        if depth > 256 {
+            return false;
+        }
+
+        let mut hash = match (&self.leaf, value) {
+            (Some(leaf), Some(value)) if leaf.key == key && leaf.value == value => {
+                hash_leaf(leaf.key, leaf.value)
+            }
+            (Some(leaf), None)
+                if leaf.key != key && (0..depth).all(|d| bit(leaf.key, d) == bit(key, d)) =>
+            {
+                hash_leaf(leaf.key, leaf.value)
+            }
+            (None, None) => B256::ZERO,
+            _ => return false,
+        };
+
+        for (depth, sibling) in self.siblings.iter().enumerate().rev() {
+            hash = if bit(key, depth) {
+                hash_branch(*sibling, hash)
+            } else {
+                hash_branch(hash, *sibling)
+            };
+        }
+
+        hash == root
+    }
+}
+
+impl SparseMerkleTree {
+    /// Returns the nodes of this tree that `previous` does not have at the same position, which
+    /// are the ones to persist so that the nodes of both trees are stored.
+    pub fn changed_nodes(&self, previous: &SparseMerkleTree) -> Vec<StoredNode> {
+        let mut nodes = vec![];
+        changed_nodes(&self.root, &previous.root, &mut nodes);
+        nodes
+    }
+}
+
+impl PartialEq for SparseMerkleTree {
+    fn eq(&self, other: &Self) -> bool {
+        self.root() == other.root()
+    }
+}
+
+impl Eq for SparseMerkleTree {}
+
+impl Node {
+    fn leaf(key: B256, value: B256) -> Self {
+        Node::Leaf { key, value, hash: hash_leaf(key, value) }
+    }
+
+    fn branch(left: Arc<Node>, right: Arc<Node>) -> Self {
+        let hash = hash_branch(left.hash(), right.hash());
+        Node::Branch { left, right, hash }
+    }
+
+    fn hash(&self) -> B256 {
+        match self {
+            Node::Empty => B256::ZERO,
+            Node::Leaf { hash, .. } | Node::Branch { hash, .. } => *hash,
+        }
+    }
+}
+
+fn insert(node: &Arc<Node>, depth: usize, key: B256, value: B256) -> Arc<Node> {
+    match node.as_ref() {
+        Node::Empty => Arc::new(Node::leaf(key, value)),
+        Node::Leaf { key: leaf_key, .. } if *leaf_key == key => Arc::new(Node::leaf(key, value)),
+        Node::Leaf { key: leaf_key, .. } => {
+            split(node.clone(), *leaf_key, Arc::new(Node::leaf(key, value)), key, depth)
+        }
+        Node::Branch { left, right, .. } => {
+            if bit(key, depth) {
+                Arc::new(Node::branch(left.clone(), insert(right, depth + 1, key, value)))
+            } else {
+                Arc::new(Node::branch(insert(left, depth + 1, key, value), right.clone()))
+            }
+        }
+    }
+}
+
+fn changed_nodes(node: &Node, previous: &Node, nodes: &mut Vec<StoredNode>) {
+    if node.hash() == previous.hash() {
+        return;
+    }
+
+    match node {
+        Node::Empty => {}
+        Node::Leaf { key, value, .. } => nodes.push(StoredNode::Leaf { key: *key, value: *value }),
+        Node::Branch { left, right, .. } => {
+            nodes.push(StoredNode::Branch { left: left.hash(), right: right.hash() });
+            match previous {
+                Node::Branch { left: previous_left, right: previous_right, .. } => {
+                    changed_nodes(left, previous_left, nodes);
+                    changed_nodes(right, previous_right, nodes);
+                }
+                _ => {
+                    changed_nodes(left, &Node::Empty, nodes);
+                    changed_nodes(right, &Node::Empty, nodes);
+                }
+            }
+        }
+    }
+}
+
+/// Builds the subtree holding two leaves whose keys agree on every bit before `depth`.
+fn split(a: Arc<Node>, a_key: B256, b: Arc<Node>, b_key: B256, depth: usize) -> Arc<Node> {
+    match (bit(a_key, depth), bit(b_key, depth)) {
+        (false, true) => Arc::new(Node::branch(a, b)),
+        (true, false) => Arc::new(Node::branch(b, a)),
+        (side, _) => {
+            let child = split(a, a_key, b, b_key, depth + 1);
+            let empty = Arc::new(Node::Empty);
+            if side {
+                Arc::new(Node::branch(empty, child))
+            } else {
+                Arc::new(Node::branch(child, empty))
+            }
+        }
+    }
+}
+
+/// Bit `depth` of `key`, counting from the most significant bit. Set bits go right.
+pub(crate) fn bit(key: B256, depth: usize) -> bool {
+    key.as_bytes()[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+pub(crate) fn hash_leaf(key: B256, value: B256) -> B256 {
+    let hash = Keccak256::new()
+        .chain_update([LEAF_PREFIX])
+        .chain_update(key.as_bytes())
+        .chain_update(value.as_bytes())
+        .finalize();
+    B256::new(hash.into())
+}
+
+pub(crate) fn hash_branch(left: B256, right: B256) -> B256 {
+    let hash = Keccak256::new()
+        .chain_update([BRANCH_PREFIX])
+        .chain_update(left.as_bytes())
+        .chain_update(right.as_bytes())
+        .finalize();
+    B256::new(hash.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use helper::keccak256;
+
+    fn entry(n: u64) -> (B256, B256) {
+        (keccak256(format!("key {}", n)), keccak256(format!("value {}", n)))
+    }
+
+    fn tree(entries: impl IntoIterator<Item = (B256, B256)>) -> SparseMerkleTree {
+        let mut tree = SparseMerkleTree::new();
+        for (key, value) in entries {
+            tree.insert(key, value);
+        }
+        tree
+    }
+
+    #[test]
+    fn root_does_not_depend_on_insertion_order() {
+        let entries: Vec<_> = (0..32).map(entry).collect();
+        let forward = tree(entries.clone());
+        let backward = tree(entries.into_iter().rev());
+
+        assert_eq!(forward.root(), backward.root());
+        assert_ne!(forward.root(), SparseMerkleTree::new().root());
+    }
+
+    #[test]
+    fn overwriting_a_value_changes_the_root() {
+        let mut tree = tree((0..8).map(entry));
+        let root = tree.root();
+        let (key, value) = entry(3);
+
+        tree.insert(key, keccak256("another value"));
+        assert_ne!(tree.root(), root);
+        tree.insert(key, value);
+        assert_eq!(tree.root(), root);
+    }
+
+    #[test]
+    fn proves_present_keys() {
+        let tree = tree((0..16).map(entry));
+        for n in 0..16 {
+            let (key, value) = entry(n);
+            assert_eq!(tree.get(key), Some(value));
+
+            let proof = tree.prove(key);
+            assert!(proof.verify(tree.root(), key, Some(value)));
+            assert!(!proof.verify(tree.root(), key, Some(keccak256("wrong"))));
+            assert!(!proof.verify(tree.root(), key, None));
+        }
+    }
+
+    #[test]
+    fn proves_absent_keys() {
+        let tree = tree((0..16).map(entry));
+        for n in 16..32 {
+            let (key, value) = entry(n);
+            assert_eq!(tree.get(key), None);
+
+            let proof = tree.prove(key);
+            assert!(proof.verify(tree.root(), key, None));
+            assert!(!proof.verify(tree.root(), key, Some(value)));
+        }
+
+        let empty = SparseMerkleTree::new();
+        let (key, _) = entry(0);
+        assert!(empty.prove(key).verify(empty.root(), key, None));
+    }
+
+    #[test]
+    fn proves_from_the_nodes_changed_by_each_update() {
+        let mut stored = std::collections::HashMap::new();
+        let mut trees = vec![SparseMerkleTree::new()];
+        for n in 0..24 {
+            let mut tree = trees.last().unwrap().clone();
+            tree.insert(entry(n).0, entry(n).1);
+            tree.insert(entry(n / 2).0, keccak256(format!("update {}", n)));
+            for node in tree.changed_nodes(trees.last().unwrap()) {
+                stored.insert(node.hash(), node);
+            }
+            trees.push(tree);
+        }
+
+        let load = |hash| stored.get(&hash).cloned().ok_or(hash);
+        for tree in &trees {
+            for n in 0..32 {
+                let (key, _) = entry(n);
+                assert_eq!(prove_stored(tree.root(), key, load), Ok(tree.prove(key)));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_proofs() {
+        let tree = tree((0..16).map(entry));
+        let (key, value) = entry(5);
+        let proof = tree.prove(key);
+
+        let mut tampered = proof.clone();
+        tampered.siblings[0] = keccak256("sibling");
+        assert!(!tampered.verify(tree.root(), key, Some(value)));
+
+        let mut shortened = proof.clone();
+        shortened.siblings.pop();
+        assert!(!shortened.verify(tree.root(), key, Some(value)));
+
+        let other = self::tree((0..17).map(entry));
+        assert!(!proof.verify(other.root(), key, Some(value)));
+    }
+}
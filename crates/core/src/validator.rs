@@ -0,0 +1,67 @@
+use crate::block::Block;
+use eyre::{bail, Report, Result};
+use helper::Address;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Addresses allowed to propose blocks, taking turns in order.
+///
+/// The set only holds public addresses. Every validator seals its blocks with a key that never
+/// leaves its own node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<Address>", into = "Vec<Address>")]
+pub struct ValidatorSet {
+    validators: Vec<Address>,
+}
+
+impl ValidatorSet {
+    pub fn new(validators: Vec<Address>) -> Result<Self> {
+        if validators.is_empty() {
+            bail!("validator set is empty");
+        }
+
+        let mut seen = HashSet::new();
+        for validator in &validators {
+            if !seen.insert(validator) {
+                bail!("validator {} is in the set twice", validator);
+            }
+        }
+
+        Ok(ValidatorSet { validators })
+    }
+
+    pub fn len(&self) -> usize {
+        self.validators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    pub fn contains(&self, address: &Address) -> bool {
+        self.validators.contains(address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Address> {
+        self.validators.iter()
+    }
+
+    /// The validator whose turn it is to propose the child of `parent`.
+    pub fn get_proposer(&self, parent: &Block) -> Address {
+        self.validators[(parent.header.number as usize) % self.validators.len()]
+    }
+}
+
+impl TryFrom<Vec<Address>> for ValidatorSet {
+    type Error = Report;
+
+    fn try_from(validators: Vec<Address>) -> Result<Self> {
+        ValidatorSet::new(validators)
+    }
+}
+
+impl From<ValidatorSet> for Vec<Address> {
+    fn from(set: ValidatorSet) -> Self {
+        set.validators
+    }
+}
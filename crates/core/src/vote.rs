@@ -0,0 +1,204 @@
+use crate::p2p::VoteOnBlock;
+use alloy_rlp::{RlpDecodable, RlpEncodable};
+use eyre::{bail, Result};
+use helper::{Address, B256};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Votes on proposed blocks, counting each validator at most once per block height.
+///
+/// Only signed votes of validators get in. A validator that voted for a block cannot vote for
+/// another block at the same height, nor change its mind on the block, whatever round either
+/// was proposed in, so nobody can push a block over the quorum by sending the same vote again
+/// and no two blocks at one height can both get the quorum. Rejecting a block does not bind the
+/// validator, so after an invalid proposal it can still vote for the next block at the height.
+#[derive(Debug, Default)]
+pub struct VotePool {
+    /// The vote of each validator, by block number.
+    votes: BTreeMap<u64, BTreeMap<Address, VoteOnBlock>>,
+}
+
+/// Proof that a block was finalized by votes: the yes votes for it of validators that hold a
+/// quorum. Stored alongside the block and sent along with it to nodes that catch up.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, RlpEncodable, RlpDecodable)]
+pub struct VoteCertificate {
+    pub block_number: u64,
+    pub block_hash: B256,
+    pub votes: Vec<VoteOnBlock>,
+}
+
+impl VotePool {
+    pub fn new() -> Self {
+        VotePool::default()
+    }
+
+    /// Adds `vote` if it was signed by one of `validators`. Returns whether the vote counts
+    /// from now on, and fails if it is not valid or contradicts an earlier vote of the same
+    /// validator.
+    pub fn add(&mut self, vote: VoteOnBlock, validators: &[Address]) -> Result<bool> {
+        if !validators.contains(&vote.voter) {
+            bail!("{} is not a validator", vote.voter);
+        }
+        vote.verify()?;
+
+        let votes = self.votes.entry(vote.block_number).or_default();
+        match votes.get(&vote.voter) {
+            Some(cast) if cast.conflicts_with(&vote) => bail!(
+                "{} already voted {} on block {} {} and cannot vote {} on {}",
+                vote.voter,
+                cast.approve,
+                cast.block_number,
+                cast.block_hash,
+                vote.approve,
+                vote.block_hash
+            ),
+            // A rejection gives way to a vote for another block, but never the other way round.
+            Some(cast) if cast.approve || !vote.approve => Ok(false),
+            _ => {
+                votes.insert(vote.voter, vote);
+                Ok(true)
+            }
+        }
+    }
+
+    /// The earlier vote of the voter of `vote` at the same height, if the two contradict each
+    /// other.
+    pub fn conflicting(&self, vote: &VoteOnBlock) -> Option<&VoteOnBlock> {
+        self.votes
+            .get(&vote.block_number)?
+            .get(&vote.voter)
+            .filter(|cast| cast.conflicts_with(vote))
+    }
+
+    /// The validators that voted for the block `block_hash` at height `block_number`.
+    pub fn yes_voters(&self, block_number: u64, block_hash: &B256) -> Vec<Address> {
+        self.votes.get(&block_number).map_or(vec![], |votes| {
+            votes
+                .values()
+                .filter(|vote| vote.approve && vote.block_hash == *block_hash)
+                .map(|vote| vote.voter)
+                .collect()
+        })
+    }
+
+    /// The yes votes for the block `block_hash` at height `block_number`, to be stored with the
+    /// block once they are a quorum.
+    pub fn certificate(&self, block_number: u64, block_hash: &B256) -> VoteCertificate {
+        let votes = self.votes.get(&block_number).map_or(vec![], |votes| {
+            votes
+                .values()
+                .filter(|vote| vote.approve && vote.block_hash == *block_hash)
+                .cloned()
+                .collect()
+        });
+
+        VoteCertificate { block_number, block_hash: *block_hash, votes }
+    }
+
+    /// Drops the votes on every block up to `block_number`, once they can no longer change
+    /// anything.
+    pub fn prune(&mut self, block_number: u64) {
+        self.votes.retain(|number, _| *number > block_number);
+    }
+}
+
+impl VoteCertificate {
+    /// Checks that every vote of the certificate is a signed yes vote for its block by a
+    /// different one of `validators`, and returns the validators that cast them. Whether they
+    /// are a quorum is up to the consensus engine.
+    pub fn verify(&self, validators: &[Address]) -> Result<Vec<Address>> {
+        let mut voters = BTreeSet::new();
+        for vote in &self.votes {
+            if !vote.approve
+                || vote.block_number != self.block_number
+                || vote.block_hash != self.block_hash
+            {
+                bail!("certificate of block {} holds a vote for something else", self.block_number);
+            }
+            if !validators.contains(&vote.voter) {
+                bail!("{} is not a validator", vote.voter);
+            }
+            if !voters.insert(vote.voter) {
+                bail!("{} voted twice in the certificate", vote.voter);
+            }
+            vote.verify()?;
+        }
+
+        Ok(voters.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::signers::{LocalWallet, Signer};
+
+    const KEYS: [&str; 2] = [
+        "ac0974bec39a17e36ba4a6b4d238ff944bacb478cbed5efcae784d7bf4f2ff80",
+        "59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d",
+    ];
+
+    fn wallets() -> Vec<LocalWallet> {
+        KEYS.iter().map(|key| key.parse().unwrap()).collect()
+    }
+
+    fn validators() -> Vec<Address> {
+        wallets().iter().map(|wallet| Address::from(wallet.address())).collect()
+    }
+
+    fn vote(wallet: &LocalWallet, round: u64, block: u8, approve: bool) -> VoteOnBlock {
+        VoteOnBlock::new(1, round, B256::from([block; 32]), approve, wallet).unwrap()
+    }
+
+    #[test]
+    fn counts_one_block_per_validator_and_height_whatever_the_round() {
+        let (wallet, validators) = (&wallets()[0], validators());
+        let mut votes = VotePool::new();
+
+        assert!(votes.add(vote(wallet, 1, 1, true), &validators).unwrap());
+        assert!(!votes.add(vote(wallet, 1, 1, true), &validators).unwrap());
+        for second in [vote(wallet, 2, 2, true), vote(wallet, 1, 1, false)] {
+            assert!(votes.conflicting(&second).is_some());
+            assert!(votes.add(second, &validators).is_err());
+        }
+        // Rejecting another block contradicts nothing, but does not replace the vote either.
+        assert!(!votes.add(vote(wallet, 2, 2, false), &validators).unwrap());
+        assert_eq!(votes.yes_voters(1, &B256::from([1; 32])), [validators[0]]);
+    }
+
+    #[test]
+    fn a_rejection_gives_way_to_a_vote_for_the_next_block() {
+        let (wallet, validators) = (&wallets()[0], validators());
+        let mut votes = VotePool::new();
+
+        assert!(votes.add(vote(wallet, 1, 1, false), &validators).unwrap());
+        assert!(votes.conflicting(&vote(wallet, 2, 2, true)).is_none());
+        assert!(votes.add(vote(wallet, 2, 2, true), &validators).unwrap());
+        assert_eq!(votes.yes_voters(1, &B256::from([2; 32])), [validators[0]]);
+    }
+
+    #[test]
+    fn certificates_only_hold_distinct_yes_votes_for_their_block() {
+        let (wallets, validators) = (wallets(), validators());
+        let mut votes = VotePool::new();
+        for wallet in &wallets {
+            votes.add(vote(wallet, 1, 1, true), &validators).unwrap();
+        }
+
+        let certificate = votes.certificate(1, &B256::from([1; 32]));
+        assert_eq!(certificate.verify(&validators).unwrap().len(), 2);
+        assert!(certificate.verify(&validators[..1]).is_err());
+
+        let mut doubled = certificate.clone();
+        doubled.votes[1] = doubled.votes[0].clone();
+        assert!(doubled.verify(&validators).is_err());
+
+        let mut other = certificate.clone();
+        other.votes[1] = vote(&wallets[1], 1, 2, true);
+        assert!(other.verify(&validators).is_err());
+
+        let mut rejected = certificate;
+        rejected.votes[1] = vote(&wallets[1], 1, 1, false);
+        assert!(rejected.verify(&validators).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use crate::{hex::decode_fixed, ParseError};
+use alloy_rlp::{RlpDecodableWrapper, RlpEncodableWrapper};
+use base16ct::lower::encode_string;
+use ethers::types::H160;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha3::{Digest, Keccak256};
+use std::{fmt, str::FromStr};
+
+/// A 20 byte account address. Written with the EIP-55 mixed-case checksum, and parsed from hex in
+/// any case with or without the `0x` prefix, so two spellings of one address always compare equal.
+#[derive(
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    RlpEncodableWrapper,
+    RlpDecodableWrapper,
+)]
+pub struct Address([u8; 20]);
+
+impl Address {
+    pub const ZERO: Address = Address([0; 20]);
+
+    pub const fn new(bytes: [u8; 20]) -> Self {
+        Address(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// The address with the EIP-55 checksum applied to its hex digits.
+    pub fn to_checksum(&self) -> String {
+        let hex = encode_string(&self.0);
+        let hash = Keccak256::digest(hex.as_bytes());
+
+        let mut checksummed = String::from("0x");
+        for (i, c) in hex.chars().enumerate() {
+            let nibble = (hash[i / 2] >> (4 * (1 - i % 2))) & 0x0f;
+            checksummed.push(if nibble >= 8 { c.to_ascii_uppercase() } else { c });
+        }
+
+        checksummed
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_checksum())
+    }
+}
+
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for Address {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Address(decode_fixed(s)?))
+    }
+}
+
+impl From<[u8; 20]> for Address {
+    fn from(bytes: [u8; 20]) -> Self {
+        Address(bytes)
+    }
+}
+
+impl From<H160> for Address {
+    fn from(address: H160) -> Self {
+        Address(address.0)
+    }
+}
+
+impl From<Address> for H160 {
+    fn from(address: Address) -> Self {
+        H160(address.0)
+    }
+}
+
+impl Serialize for Address {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
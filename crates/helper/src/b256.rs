@@ -0,0 +1,102 @@
+use crate::{hex::decode_fixed, ParseError};
+use alloy_rlp::{RlpDecodableWrapper, RlpEncodableWrapper};
+use base16ct::lower::encode_string;
+use ethers::types::H256;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use sha3::{Digest, Keccak256};
+use std::{fmt, str::FromStr};
+
+/// A 32 byte hash or root. Written as `0x` followed by 64 lowercase hex digits, and parsed from
+/// hex in any case with or without the prefix.
+#[derive(
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    RlpEncodableWrapper,
+    RlpDecodableWrapper,
+)]
+pub struct B256([u8; 32]);
+
+impl B256 {
+    pub const ZERO: B256 = B256([0; 32]);
+
+    pub const fn new(bytes: [u8; 32]) -> Self {
+        B256(bytes)
+    }
+
+    /// Panics if `bytes` is not 32 bytes long.
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        let mut hash = [0; 32];
+        hash.copy_from_slice(bytes);
+        B256(hash)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// The hex digits without the `0x` prefix.
+    pub fn to_hex(&self) -> String {
+        encode_string(&self.0)
+    }
+}
+
+/// Keccak-256 hash of `data`.
+pub fn keccak256(data: impl AsRef<[u8]>) -> B256 {
+    B256(Keccak256::digest(data).into())
+}
+
+impl fmt::Display for B256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{}", self.to_hex())
+    }
+}
+
+impl fmt::Debug for B256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for B256 {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(B256(decode_fixed(s)?))
+    }
+}
+
+impl From<[u8; 32]> for B256 {
+    fn from(bytes: [u8; 32]) -> Self {
+        B256(bytes)
+    }
+}
+
+impl From<H256> for B256 {
+    fn from(hash: H256) -> Self {
+        B256(hash.0)
+    }
+}
+
+impl From<B256> for H256 {
+    fn from(hash: B256) -> Self {
+        H256(hash.0)
+    }
+}
+
+impl Serialize for B256 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for B256 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
@@ -0,0 +1,12 @@
+/// Why a string could not be parsed into one of the primitive types.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ParseError {
+    #[error("expected {expected} hex digits but got {got}")]
+    InvalidLength { expected: usize, got: usize },
+    #[error("invalid hex digit")]
+    InvalidHex,
+    #[error("{0:?} is not a canonical decimal number")]
+    InvalidDecimal(String),
+    #[error("{0} is out of range")]
+    OutOfRange(String),
+}
@@ -0,0 +1,22 @@
+use crate::ParseError;
+
+/// Decodes exactly `N` bytes of hex, with or without a `0x` prefix and in any case.
+pub(crate) fn decode_fixed<const N: usize>(s: &str) -> Result<[u8; N], ParseError> {
+    let hex = strip_prefix(s);
+    if hex.len() != N * 2 {
+        return Err(ParseError::InvalidLength { expected: N * 2, got: hex.len() });
+    }
+
+    let mut bytes = [0u8; N];
+    base16ct::mixed::decode(hex, &mut bytes).map_err(|_| ParseError::InvalidHex)?;
+
+    Ok(bytes)
+}
+
+pub(crate) fn decode_vec(s: &str) -> Result<Vec<u8>, ParseError> {
+    base16ct::mixed::decode_vec(strip_prefix(s)).map_err(|_| ParseError::InvalidHex)
+}
+
+fn strip_prefix(s: &str) -> &str {
+    s.strip_prefix("0x").unwrap_or(s)
+}
@@ -1 +1,10 @@
+mod address;
+mod b256;
+mod error;
+mod hex;
+mod signature;
 
+pub use address::Address;
+pub use b256::{keccak256, B256};
+pub use error::ParseError;
+pub use signature::Signature;
@@ -0,0 +1,110 @@
+use crate::{hex::decode_vec, ParseError, B256};
+use alloy_rlp::{RlpDecodable, RlpEncodable};
+use base16ct::lower::encode_string;
+use ethers::types::U256;
+use serde::{de, ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+/// A recoverable secp256k1 signature.
+///
+/// Written as hex of `r`, `s` and then `v` in as few bytes as it fits in, which for `v` of 27 or
+/// 28 is the usual 65 byte form. Serialized as the `v`, `r` and `s` decimal strings that
+/// transactions have always been stored with; only the canonical decimal spelling is accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, RlpEncodable, RlpDecodable)]
+pub struct Signature {
+    pub r: B256,
+    pub s: B256,
+    pub v: u64,
+}
+
+impl Signature {
+    pub fn r_u256(&self) -> U256 {
+        U256::from_big_endian(self.r.as_bytes())
+    }
+
+    pub fn s_u256(&self) -> U256 {
+        U256::from_big_endian(self.s.as_bytes())
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let v = self.v.to_be_bytes();
+        let v = &v[(self.v.leading_zeros() as usize / 8).min(7)..];
+        write!(f, "0x{}{}{}", self.r.to_hex(), self.s.to_hex(), encode_string(v))
+    }
+}
+
+impl FromStr for Signature {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = decode_vec(s)?;
+        if !(65..=72).contains(&bytes.len()) {
+            return Err(ParseError::InvalidLength { expected: 130, got: bytes.len() * 2 });
+        }
+
+        let v = bytes[64..].iter().fold(0u64, |v, byte| (v << 8) | u64::from(*byte));
+        Ok(Signature { r: B256::from_slice(&bytes[..32]), s: B256::from_slice(&bytes[32..64]), v })
+    }
+}
+
+impl From<ethers::types::Signature> for Signature {
+    fn from(signature: ethers::types::Signature) -> Self {
+        Signature { r: u256_to_b256(signature.r), s: u256_to_b256(signature.s), v: signature.v }
+    }
+}
+
+impl From<Signature> for ethers::types::Signature {
+    fn from(signature: Signature) -> Self {
+        ethers::types::Signature { r: signature.r_u256(), s: signature.s_u256(), v: signature.v }
+    }
+}
+
+impl Serialize for Signature {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("Signature", 3)?;
+        state.serialize_field("v", &self.v.to_string())?;
+        state.serialize_field("r", &self.r_u256().to_string())?;
+        state.serialize_field("s", &self.s_u256().to_string())?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Signature {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Decimal {
+            v: String,
+            r: String,
+            s: String,
+        }
+
+        let decimal = Decimal::deserialize(deserializer)?;
+        let v = parse_decimal(&decimal.v).map_err(de::Error::custom)?;
+        if v > U256::from(u64::MAX) {
+            return Err(de::Error::custom(ParseError::OutOfRange(decimal.v)));
+        }
+
+        Ok(Signature {
+            r: u256_to_b256(parse_decimal(&decimal.r).map_err(de::Error::custom)?),
+            s: u256_to_b256(parse_decimal(&decimal.s).map_err(de::Error::custom)?),
+            v: v.as_u64(),
+        })
+    }
+}
+
+fn parse_decimal(s: &str) -> Result<U256, ParseError> {
+    let value = U256::from_dec_str(s).map_err(|_| ParseError::InvalidDecimal(s.to_string()))?;
+    if value.to_string() != s {
+        return Err(ParseError::InvalidDecimal(s.to_string()));
+    }
+
+    Ok(value)
+}
+
+fn u256_to_b256(value: U256) -> B256 {
+    let mut bytes = [0; 32];
+    value.to_big_endian(&mut bytes);
+    B256::new(bytes)
+}
@@ -1,5 +1,6 @@
 import express, { json } from "express";
 import fs from "fs";
+import path from "path";
 import cors from "cors";
 import createKeccakHash from "keccak";
 
@@ -13,85 +14,74 @@ const corsOption = {
 
 app.use(express.json());
 
+// Sends large integers as the exact JSON numbers they are, which `res.json`
+// cannot.
+app.use((req, res, next) => {
+  res.json = (body) => res.type("json").send(stringifyJson(body));
+  next();
+});
+
 app.use(cors(corsOption));
 
-app.get("/blockNumber", (req, res) => {
-  fs.readFile("../blockchain.json", "utf8", (err, data) => {
-    if (err) {
-      console.error("Error reading JSON file:", err);
-      res.status(500).json({ error: "Internal Server Error" });
-      return;
-    }
+// Data directory of the node to serve, defaults to the repository root where
+// `cargo run --bin node` keeps its files.
+const DATADIR = process.env.DATADIR || "..";
+const PORT = process.env.PORT || 8888;
 
-    try {
-      const blockchain = JSON.parse(data);
-      const blocks = blockchain.blocks;
+const BLOCKS_LOG = path.join(DATADIR, "blocks.log");
+const ACCOUNTS_LOG = path.join(DATADIR, "accounts.log");
+const MEMPOOL_LOG = path.join(DATADIR, "mempool.log");
+const METADATA_LOG = path.join(DATADIR, "metadata.log");
 
-      let highestBlock = blocks[0];
-      for (let i = 1; i < blocks.length; i++) {
-        if (blocks[i].header.number > highestBlock.header.number) {
-          highestBlock = blocks[i];
-        }
-      }
+app.get("/blockNumber", (req, res) => {
+  try {
+    const blocks = readLog(BLOCKS_LOG);
+    const head = blocks.get("head");
 
-      res.json(highestBlock.header.number);
-    } catch (error) {
-      console.error("Error parsing JSON:", error);
-      res.status(500).json({ error: "Internal Server Error" });
+    if (head === undefined) {
+      return res.json(null);
     }
-  });
+
+    res.json(head);
+  } catch (error) {
+    console.error("Error reading blocks log:", error);
+    res.status(500).json({ error: "Internal Server Error" });
+  }
 });
 
 app.get("/block", (req, res) => {
   const blockHash = req.query.hash;
   const blockNumber = parseInt(req.query.number, 10);
 
-  if (!blockNumber && !blockHash) {
+  if (isNaN(blockNumber) && !blockHash) {
     return res.status(400).json({
       error: "Please provide either blockNumber or blockHash query parameter",
     });
   }
 
-  if (blockNumber && blockHash) {
+  if (!isNaN(blockNumber) && blockHash) {
     return res.status(400).json({
       error:
         "Please provide only one of blockNumber or blockHash query parameter",
     });
   }
 
-  fs.readFile("../blockchain.json", "utf8", (err, data) => {
-    if (err) {
-      console.error("Error reading JSON file:", err);
-      res.status(500).json({ error: "Internal Server Error" });
-      return;
-    }
-
-    try {
-      const blockchain = JSON.parse(data);
-      const blocks = blockchain.blocks;
-
-      if (!blocks || blocks.length === 0) {
-        return res.json(null);
-      }
+  try {
+    const blocks = readLog(BLOCKS_LOG);
 
-      if (blockNumber) {
-        const block = blocks.find(
-          (block) => block.header.number === blockNumber
-        );
-        res.json(block || null);
-      }
+    if (!isNaN(blockNumber)) {
+      return res.json(blocks.get(`number:${blockNumber}`) || null);
+    }
 
-      if (blockHash) {
-        const block = blocks.find(
-          (block) => calculateBlockHash(block) === blockHash
-        );
-        res.json(block || null);
-      }
-    } catch (error) {
-      console.error("Error parsing JSON:", error);
-      res.status(500).json({ error: "Internal Server Error" });
+    const number = blocks.get(`hash:${normalizeHash(blockHash)}`);
+    if (number === undefined) {
+      return res.json(null);
     }
-  });
+    res.json(blocks.get(`number:${number}`) || null);
+  } catch (error) {
+    console.error("Error reading blocks log:", error);
+    res.status(500).json({ error: "Internal Server Error" });
+  }
 });
 
 app.get("/tx", (req, res) => {
@@ -103,137 +93,138 @@ app.get("/tx", (req, res) => {
       .json({ error: "Please provide a transaction hash query parameter" });
   }
 
-  fs.readFile("../blockchain.json", "utf8", (err, data) => {
-    if (err) {
-      console.error("Error reading JSON file:", err);
-      res.status(500).json({ error: "Internal Server Error" });
-      return;
-    }
-
-    try {
-      const blockchain = JSON.parse(data);
-      const blocks = blockchain.blocks;
-
-      if (!blocks || blocks.length === 0) {
-        return res.json(null);
-      }
-
-      const foundTransaction = blocks.reduce((foundTx, block) => {
-        if (foundTx) return foundTx;
-        return block.txs.find((tx) => calculateTransactionHash(tx) === txHash);
-      }, null);
+  try {
+    const blocks = readLog(BLOCKS_LOG);
+    const head = blocks.get("head");
+    const scheme = getHashScheme();
+
+    for (let number = 0; head !== undefined && number <= head; number++) {
+      const block = blocks.get(`number:${number}`);
+      const foundTransaction =
+        block &&
+        block.txs.find(
+          (tx) =>
+            calculateTransactionHash(tx, scheme) === normalizeHash(txHash)
+        );
 
-      if (!foundTransaction) {
-        return res.status(404).json({ error: "Transaction not found" });
+      if (foundTransaction) {
+        return res.json(foundTransaction);
       }
-
-      res.json(foundTransaction);
-    } catch (error) {
-      console.error("Error parsing JSON:", error);
-      res.status(500).json({ error: "Internal Server Error" });
     }
-  });
+
+    res.status(404).json({ error: "Transaction not found" });
+  } catch (error) {
+    console.error("Error reading blocks log:", error);
+    res.status(500).json({ error: "Internal Server Error" });
+  }
 });
 
 app.get("/getNonce", (req, res) => {
   const address = req.query.address;
 
-  if (!address) {
+  if (!isAddress(address)) {
     return res.status(400).json({
       error: "Please provide address of the account that you want to query",
     });
   }
 
-  fs.readFile("../accounts.json", "utf8", (err, data) => {
-    if (err) {
-      console.error("Error reading JSON file:", err);
-      res.status(500).json({ error: "Internal Server Error" });
-      return;
-    }
-
-    try {
-      const accounts = JSON.parse(data);
-
-      if (!accounts.length === 0) {
-        return res.json(null);
-      }
-
-      if (address) {
-        const account = accounts.find((acc) => acc.address === address);
-        if (!account) {
-          res.json(null);
-        } else {
-          res.json(account.nonce);
-        }
-      }
-    } catch (error) {
-      console.error("Error parsing JSON:", error);
-      res.status(500).json({ error: "Internal Server Error" });
-    }
-  });
+  try {
+    const account = readLog(ACCOUNTS_LOG).get(toChecksumAddress(address));
+    res.json(account ? account.nonce : null);
+  } catch (error) {
+    console.error("Error reading accounts log:", error);
+    res.status(500).json({ error: "Internal Server Error" });
+  }
 });
 
 app.get("/getBalance", (req, res) => {
   const address = req.query.address;
 
-  if (!address) {
+  if (!isAddress(address)) {
     return res.status(400).json({
       error: "Please provide address of the account that you want to query",
     });
   }
 
-  fs.readFile("../accounts.json", "utf8", (err, data) => {
-    if (err) {
-      console.error("Error reading JSON file:", err);
-      res.status(500).json({ error: "Internal Server Error" });
-      return;
-    }
+  try {
+    const account = readLog(ACCOUNTS_LOG).get(toChecksumAddress(address));
+    res.json(account ? account.balance : null);
+  } catch (error) {
+    console.error("Error reading accounts log:", error);
+    res.status(500).json({ error: "Internal Server Error" });
+  }
+});
 
-    try {
-      const accounts = JSON.parse(data);
+// Accounts written before stakes existed have no stake.
+app.get("/getStake", (req, res) => {
+  const address = req.query.address;
 
-      if (!accounts.length === 0) {
-        return res.json(null);
-      }
+  if (!isAddress(address)) {
+    return res.status(400).json({
+      error: "Please provide address of the account that you want to query",
+    });
+  }
 
-      if (address) {
-        const account = accounts.find((acc) => acc.address === address);
-        if (!account) {
-          res.json(null);
-        } else {
-          res.json(account.balance);
-        }
-      }
-    } catch (error) {
-      console.error("Error parsing JSON:", error);
-      res.status(500).json({ error: "Internal Server Error" });
-    }
-  });
+  try {
+    const account = readLog(ACCOUNTS_LOG).get(toChecksumAddress(address));
+    res.json(account ? account.stake || 0 : null);
+  } catch (error) {
+    console.error("Error reading accounts log:", error);
+    res.status(500).json({ error: "Internal Server Error" });
+  }
 });
 
-app.post("/sendTx", (req, res) => {
-  const { from, to, value, pk } = req.body;
+// Sum of all balances, stakes and unbonding stakes after the head block, as recorded in its header.
+// Headers written before the supply was recorded have none.
+app.get("/totalSupply", (req, res) => {
+  try {
+    const blocks = readLog(BLOCKS_LOG);
+    const head = blocks.get("head");
+    const block = head === undefined ? undefined : blocks.get(`number:${head}`);
+
+    res.json(block ? block.header.total_supply || 0 : null);
+  } catch (error) {
+    console.error("Error reading blocks log:", error);
+    res.status(500).json({ error: "Internal Server Error" });
+  }
+});
 
-  if (!from || !to || !value || !pk) {
+// Accepts a transaction the client has already signed. Private keys are never
+// sent to the server; the node checks the signature before including it.
+app.post("/sendTx", (req, res) => {
+  const { sender, receiver, value, fee = 0, nonce, v, r, s } = req.body;
+
+  if (
+    !sender ||
+    !receiver ||
+    !Number.isInteger(value) ||
+    !Number.isInteger(fee) ||
+    !Number.isInteger(nonce) ||
+    !v ||
+    !r ||
+    !s
+  ) {
     return res.status(400).json({
       error:
-        "Please provide from, to, value, and privateKey in the request body",
+        "Please provide sender, receiver, value, fee, nonce, and the v, r, s signature in the request body",
     });
   }
 
-  const mempoolFilePath = "../mempool.json";
-  const txData = {
-    from: from,
-    to: to,
+  const tx = {
+    sender: sender,
+    receiver: receiver,
     value: value,
-    pk: pk,
+    fee: fee,
+    nonce: nonce,
+    v: v.toString(),
+    r: r.toString(),
+    s: s.toString(),
   };
 
+  // Every transaction is a record of its own, keyed like the node keys them,
+  // so adding one never overwrites what the node is taking out.
   try {
-    const mempoolData = fs.readFileSync(mempoolFilePath);
-    const mempool = JSON.parse(mempoolData);
-    mempool.push(txData);
-    fs.writeFileSync(mempoolFilePath, JSON.stringify(mempool, null, 2));
+    appendLog(MEMPOOL_LOG, `tx:${keccakHex(encodeTransaction(tx))}`, tx);
   } catch (err) {
     console.error("Error appending transaction to mempool:", err);
     return res.status(500).json({ error: "Internal server error" });
@@ -242,12 +233,169 @@ app.post("/sendTx", (req, res) => {
   res.status(201).json({ message: "Transaction added to mempool" });
 });
 
-function calculateBlockHash(block) {
+// The live keys of every log read so far, and the byte offset up to which it
+// was read, by file.
+const logs = new Map();
+
+// Returns a Map of the live keys of a node log file. Each line is a
+// `{"key", "value"}` record, later records win and a `null` value deletes the
+// key. Only the records appended since the last call are read. An unterminated
+// last line is a write still in progress and is picked up next time.
+function readLog(file) {
+  const stat = fs.statSync(file);
+  let log = logs.get(file);
+
+  // A compaction replaces the file with a new one, so read it from the start.
+  if (!log || log.ino !== stat.ino || stat.size < log.offset) {
+    log = { ino: stat.ino, offset: 0, entries: new Map() };
+    logs.set(file, log);
+  }
+  if (stat.size === log.offset) {
+    return log.entries;
+  }
+
+  const tail = Buffer.alloc(stat.size - log.offset);
+  const fd = fs.openSync(file, "r");
+  try {
+    fs.readSync(fd, tail, 0, tail.length, log.offset);
+  } finally {
+    fs.closeSync(fd);
+  }
+
+  let start = 0;
+  for (let end = tail.indexOf(10); end !== -1; end = tail.indexOf(10, start)) {
+    const record = parseRecord(tail.subarray(start, end));
+    if (record.value === null) {
+      log.entries.delete(record.key);
+    } else {
+      log.entries.set(record.key, record.value);
+    }
+    start = end + 1;
+  }
+  log.offset += start;
+
+  return log.entries;
+}
+
+// A line can start with the remains of a write torn by a crash, in which case
+// the actual record is the last one on the line.
+function parseRecord(line) {
+  try {
+    return parseJson(line.toString("utf8"));
+  } catch (error) {
+    return parseJson(line.subarray(line.lastIndexOf('{"key":')).toString("utf8"));
+  }
+}
+
+// The node compacts a log by replacing it with a new file, after which it
+// copies over what was appended to the old one in the meantime. A record that
+// reached the old file after that is appended again to the new one. A record
+// that ends up in both is harmless, the later one wins with the same value.
+function appendLog(file, key, value) {
+  const line = stringifyJson({ key, value }) + "\n";
+  for (;;) {
+    const fd = fs.openSync(file, "a");
+    let written;
+    try {
+      fs.writeSync(fd, line);
+      fs.fsyncSync(fd);
+      written = fs.fstatSync(fd).ino;
+    } finally {
+      fs.closeSync(fd);
+    }
+    if (fs.statSync(file).ino === written) {
+      return;
+    }
+  }
+}
+
+// Marks the integers that `JSON.parse` would round while they are passed
+// around as strings.
+const BIG_INTEGER = "\u0000bigint:";
+const NUMBER = /-?[0-9.eE+-]+/y;
+
+// Parses JSON like `JSON.parse`, except that integers above 2^53, such as
+// the total supply, become exact BigInts.
+function parseJson(text) {
+  let quoted = "";
+  let copied = 0;
+
+  for (let i = 0; i < text.length; i++) {
+    if (text[i] === '"') {
+      for (i++; i < text.length && text[i] !== '"'; i++) {
+        if (text[i] === "\\") {
+          i++;
+        }
+      }
+    } else if (/[-0-9]/.test(text[i])) {
+      NUMBER.lastIndex = i;
+      const number = NUMBER.exec(text)[0];
+      if (/^-?[0-9]+$/.test(number) && !Number.isSafeInteger(Number(number))) {
+        quoted += `${text.slice(copied, i)}"\\u0000bigint:${number}"`;
+        copied = i + number.length;
+      }
+      i += number.length - 1;
+    }
+  }
+
+  return JSON.parse(quoted + text.slice(copied), (key, value) =>
+    typeof value === "string" && value.startsWith(BIG_INTEGER)
+      ? BigInt(value.slice(BIG_INTEGER.length))
+      : value
+  );
+}
+
+// Writes JSON like `JSON.stringify`, with BigInts as plain JSON numbers.
+function stringifyJson(value) {
+  return JSON.stringify(value, (key, item) =>
+    typeof item === "bigint" ? `${BIG_INTEGER}${item}` : item
+  ).replace(/"\\u0000bigint:(-?[0-9]+)"/g, "$1");
+}
+
+function isAddress(address) {
+  return typeof address === "string" && /^(0x)?[0-9a-fA-F]{40}$/.test(address);
+}
+
+// Accounts are keyed by their EIP-55 checksummed address, so an address given
+// in any case finds its account.
+function toChecksumAddress(address) {
+  const hex = address.toLowerCase().replace(/^0x/, "");
+  const hash = createKeccakHash("keccak256").update(hex).digest("hex");
+
+  let checksummed = "0x";
+  for (let i = 0; i < hex.length; i++) {
+    checksummed += parseInt(hash[i], 16) >= 8 ? hex[i].toUpperCase() : hex[i];
+  }
+
+  return checksummed;
+}
+
+// Hashes are always written as lowercase hex with a `0x` prefix.
+function normalizeHash(hash) {
+  return `0x${hash.toLowerCase().replace(/^0x/, "")}`;
+}
+
+// The hash scheme of the chain, from the spec the node stored in its metadata.
+// Specs written before the scheme was recorded belong to legacy chains.
+function getHashScheme() {
+  const spec = readLog(METADATA_LOG).get("chain_spec");
+  if (spec === undefined) {
+    return "rlp";
+  }
+
+  return JSON.parse(spec).hash_scheme || "legacy";
+}
+
+function calculateBlockHash(block, scheme) {
+  if (scheme === "rlp") {
+    return keccakHex(encodeHeader(block.header));
+  }
+
   const header = block.header;
   const extraDataBytes = JSON.stringify(header.extra_data)
     .split("")
     .map((c) => c.charCodeAt(0));
-  const txsBytes = JSON.stringify(block.txs)
+  const txsBytes = stringifyJson(block.txs)
     .split("")
     .map((c) => c.charCodeAt(0));
 
@@ -265,20 +413,110 @@ function calculateBlockHash(block) {
   return `0x${hash}`;
 }
 
-function calculateTransactionHash(tx) {
-  const hash = createKeccakHash("keccak256")
+function calculateTransactionHash(tx, scheme) {
+  if (scheme === "rlp") {
+    return keccakHex(encodeTransaction(tx));
+  }
+
+  const keccak = createKeccakHash("keccak256")
     .update(tx.sender)
     .update(tx.receiver)
     .update(tx.value.toString())
     .update(tx.nonce.toString())
     .update(tx.v)
     .update(tx.r)
-    .update(tx.s)
-    .digest("hex");
+    .update(tx.s);
+  // Transactions without a fee keep the hashes they had before there were fees.
+  if (tx.fee) {
+    keccak.update(tx.fee.toString());
+  }
+  const hash = keccak.digest("hex");
+
+  return `0x${hash}`;
+}
+
+function keccakHex(data) {
+  return `0x${createKeccakHash("keccak256").update(data).digest("hex")}`;
+}
+
+// RLP encodings matching the `RlpEncodable` derives of the node's `Header` and
+// `Transaction`, hashes and addresses as fixed size byte strings and numbers as
+// minimal big-endian integers.
+function encodeHeader(header) {
+  return rlpList([
+    rlpHex(header.parent_hash),
+    rlpHex(header.miner),
+    rlpHex(header.state_root),
+    rlpHex(header.transactions_root),
+    rlpHex(header.evidence_root),
+    rlpUint(header.total_supply),
+    rlpUint(header.number),
+    rlpUint(header.timestamp),
+    rlpUint(header.difficulty),
+    rlpUint(header.nonce),
+    rlpHex(header.candidate),
+    rlpUint(header.authorize ? 1 : 0),
+    rlpList(header.signers.map(rlpHex)),
+    rlpList(header.stakes.map(rlpUint)),
+    rlpHex(header.seed),
+    rlpList(header.extra_data.map((data) => rlpBytes(Buffer.from(data)))),
+  ]);
+}
+
+function encodeTransaction(tx) {
+  return rlpList([
+    rlpHex(tx.sender),
+    rlpHex(tx.receiver),
+    rlpUint(tx.value),
+    rlpUint(tx.fee || 0),
+    rlpUint(tx.nonce),
+    rlpList([
+      rlpHex(BigInt(tx.r).toString(16).padStart(64, "0")),
+      rlpHex(BigInt(tx.s).toString(16).padStart(64, "0")),
+      rlpUint(tx.v),
+    ]),
+  ]);
+}
+
+function rlpHex(hex) {
+  return rlpBytes(Buffer.from(hex.replace(/^0x/, ""), "hex"));
+}
+
+function rlpUint(value) {
+  const hex = BigInt(value).toString(16);
+  if (hex === "0") {
+    return rlpBytes(Buffer.alloc(0));
+  }
+
+  return rlpBytes(Buffer.from(hex.padStart(hex.length + (hex.length % 2), "0"), "hex"));
+}
+
+function rlpBytes(bytes) {
+  if (bytes.length === 1 && bytes[0] < 0x80) {
+    return bytes;
+  }
+
+  return Buffer.concat([rlpLength(bytes.length, 0x80), bytes]);
+}
+
+function rlpList(items) {
+  const payload = Buffer.concat(items);
+  return Buffer.concat([rlpLength(payload.length, 0xc0), payload]);
+}
+
+function rlpLength(length, offset) {
+  if (length < 56) {
+    return Buffer.from([offset + length]);
+  }
 
-  return hash;
+  let hex = length.toString(16);
+  hex = hex.padStart(hex.length + (hex.length % 2), "0");
+  return Buffer.concat([
+    Buffer.from([offset + 55 + hex.length / 2]),
+    Buffer.from(hex, "hex"),
+  ]);
 }
 
-app.listen(8888, () => {
-  console.log("Server is running on port 8888");
+app.listen(PORT, () => {
+  console.log(`Server is running on port ${PORT} for ${DATADIR}`);
 });